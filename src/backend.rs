@@ -0,0 +1,318 @@
+//! The source of the chain data queried by [`crate::node_client::NodeClient`]: a live node, or a
+//! directory of exports (see [`crate::file_backend`]).
+
+use crate::node_client::{to_hex, StoragePair};
+use crate::rpc::RpcTransport;
+use anyhow::Result;
+use futures::{Stream, TryStreamExt};
+use sp_core::H256;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use subxt::metadata::Metadata;
+use subxt::rpc::types::{
+    ChainBlock, ChainBlockResponse, ReadProof, RuntimeVersion, StorageChangeSet, StorageData,
+};
+use subxt::rpc_params;
+use subxt::storage::StorageKey;
+use subxt::{Config, OnlineClient, PolkadotConfig};
+
+/// A boxed future returned by the [`Backend`] methods.
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// A boxed stream returned by the [`Backend`] subscriptions.
+pub type BackendStream<T> = Pin<Box<dyn Stream<Item = Result<T>> + Send>>;
+
+pub type Header = <PolkadotConfig as Config>::Header;
+
+/// The chain data needed by the subcommands.
+///
+/// Methods taking a `child_trie` query the given child trie, or the root when it is `None`, and
+/// methods taking an optional block hash default to the latest block.
+pub trait Backend: Send + Sync {
+    /// The hash of the given block, or of the latest block.
+    fn block_hash(&self, block_number: Option<u32>) -> BackendFuture<'_, Option<H256>>;
+
+    fn header(&self, block_hash: Option<H256>) -> BackendFuture<'_, Option<Header>>;
+
+    fn block(
+        &self,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'_, Option<ChainBlock<PolkadotConfig>>>;
+
+    fn runtime_version(&self, block_hash: H256) -> BackendFuture<'_, RuntimeVersion>;
+
+    fn metadata(&self, block_hash: H256) -> BackendFuture<'_, Metadata>;
+
+    /// Up to `count` keys starting with `prefix`, following `start_key`.
+    fn storage_keys_paged<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        prefix: &'a [u8],
+        count: u32,
+        start_key: Option<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'a, Vec<StorageKey>>;
+
+    /// The values of the given keys, in the order of the keys.
+    fn storage_values<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        keys: Vec<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'a, Vec<StoragePair>>;
+
+    /// The changes of the given keys from `from` to `to`, or to the latest block. The first change
+    /// set holds the values at `from`.
+    fn query_storage(
+        &self,
+        keys: Vec<StorageKey>,
+        from: H256,
+        to: Option<H256>,
+    ) -> BackendFuture<'_, Vec<StorageChangeSet<H256>>>;
+
+    /// The trie nodes proving the given key, or its absence.
+    fn read_proof<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        key: &'a [u8],
+        block_hash: H256,
+    ) -> BackendFuture<'a, Vec<Vec<u8>>>;
+
+    /// The headers of the new best blocks, or of the finalized blocks, as they are imported.
+    fn subscribe_heads(&self, finalized: bool) -> BackendFuture<'_, BackendStream<Header>>;
+}
+
+/// A backend querying a node over JSON-RPC.
+pub struct RpcBackend<R> {
+    /// The connection to the node, shared with `client` and used for batch requests.
+    rpc: Arc<R>,
+    client: OnlineClient<PolkadotConfig>,
+}
+
+impl<R: RpcTransport> RpcBackend<R> {
+    pub async fn new(rpc: R) -> Result<Self> {
+        let rpc = Arc::new(rpc);
+        let client = OnlineClient::<PolkadotConfig>::from_rpc_client(rpc.clone()).await?;
+        Ok(Self { rpc, client })
+    }
+
+    /// Get the values of the given child trie keys, in a single JSON-RPC batch request.
+    async fn child_storage_values(
+        &self,
+        child_trie: &StorageKey,
+        keys: Vec<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> Result<Vec<StoragePair>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let params = keys
+            .iter()
+            .map(|key| rpc_params![to_hex(child_trie), to_hex(key), block_hash].build())
+            .collect();
+        let values = self
+            .rpc
+            .batch_raw("childstate_getStorage", params)
+            .await
+            .map_err(|reason| {
+                anyhow::format_err!("failed to get child storage values: {reason}")
+            })?;
+
+        let values = values
+            .iter()
+            .map(|value| serde_json::from_str::<Option<StorageData>>(value.get()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(keys.into_iter().zip(values).collect())
+    }
+
+    /// Get the values of the given keys, in a single `state_queryStorageAt` request.
+    async fn root_storage_values(
+        &self,
+        keys: Vec<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> Result<Vec<StoragePair>> {
+        let mut values = self
+            .client
+            .rpc()
+            .query_storage_at(keys.iter().map(|k| &*k.0), block_hash)
+            .await
+            .map_err(|err| anyhow::format_err!("failed to get storage values: {:?}", err))?
+            .into_iter()
+            .flat_map(|change_set| change_set.changes)
+            .collect::<HashMap<_, _>>();
+
+        Ok(keys
+            .into_iter()
+            .map(|key| {
+                let value = values.remove(&key).flatten();
+                (key, value)
+            })
+            .collect())
+    }
+}
+
+impl<R: RpcTransport> Backend for RpcBackend<R> {
+    fn block_hash(&self, block_number: Option<u32>) -> BackendFuture<'_, Option<H256>> {
+        Box::pin(async move {
+            self.client
+                .rpc()
+                .block_hash(block_number.map(Into::into))
+                .await
+                .map_err(|reason| anyhow::format_err!("failed to get block hash: {:?}", reason))
+        })
+    }
+
+    fn header(&self, block_hash: Option<H256>) -> BackendFuture<'_, Option<Header>> {
+        Box::pin(async move { Ok(self.client.rpc().header(block_hash).await?) })
+    }
+
+    fn block(
+        &self,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'_, Option<ChainBlock<PolkadotConfig>>> {
+        Box::pin(async move {
+            let block = self.client.rpc().block(block_hash).await?;
+            Ok(block.map(|ChainBlockResponse { block, .. }| block))
+        })
+    }
+
+    fn runtime_version(&self, block_hash: H256) -> BackendFuture<'_, RuntimeVersion> {
+        Box::pin(async move {
+            self.client
+                .rpc()
+                .runtime_version(Some(block_hash))
+                .await
+                .map_err(|reason| {
+                    anyhow::format_err!("failed to get runtime version: {:?}", reason)
+                })
+        })
+    }
+
+    fn metadata(&self, block_hash: H256) -> BackendFuture<'_, Metadata> {
+        Box::pin(async move {
+            self.client
+                .rpc()
+                .metadata(Some(block_hash))
+                .await
+                .map_err(|reason| anyhow::format_err!("failed to get metadata: {:?}", reason))
+        })
+    }
+
+    fn storage_keys_paged<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        prefix: &'a [u8],
+        count: u32,
+        start_key: Option<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'a, Vec<StorageKey>> {
+        Box::pin(async move {
+            match child_trie {
+                None => self
+                    .client
+                    .rpc()
+                    .storage_keys_paged(
+                        prefix,
+                        count,
+                        start_key.as_ref().map(|k| k.as_ref()),
+                        block_hash,
+                    )
+                    .await
+                    .map_err(|reason| anyhow::format_err!("get_keys failed: {:?}", reason)),
+                Some(child_trie) => {
+                    let params = rpc_params![
+                        to_hex(child_trie),
+                        to_hex(prefix),
+                        count,
+                        start_key.map(to_hex),
+                        block_hash
+                    ];
+                    self.client
+                        .rpc()
+                        .request("childstate_getKeysPaged", params)
+                        .await
+                        .map_err(|reason| {
+                            anyhow::format_err!("get_child_keys failed: {:?}", reason)
+                        })
+                }
+            }
+        })
+    }
+
+    fn storage_values<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        keys: Vec<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'a, Vec<StoragePair>> {
+        Box::pin(async move {
+            match child_trie {
+                None => self.root_storage_values(keys, block_hash).await,
+                Some(child_trie) => {
+                    self.child_storage_values(child_trie, keys, block_hash)
+                        .await
+                }
+            }
+        })
+    }
+
+    fn query_storage(
+        &self,
+        keys: Vec<StorageKey>,
+        from: H256,
+        to: Option<H256>,
+    ) -> BackendFuture<'_, Vec<StorageChangeSet<H256>>> {
+        Box::pin(async move {
+            let keys = keys.iter().map(|k| &*k.0);
+            self.client
+                .rpc()
+                .query_storage(keys, from, to)
+                .await
+                .map_err(|err| anyhow::format_err!("failed to query storage: {:?}", err))
+        })
+    }
+
+    fn read_proof<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        key: &'a [u8],
+        block_hash: H256,
+    ) -> BackendFuture<'a, Vec<Vec<u8>>> {
+        Box::pin(async move {
+            let proof = match child_trie {
+                None => {
+                    self.client
+                        .rpc()
+                        .read_proof([key], Some(block_hash))
+                        .await?
+                }
+                Some(child_trie) => {
+                    let params = rpc_params![child_trie, vec![to_hex(key)], block_hash];
+                    let proof: ReadProof<H256> = self
+                        .client
+                        .rpc()
+                        .request("state_getChildReadProof", params)
+                        .await?;
+                    proof
+                }
+            };
+            Ok(proof.proof.into_iter().map(|node| node.0).collect())
+        })
+    }
+
+    fn subscribe_heads(&self, finalized: bool) -> BackendFuture<'_, BackendStream<Header>> {
+        Box::pin(async move {
+            let rpc = self.client.rpc();
+            let heads = match finalized {
+                true => rpc.subscribe_finalized_block_headers().await,
+                false => rpc.subscribe_best_block_headers().await,
+            }
+            .map_err(|reason| anyhow::format_err!("failed to subscribe to heads: {reason}"))?;
+            let heads: BackendStream<Header> = Box::pin(heads.map_err(Into::into));
+            Ok(heads)
+        })
+    }
+}
@@ -0,0 +1,197 @@
+//! Search the history of the chain for the block where a value changed, probing as few blocks
+//! as possible.
+
+use anyhow::Result;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[tokio::test]
+async fn test_find_previous_change() {
+    // the value changes at blocks 10 and 90
+    let value = |block_number: u32| match block_number {
+        0..=9 => 'a',
+        10..=89 => 'b',
+        _ => 'c',
+    };
+    let probe = |block_number| async move { Ok(value(block_number)) };
+
+    let change = find_previous_change(100, 0, 1, probe, |value| *value == 'c').await;
+    assert_eq!(change.unwrap(), Some((89, 'b')));
+    let change = find_previous_change(89, 0, 3, probe, |value| *value == 'b').await;
+    assert_eq!(change.unwrap(), Some((9, 'a')));
+
+    // no change down to the floor
+    let change = find_previous_change(89, 10, 1, probe, |value| *value == 'b').await;
+    assert_eq!(change.unwrap(), None);
+
+    let changes = find_changes(5, 100, 2, probe).await.unwrap();
+    assert_eq!(changes, [(10, 'a', 'b'), (90, 'b', 'c')]);
+
+    // a second search only probes the blocks the first one did not
+    let cache = ProbeCache::default();
+    let probe = |block_number| cache.probe(block_number, probe);
+    find_changes(5, 100, 2, probe).await.unwrap();
+    let probes = cache.probes();
+    find_changes(5, 100, 2, probe).await.unwrap();
+    assert_eq!(cache.probes(), probes);
+}
+
+/// The values probed at each block, so that the searches over the same blocks fetch each of them
+/// once.
+pub struct ProbeCache<T> {
+    values: Mutex<HashMap<u32, T>>,
+    probes: AtomicUsize,
+}
+
+impl<T> Default for ProbeCache<T> {
+    fn default() -> Self {
+        Self {
+            values: Default::default(),
+            probes: Default::default(),
+        }
+    }
+}
+
+impl<T: Clone> ProbeCache<T> {
+    /// Get the value of the given block, fetching it on its first probe.
+    pub async fn probe<F, Fut>(&self, block_number: u32, fetch: F) -> Result<T>
+    where
+        F: FnOnce(u32) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if let Some(value) = self.values.lock().unwrap().get(&block_number) {
+            return Ok(value.clone());
+        }
+
+        let value = fetch(block_number).await?;
+        self.probes.fetch_add(1, Ordering::Relaxed);
+        self.values
+            .lock()
+            .unwrap()
+            .insert(block_number, value.clone());
+        Ok(value)
+    }
+
+    /// The number of blocks fetched.
+    pub fn probes(&self) -> usize {
+        self.probes.load(Ordering::Relaxed)
+    }
+}
+
+/// Probe the given blocks, up to `parallel_probes` at once.
+async fn probe_blocks<T, F, Fut>(
+    block_numbers: Vec<u32>,
+    parallel_probes: usize,
+    probe: &F,
+) -> Result<Vec<(u32, T)>>
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    stream::iter(block_numbers)
+        .map(|block_number| async move { Ok((block_number, probe(block_number).await?)) })
+        .buffered(parallel_probes.max(1))
+        .try_collect()
+        .await
+}
+
+/// Find the latest block before `block_number`, and not before `floor`, whose probed value does
+/// not match, or `None` if every block down to `floor` matches.
+///
+/// The search walks back from `block_number` by doubling steps, then bisects between the last
+/// matching block and the first that differs. Each round probes `parallel_probes` blocks at
+/// once, trading extra requests for fewer round trips. A value that changes and changes back
+/// between two probes is missed.
+pub async fn find_previous_change<T, F, Fut>(
+    block_number: u32,
+    floor: u32,
+    parallel_probes: usize,
+    probe: F,
+    matches: impl Fn(&T) -> bool,
+) -> Result<Option<(u32, T)>>
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let parallel_probes = parallel_probes.max(1);
+
+    // the oldest block known to match, and the latest known to differ
+    let mut upper = block_number;
+    let mut lower = None;
+    let mut step = 1u32;
+    while lower.is_none() {
+        if upper <= floor {
+            return Ok(None);
+        }
+
+        let mut block_numbers = Vec::new();
+        while block_numbers.len() < parallel_probes && block_numbers.last() != Some(&floor) {
+            block_numbers.push(block_number.saturating_sub(step).max(floor));
+            step = step.saturating_mul(2);
+        }
+
+        for (block_number, value) in probe_blocks(block_numbers, parallel_probes, &probe).await? {
+            if !matches(&value) {
+                lower = Some((block_number, value));
+                break;
+            }
+            upper = block_number;
+        }
+    }
+
+    let mut lower = lower.expect("the loop ends once a differing block is found");
+    while upper - lower.0 > 1 {
+        // probe evenly spaced blocks between the bounds
+        let range = upper - lower.0;
+        let probes = (parallel_probes as u32).min(range - 1);
+        let block_numbers = (1..=probes)
+            .map(|i| lower.0 + (range as u64 * i as u64 / (probes as u64 + 1)) as u32)
+            .dedup()
+            .collect();
+
+        for (block_number, value) in probe_blocks(block_numbers, parallel_probes, &probe).await? {
+            if matches(&value) {
+                upper = block_number;
+                break;
+            }
+            lower = (block_number, value);
+        }
+    }
+
+    Ok(Some(lower))
+}
+
+/// Find every change of the probed value from `start_block` to `end_block`, from the oldest: the
+/// first block holding the new value, with the values before and after it.
+pub async fn find_changes<T, F, Fut>(
+    start_block: u32,
+    end_block: u32,
+    parallel_probes: usize,
+    probe: F,
+) -> Result<Vec<(u32, T, T)>>
+where
+    T: Clone + PartialEq,
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut changes = Vec::new();
+    let (mut block_number, mut value) = (end_block, probe(end_block).await?);
+    while let Some((previous, old_value)) = find_previous_change(
+        block_number,
+        start_block,
+        parallel_probes,
+        &probe,
+        |probed| *probed == value,
+    )
+    .await?
+    {
+        changes.push((previous + 1, old_value.clone(), value));
+        (block_number, value) = (previous, old_value);
+    }
+    changes.reverse();
+    Ok(changes)
+}
@@ -0,0 +1,326 @@
+//! A disk cache of the node responses that never change: the responses to queries at a given
+//! block hash, such as a header, a block, or storage values.
+//!
+//! Responses are stored by chain and method, in a file named after the hash of the request:
+//! `<cache dir>/<genesis hash>/<method>/<sha256 of the request>.json`.
+
+use crate::rpc::{client_error, NodeOptions, RpcTransport};
+use anyhow::Result;
+use clap::Parser;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sp_core::H256;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use subxt::error::RpcError;
+use subxt::rpc::{RpcClientT, RpcFuture, RpcSubscription};
+use subxt::rpc_params;
+
+#[tokio::test]
+async fn test_cache() {
+    use crate::mock_node::{temp_path, MockNode};
+    use crate::rpc::ReconnectingClient;
+
+    let node = MockNode::fixtures().await;
+    let dir = temp_path("cache");
+    let mut options = node.options();
+    options.max_retries = 0;
+    let rpc = ReconnectingClient::connect(options).await.unwrap();
+    let client = CachingClient::new(rpc, &dir).await.unwrap();
+
+    let header =
+        |hash: Option<H256>| client.request_raw("chain_getHeader", rpc_params![hash].build());
+    let latest = header(None).await.unwrap();
+    let hash = serde_json::from_str::<crate::backend::Header>(latest.get()).unwrap();
+    let hash = subxt::config::Header::hash(&hash);
+    let at_hash = header(Some(hash)).await.unwrap();
+    assert_eq!(at_hash.get(), latest.get());
+
+    // only the query at a given hash is cached
+    let chains = usage(&dir).unwrap();
+    assert_eq!(chains.len(), 1);
+    assert_eq!(
+        chains.values().next().unwrap()["chain_getHeader"].entries,
+        1
+    );
+
+    // and answered without the node
+    drop(node);
+    assert!(header(None).await.is_err());
+    assert_eq!(header(Some(hash)).await.unwrap().get(), at_hash.get());
+
+    assert_eq!(clear(&dir, None).unwrap(), 1);
+    assert!(usage(&dir).unwrap().is_empty());
+}
+
+/// Inspect or clear the cache enabled with `--cache`.
+#[derive(Parser, Debug)]
+pub enum CacheCmd {
+    /// Print the number and size of the cached responses, by chain and method
+    Stats,
+    /// Remove the cached responses
+    Clear {
+        /// Only remove the responses of the chain with this genesis hash
+        #[clap(long)]
+        genesis_hash: Option<H256>,
+    },
+}
+
+/// The methods whose last param is a block hash. Their responses at a given hash never change.
+const CACHED_METHODS: &[&str] = &[
+    "chain_getHeader",
+    "chain_getBlock",
+    "state_getRuntimeVersion",
+    "state_getMetadata",
+    "state_call",
+    "state_getStorage",
+    "state_getKeysPaged",
+    "state_queryStorageAt",
+    "state_queryStorage",
+    "state_getReadProof",
+    "state_getChildReadProof",
+    "childstate_getStorage",
+    "childstate_getKeysPaged",
+];
+
+/// A client answering the queries at a given block hash from the cache, and caching the
+/// responses of the node to the others.
+pub struct CachingClient<R> {
+    inner: R,
+    /// The directory of the chain, named after its genesis hash.
+    dir: PathBuf,
+}
+
+impl<R: RpcTransport> CachingClient<R> {
+    pub async fn new(inner: R, cache_dir: &Path) -> Result<Self> {
+        let genesis_hash = inner
+            .request_raw("chain_getBlockHash", rpc_params![0u32].build())
+            .await?;
+        let genesis_hash = serde_json::from_str::<Option<H256>>(genesis_hash.get())?
+            .ok_or_else(|| anyhow::format_err!("genesis block not found"))?;
+        Ok(Self {
+            inner,
+            dir: cache_dir.join(format!("{genesis_hash:?}")),
+        })
+    }
+
+    /// The cache entry of the request, if its response never changes.
+    fn entry(&self, method: &str, params: Option<&RawValue>) -> Result<Option<PathBuf>, RpcError> {
+        if !CACHED_METHODS.contains(&method) {
+            return Ok(None);
+        }
+        let Some(params) = params else {
+            return Ok(None);
+        };
+        let params: Vec<Value> = serde_json::from_str(params.get()).map_err(client_error)?;
+        if params.last().is_none_or(Value::is_null) {
+            return Ok(None);
+        }
+
+        let request = serde_json::to_vec(&(method, params)).map_err(client_error)?;
+        let digest = hex::encode(Sha256::digest(request));
+        Ok(Some(self.dir.join(method).join(format!("{digest}.json"))))
+    }
+
+    fn read(entry: &Path) -> Option<Box<RawValue>> {
+        let response = std::fs::read_to_string(entry).ok()?;
+        serde_json::from_str(&response).ok()
+    }
+
+    /// Store the response, unless it is empty: an unknown block may be imported later.
+    fn write(entry: &Path, response: &RawValue) -> Result<(), RpcError> {
+        if response.get() == "null" {
+            return Ok(());
+        }
+
+        // written atomically, so that concurrent runs never read a partial response
+        let dir = entry.parent().expect("entries are in a method directory");
+        std::fs::create_dir_all(dir).map_err(client_error)?;
+        let tmp_path = entry.with_extension(format!("{}.tmp", rand::random::<u64>()));
+        std::fs::write(&tmp_path, response.get()).map_err(client_error)?;
+        std::fs::rename(tmp_path, entry).map_err(client_error)
+    }
+}
+
+impl<R: RpcTransport> RpcClientT for CachingClient<R> {
+    fn request_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Option<Box<RawValue>>,
+    ) -> RpcFuture<'a, Box<RawValue>> {
+        Box::pin(async move {
+            let entry = self.entry(method, params.as_deref())?;
+            if let Some(response) = entry.as_deref().and_then(Self::read) {
+                return Ok(response);
+            }
+
+            let response = self.inner.request_raw(method, params).await?;
+            if let Some(entry) = entry {
+                Self::write(&entry, &response)?;
+            }
+            Ok(response)
+        })
+    }
+
+    fn subscribe_raw<'a>(
+        &'a self,
+        sub: &'a str,
+        params: Option<Box<RawValue>>,
+        unsub: &'a str,
+    ) -> RpcFuture<'a, RpcSubscription> {
+        self.inner.subscribe_raw(sub, params, unsub)
+    }
+}
+
+impl<R: RpcTransport> RpcTransport for CachingClient<R> {
+    /// Only the calls missing from the cache are sent to the node.
+    fn batch_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Vec<Option<Box<RawValue>>>,
+    ) -> RpcFuture<'a, Vec<Box<RawValue>>> {
+        Box::pin(async move {
+            let mut responses = Vec::with_capacity(params.len());
+            let mut missing = Vec::new();
+            for params in params {
+                let entry = self.entry(method, params.as_deref())?;
+                let response = entry.as_deref().and_then(Self::read);
+                if response.is_none() {
+                    missing.push(params);
+                }
+                responses.push((entry, response));
+            }
+
+            let mut fetched = match missing.is_empty() {
+                true => Vec::new(),
+                false => self.inner.batch_raw(method, missing).await?,
+            }
+            .into_iter();
+
+            responses
+                .into_iter()
+                .map(|(entry, response)| match response {
+                    Some(response) => Ok(response),
+                    None => {
+                        let response = fetched.next().expect("a response per call");
+                        if let Some(entry) = entry {
+                            Self::write(&entry, &response)?;
+                        }
+                        Ok(response)
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+/// The number of cached responses of a method, and their size.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+    }
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut size = self.bytes as f64;
+        let mut units = ["B", "KiB", "MiB", "GiB"].into_iter().peekable();
+        let mut unit = units.next().unwrap_or_default();
+        while size >= 1024.0 && units.peek().is_some() {
+            size /= 1024.0;
+            unit = units.next().unwrap_or_default();
+        }
+        write!(f, "{} responses, {size:.1} {unit}", self.entries)
+    }
+}
+
+/// The entries of each method directory of the chain directory, or of each chain directory of
+/// the cache.
+fn sub_dirs(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            ));
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// The usage of the cache, by genesis hash and method.
+pub fn usage(cache_dir: &Path) -> Result<BTreeMap<String, BTreeMap<String, Usage>>> {
+    let mut chains = BTreeMap::new();
+    for (genesis_hash, chain_dir) in sub_dirs(cache_dir)? {
+        let mut methods = BTreeMap::new();
+        for (method, method_dir) in sub_dirs(&chain_dir)? {
+            let mut usage = Usage::default();
+            for entry in std::fs::read_dir(method_dir)? {
+                let metadata = entry?.metadata()?;
+                usage += Usage {
+                    entries: 1,
+                    bytes: metadata.len(),
+                };
+            }
+            methods.insert(method, usage);
+        }
+        chains.insert(genesis_hash, methods);
+    }
+    Ok(chains)
+}
+
+/// Remove the cached responses of the given chain, or of all chains, and return their number.
+pub fn clear(cache_dir: &Path, genesis_hash: Option<H256>) -> Result<u64> {
+    let mut chains = usage(cache_dir)?;
+    let mut removed = 0;
+    for (name, chain_dir) in sub_dirs(cache_dir)? {
+        if genesis_hash.is_some_and(|hash| format!("{hash:?}") != name) {
+            continue;
+        }
+        let methods = chains.remove(&name).unwrap_or_default();
+        removed += methods.values().map(|usage| usage.entries).sum::<u64>();
+        std::fs::remove_dir_all(chain_dir)?;
+    }
+    Ok(removed)
+}
+
+pub fn cache_command(node: &NodeOptions, cmd: CacheCmd) -> Result<()> {
+    let cache_dir = node.cache_dir()?;
+    match cmd {
+        CacheCmd::Stats => {
+            println!("Cache directory: {}", cache_dir.display());
+            for (genesis_hash, methods) in usage(&cache_dir)? {
+                let mut total = Usage::default();
+                for usage in methods.values() {
+                    total += *usage;
+                }
+                println!("Chain {genesis_hash}: {total}");
+                for (method, usage) in methods {
+                    println!("  {method}: {usage}");
+                }
+            }
+        }
+        CacheCmd::Clear { genesis_hash } => {
+            let removed = clear(&cache_dir, genesis_hash)?;
+            println!("Removed {removed} cached responses");
+        }
+    }
+    Ok(())
+}
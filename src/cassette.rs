@@ -0,0 +1,337 @@
+//! Record the JSON-RPC requests made to a node, and replay them without the node.
+//!
+//! A cassette holds one interaction per line: the method, its params, and the result or error
+//! returned by the node. Batch requests are recorded as one interaction per call. Subscriptions
+//! are recorded as their request, then one line per notification received, tagged with the number
+//! of the subscription.
+
+use crate::rpc::{client_error, RpcTransport};
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use jsonrpsee::core::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use subxt::error::RpcError;
+use subxt::rpc::{RpcClientT, RpcFuture, RpcSubscription};
+
+#[tokio::test]
+async fn test_record_and_replay() {
+    use crate::mock_node::{set_contract_version, temp_path, MockNode, Scenario};
+    use crate::node_client::NodeClient;
+    use futures::TryStreamExt;
+
+    async fn watch(client: &NodeClient) -> Vec<String> {
+        crate::watch::migration_events(client, 10, false)
+            .map_ok(|event| event.to_string())
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    let scenario = Scenario::fixtures()
+        .push_blocks(3, |_| {})
+        .push_block(|state| set_contract_version(state, 10))
+        .push_blocks(2, |_| {});
+    let node = MockNode::start(scenario.into_backend()).await;
+    let cassette = temp_path("migration.jsonl");
+
+    let mut options = node.options();
+    options.record = Some(cassette.clone());
+    let client = NodeClient::connect(options).await.unwrap();
+    let head = client.get_block_info(None).await.unwrap();
+    let recorded = client.find_previous_migration_info(&head, 1).await.unwrap();
+    assert_eq!((recorded.block_number, recorded.version), (3, 9));
+    let recorded_events = watch(&client).await;
+    assert_eq!(recorded_events.len(), 2);
+    drop((client, node));
+
+    // the same queries are answered without the node
+    let mut options = crate::rpc::NodeOptions::with_url("ws://127.0.0.1:1");
+    options.replay = Some(cassette);
+    let client = NodeClient::connect(options).await.unwrap();
+    let head = client.get_block_info(None).await.unwrap();
+    let replayed = client.find_previous_migration_info(&head, 1).await.unwrap();
+    assert_eq!(replayed.block_hash, recorded.block_hash);
+    // and the heads are notified again
+    assert_eq!(watch(&client).await, recorded_events);
+
+    // other queries were not recorded
+    let err = client.get_timestamp(head.block_hash).await.unwrap_err();
+    assert!(err.to_string().contains("not in the cassette"), "{err}");
+}
+
+/// A request and the response of the node, or a notification of a subscription.
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    result: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The subscription of a notification, numbered in the order of the subscriptions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subscription: Option<usize>,
+}
+
+impl Interaction {
+    fn new(
+        method: &str,
+        params: Option<&RawValue>,
+        response: Result<&RawValue, &RpcError>,
+    ) -> Result<Self, RpcError> {
+        let params = params.map(|params| serde_json::from_str(params.get()));
+        let (result, error) = match response {
+            Ok(result) => (serde_json::from_str(result.get()), None),
+            Err(err) => (Ok(Value::Null), Some(err.to_string())),
+        };
+        Ok(Self {
+            method: method.to_string(),
+            params: params
+                .transpose()
+                .map_err(client_error)?
+                .unwrap_or_default(),
+            result: result.map_err(client_error)?,
+            error,
+            subscription: None,
+        })
+    }
+
+    /// The recorded result, or error.
+    fn response(&self) -> Result<Box<RawValue>, RpcError> {
+        match &self.error {
+            Some(err) => Err(client_error(Error::Custom(err.clone()))),
+            None => serde_json::value::to_raw_value(&self.result).map_err(client_error),
+        }
+    }
+
+    /// The request, with its params in canonical form.
+    fn request(method: &str, params: Option<&RawValue>) -> Result<(String, String), RpcError> {
+        let params = match params {
+            Some(params) => serde_json::from_str::<Value>(params.get()).map_err(client_error)?,
+            None => Value::Null,
+        };
+        Ok((method.to_string(), params.to_string()))
+    }
+}
+
+/// Append the interaction to the cassette, it is on disk when this returns.
+fn write(cassette: &Mutex<BufWriter<File>>, interaction: &Interaction) -> Result<(), RpcError> {
+    let mut cassette = cassette.lock().unwrap();
+    serde_json::to_writer(&mut *cassette, interaction).map_err(client_error)?;
+    writeln!(cassette).map_err(client_error)?;
+    cassette.flush().map_err(client_error)
+}
+
+/// A client recording the requests sent to the node, and the responses.
+pub struct Recorder<R> {
+    inner: R,
+    /// Shared with the streams recording the notifications.
+    cassette: Arc<Mutex<BufWriter<File>>>,
+    /// The number of subscriptions recorded so far.
+    subscriptions: AtomicUsize,
+}
+
+impl<R: RpcTransport> Recorder<R> {
+    pub fn create(inner: R, cassette: &Path) -> Result<Self> {
+        let file = File::create(cassette).map_err(|reason| {
+            anyhow::format_err!("failed to create {}: {reason}", cassette.display())
+        })?;
+        Ok(Self {
+            inner,
+            cassette: Arc::new(Mutex::new(BufWriter::new(file))),
+            subscriptions: AtomicUsize::new(0),
+        })
+    }
+
+    /// Append the interaction to the cassette, it is on disk when this returns.
+    fn record(
+        &self,
+        method: &str,
+        params: Option<&RawValue>,
+        response: Result<&RawValue, &RpcError>,
+    ) -> Result<(), RpcError> {
+        write(&self.cassette, &Interaction::new(method, params, response)?)
+    }
+}
+
+impl<R: RpcTransport> RpcClientT for Recorder<R> {
+    fn request_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Option<Box<RawValue>>,
+    ) -> RpcFuture<'a, Box<RawValue>> {
+        Box::pin(async move {
+            let response = self.inner.request_raw(method, params.clone()).await;
+            self.record(method, params.as_deref(), response.as_deref())?;
+            response
+        })
+    }
+
+    /// Record the subscription id, then each notification as it is received.
+    fn subscribe_raw<'a>(
+        &'a self,
+        sub: &'a str,
+        params: Option<Box<RawValue>>,
+        unsub: &'a str,
+    ) -> RpcFuture<'a, RpcSubscription> {
+        Box::pin(async move {
+            let RpcSubscription { stream, id } =
+                match self.inner.subscribe_raw(sub, params.clone(), unsub).await {
+                    Ok(subscription) => subscription,
+                    Err(err) => {
+                        self.record(sub, params.as_deref(), Err(&err))?;
+                        return Err(err);
+                    }
+                };
+            let raw_id = serde_json::value::to_raw_value(&id).map_err(client_error)?;
+            self.record(sub, params.as_deref(), Ok(&raw_id))?;
+
+            let subscription = self.subscriptions.fetch_add(1, Ordering::SeqCst);
+            let cassette = self.cassette.clone();
+            let sub = sub.to_string();
+            let stream = stream
+                .map(move |notification| {
+                    let interaction = Interaction::new(&sub, None, notification.as_deref())?;
+                    let interaction = Interaction {
+                        subscription: Some(subscription),
+                        ..interaction
+                    };
+                    write(&cassette, &interaction)?;
+                    notification
+                })
+                .boxed();
+            Ok(RpcSubscription { stream, id })
+        })
+    }
+}
+
+impl<R: RpcTransport> RpcTransport for Recorder<R> {
+    fn batch_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Vec<Option<Box<RawValue>>>,
+    ) -> RpcFuture<'a, Vec<Box<RawValue>>> {
+        Box::pin(async move {
+            let results = self.inner.batch_raw(method, params.clone()).await?;
+            for (params, result) in params.iter().zip(&results) {
+                self.record(method, params.as_deref(), Ok(result))?;
+            }
+            Ok(results)
+        })
+    }
+}
+
+/// A client answering the requests recorded in a cassette.
+///
+/// The responses to a request are served in the order they were recorded, and the last one is
+/// served again to any further identical request. Each subscription replays the notifications of
+/// the recorded subscription with the same number, then ends.
+pub struct Replayer {
+    responses: Mutex<HashMap<(String, String), VecDeque<Interaction>>>,
+    /// The notifications of each subscription, by number.
+    notifications: Mutex<HashMap<usize, Vec<Interaction>>>,
+    /// The number of subscriptions replayed so far.
+    subscriptions: AtomicUsize,
+}
+
+impl Replayer {
+    pub fn load(cassette: &Path) -> Result<Self> {
+        let file = File::open(cassette).map_err(|reason| {
+            anyhow::format_err!("failed to open {}: {reason}", cassette.display())
+        })?;
+
+        let mut responses = HashMap::<_, VecDeque<_>>::new();
+        let mut notifications = HashMap::<_, Vec<_>>::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let interaction: Interaction = serde_json::from_str(&line?).map_err(|reason| {
+                anyhow::format_err!("invalid interaction on line {}: {reason}", index + 1)
+            })?;
+            if let Some(subscription) = interaction.subscription {
+                notifications
+                    .entry(subscription)
+                    .or_default()
+                    .push(interaction);
+                continue;
+            }
+            let request = (interaction.method.clone(), interaction.params.to_string());
+            responses.entry(request).or_default().push_back(interaction);
+        }
+
+        Ok(Self {
+            responses: Mutex::new(responses),
+            notifications: Mutex::new(notifications),
+            subscriptions: AtomicUsize::new(0),
+        })
+    }
+
+    fn respond(&self, method: &str, params: Option<&RawValue>) -> Result<Box<RawValue>, RpcError> {
+        let request = Interaction::request(method, params)?;
+        let mut responses = self.responses.lock().unwrap();
+        let interactions = responses.get_mut(&request).ok_or_else(|| {
+            let reason = format!("{method} {} is not in the cassette", request.1);
+            client_error(Error::Custom(reason))
+        })?;
+
+        let interaction = match interactions.len() {
+            1 => &interactions[0],
+            _ => {
+                interactions.pop_front();
+                &interactions[0]
+            }
+        };
+        interaction.response()
+    }
+}
+
+impl RpcClientT for Replayer {
+    fn request_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Option<Box<RawValue>>,
+    ) -> RpcFuture<'a, Box<RawValue>> {
+        Box::pin(async move { self.respond(method, params.as_deref()) })
+    }
+
+    fn subscribe_raw<'a>(
+        &'a self,
+        sub: &'a str,
+        params: Option<Box<RawValue>>,
+        _unsub: &'a str,
+    ) -> RpcFuture<'a, RpcSubscription> {
+        Box::pin(async move {
+            let id = self.respond(sub, params.as_deref())?;
+            let id = serde_json::from_str(id.get()).map_err(client_error)?;
+
+            let subscription = self.subscriptions.fetch_add(1, Ordering::SeqCst);
+            let notifications = self.notifications.lock().unwrap().remove(&subscription);
+            let stream = stream::iter(notifications.unwrap_or_default())
+                .map(|notification| notification.response())
+                .boxed();
+            Ok(RpcSubscription { stream, id })
+        })
+    }
+}
+
+impl RpcTransport for Replayer {
+    fn batch_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Vec<Option<Box<RawValue>>>,
+    ) -> RpcFuture<'a, Vec<Box<RawValue>>> {
+        Box::pin(async move {
+            params
+                .iter()
+                .map(|params| self.respond(method, params.as_deref()))
+                .collect()
+        })
+    }
+}
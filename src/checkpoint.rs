@@ -0,0 +1,166 @@
+//! Checkpoints of long running exports, so that they can be resumed after a failure.
+//!
+//! The progress of an export is saved next to its output as `<output_file>.checkpoint.json`, and
+//! removed once the export completes.
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, Write};
+
+#[test]
+fn test_resume_json_array() {
+    let output_file = crate::mock_node::temp_path("checkpoint-blocks.json");
+    let output_file = output_file.to_string_lossy().into_owned();
+
+    let mut output = JsonArrayOutput::create(&output_file).unwrap();
+    output.push(&1).unwrap();
+    output.push(&2).unwrap();
+    save(&output_file, &output.len()).unwrap();
+    // written after the checkpoint, and dropped on resume
+    output.push(&3).unwrap();
+    drop(output);
+
+    let items = load::<usize>(&output_file).unwrap().unwrap();
+    let mut output = JsonArrayOutput::resume(&output_file, items).unwrap();
+    assert_eq!(output.len(), 2);
+    output.push(&4).unwrap();
+    output.finish().unwrap();
+    remove(&output_file).unwrap();
+
+    let json = std::fs::read_to_string(&output_file).unwrap();
+    assert_eq!(
+        serde_json::from_str::<Vec<u32>>(&json).unwrap(),
+        vec![1, 2, 4]
+    );
+    assert!(load::<usize>(&output_file).unwrap().is_none());
+}
+
+/// The path of the checkpoint of the given output.
+pub fn path(output_file: &str) -> String {
+    format!("{output_file}.checkpoint.json")
+}
+
+/// Load the checkpoint of the given output, if any.
+pub fn load<T: DeserializeOwned>(output_file: &str) -> Result<Option<T>> {
+    match std::fs::read_to_string(path(output_file)) {
+        Ok(checkpoint) => serde_json::from_str(&checkpoint)
+            .map(Some)
+            .map_err(|reason| anyhow::format_err!("invalid checkpoint: {reason}")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Save the checkpoint of the given output, replacing the previous one atomically.
+pub fn save<T: Serialize>(output_file: &str, checkpoint: &T) -> Result<()> {
+    let path = path(output_file);
+    let tmp_path = format!("{path}.tmp");
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer_pretty(&mut file, checkpoint)?;
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Remove the checkpoint of a completed output.
+pub fn remove(output_file: &str) -> Result<()> {
+    match std::fs::remove_file(path(output_file)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// A json array written to disk one item at a time.
+///
+/// Items are appended to `<output_file>.partial.jsonl`, and the output itself is only written by
+/// [`JsonArrayOutput::finish`], so an interrupted export can be resumed from the number of items
+/// saved in its checkpoint.
+pub struct JsonArrayOutput {
+    output_file: String,
+    partial: File,
+    len: usize,
+}
+
+impl JsonArrayOutput {
+    fn partial_path(output_file: &str) -> String {
+        format!("{output_file}.partial.jsonl")
+    }
+
+    /// Start a new output, discarding any partial one.
+    pub fn create(output_file: &str) -> Result<Self> {
+        let partial = File::create(Self::partial_path(output_file))?;
+        Ok(Self {
+            output_file: output_file.to_string(),
+            partial,
+            len: 0,
+        })
+    }
+
+    /// Resume a partial output, keeping its first `len` items.
+    pub fn resume(output_file: &str, len: usize) -> Result<Self> {
+        let partial_path = Self::partial_path(output_file);
+        let mut partial = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&partial_path)
+            .map_err(|reason| anyhow::format_err!("failed to open {partial_path}: {reason}"))?;
+
+        // drop the items written after the checkpoint
+        let mut offset = 0;
+        let mut lines = BufReader::new(&partial).lines();
+        for _ in 0..len {
+            let line = lines
+                .next()
+                .ok_or_else(|| anyhow::format_err!("{partial_path} is missing items"))??;
+            offset += line.len() as u64 + 1;
+        }
+        partial.set_len(offset)?;
+        partial.seek(std::io::SeekFrom::End(0))?;
+
+        Ok(Self {
+            output_file: output_file.to_string(),
+            partial,
+            len,
+        })
+    }
+
+    /// The number of items written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Append an item, it is on disk when this returns.
+    pub fn push<T: Serialize>(&mut self, item: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(item)?;
+        line.push(b'\n');
+        self.partial.write_all(&line)?;
+        self.partial.flush()?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Write the output as a json array, one item per line, and remove the partial output.
+    pub fn finish(self) -> Result<()> {
+        let partial_path = Self::partial_path(&self.output_file);
+        let mut out = BufWriter::new(File::create(&self.output_file)?);
+
+        write!(out, "[")?;
+        for (i, line) in BufReader::new(File::open(&partial_path)?)
+            .lines()
+            .enumerate()
+        {
+            let separator = if i == 0 { "" } else { "," };
+            write!(out, "{separator}\n  {}", line?)?;
+        }
+        if self.len > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, "]")?;
+        out.flush()?;
+
+        std::fs::remove_file(partial_path)?;
+        Ok(())
+    }
+}
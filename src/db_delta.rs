@@ -0,0 +1,280 @@
+//! Delta exports, holding only the entries changed between two blocks.
+//!
+//! A delta is written like a regular export, where a `null` value removes its key. Its manifest
+//! records the block it applies to, so that `db-apply` can check the chain of deltas.
+
+use crate::db_diff::{DBDiff, TrieDiff};
+use crate::db_export::{
+    BlockRef, DBExport, DBExportWriter, ExportFormat, Manifest, TrieEntries, FORMAT_VERSION,
+};
+use crate::node_client::{NodeClient, PAGE_SIZE};
+use anyhow::Result;
+use clap::Parser;
+use futures::TryStreamExt;
+use sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use sp_core::H256;
+use std::collections::{BTreeMap, BTreeSet};
+use subxt::storage::StorageKey;
+
+#[test]
+fn test_apply_deltas() {
+    let mut export = DBExport::load("db-0.json").unwrap();
+    for number in 1..=4 {
+        let target = DBExport::load(&format!("db-{number}.json")).unwrap();
+        export.apply(delta_export(&DBDiff::new(&export, &target)));
+        assert_eq!(export, target, "block {number}");
+    }
+
+    // contract 1 is terminated at block 4, along with its child trie
+    let db_3 = DBExport::load("db-3.json").unwrap();
+    let delta = delta_export(&DBDiff::new(&db_3, &export));
+    assert_eq!(export.child_tries.len() + 1, db_3.child_tries.len());
+    assert!(delta.root.values().any(|value| value.is_none()));
+}
+
+#[tokio::test]
+async fn test_export_delta_from_node() {
+    use crate::mock_node::{temp_path, MockNode};
+
+    let node = MockNode::fixtures().await;
+    let client = node.client().await;
+    let delta_file = temp_path("delta-1-3.json");
+    let delta_file = delta_file.to_str().unwrap();
+    let output_file = temp_path("db-apply-3.json");
+    let output_file = output_file.to_str().unwrap();
+
+    let cmd = DBDeltaCmd::parse_from(["db-delta", delta_file, "--from", "1", "--to", "3"]);
+    export_delta(&client, cmd).await.unwrap();
+    let cmd = DBApplyCmd::parse_from(["db-apply", "db-1.json", delta_file, "-o", output_file]);
+    apply_deltas(cmd).unwrap();
+
+    assert_eq!(
+        DBExport::load(output_file).unwrap(),
+        DBExport::load("db-3.json").unwrap()
+    );
+}
+
+/// Export the entries changed between two blocks.
+#[derive(Parser, Debug)]
+pub struct DBDeltaCmd {
+    output_file: String,
+    /// The base of the delta: a full export, or a block number
+    #[clap(long)]
+    from: String,
+    /// The block to compute the delta to
+    #[clap(long)]
+    to: u32,
+    #[clap(long, value_enum, default_value_t)]
+    format: ExportFormat,
+    /// The maximum number of requests in flight
+    #[clap(
+        long,
+        default_value_t = 8,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrency: usize,
+}
+
+/// Rebuild a full export from a base export and a chain of deltas.
+#[derive(Parser, Debug)]
+pub struct DBApplyCmd {
+    /// The full export the first delta applies to
+    base: String,
+    /// The deltas to apply, in order
+    #[clap(required = true)]
+    deltas: Vec<String>,
+    /// The export to write
+    #[clap(short, long)]
+    output_file: String,
+    #[clap(long, value_enum, default_value_t)]
+    format: ExportFormat,
+}
+
+/// Export the entries changed between the two blocks.
+///
+/// Root entries are found by querying the changes of the keys of both blocks over the block range,
+/// child tries are only fetched when their root entry changed.
+pub async fn export_delta(client: &NodeClient, cmd: DBDeltaCmd) -> Result<()> {
+    // the base export, or the block to fetch it from
+    let (from_block, base) = match cmd.from.parse::<u32>() {
+        Ok(block_number) => (block_number, None),
+        Err(_) => {
+            let manifest = Manifest::load(&cmd.from)?
+                .ok_or_else(|| anyhow::format_err!("{} has no manifest", cmd.from))?;
+            if !manifest.is_full() || manifest.delta_from.is_some() {
+                anyhow::bail!("{} is not a full export", cmd.from);
+            }
+            (manifest.block_number, Some(DBExport::load(&cmd.from)?))
+        }
+    };
+
+    let from = client.get_blockhash(from_block).await?;
+    let to = client.get_blockhash(cmd.to).await?;
+
+    let mut keys = client
+        .stream_keys(None, None, PAGE_SIZE, to.into())
+        .try_collect::<BTreeSet<_>>()
+        .await?;
+    match &base {
+        Some(base) => keys.extend(base.root.keys().cloned()),
+        None => keys.extend(
+            client
+                .stream_keys(None, None, PAGE_SIZE, from.into())
+                .try_collect::<Vec<_>>()
+                .await?,
+        ),
+    }
+
+    // the first change set of each query holds the values at `from`, the others their changes
+    let mut before = TrieEntries::new();
+    let mut after = TrieEntries::new();
+    let changes =
+        client.stream_storage_changes(keys.into_iter().collect(), from, to, cmd.concurrency);
+    tokio::pin!(changes);
+    while let Some(change_sets) = changes.try_next().await? {
+        let mut change_sets = change_sets.into_iter();
+        if let Some(change_set) = change_sets.next() {
+            before.extend(change_set.changes.clone());
+            after.extend(change_set.changes);
+        }
+        for change_set in change_sets {
+            after.extend(change_set.changes);
+        }
+    }
+    let root = TrieDiff::new(&before, &after);
+
+    let mut child_tries = BTreeMap::new();
+    let changed_child_tries = root
+        .0
+        .iter()
+        .filter(|change| change.key.0.starts_with(DEFAULT_CHILD_STORAGE_KEY_PREFIX));
+    for change in changed_child_tries {
+        let key = &change.key;
+        let before = match (&base, &change.before) {
+            (_, None) => TrieEntries::new(),
+            (Some(base), _) => base.child_tries.get(key).cloned().unwrap_or_default(),
+            (None, _) => child_trie_entries(client, key, from, cmd.concurrency).await?,
+        };
+
+        // removed child tries are dropped with their root entry
+        if change.after.is_some() {
+            let after = child_trie_entries(client, key, to, cmd.concurrency).await?;
+            child_tries.insert(key.clone(), TrieDiff::new(&before, &after));
+        }
+    }
+
+    let delta = delta_export(&DBDiff { root, child_tries });
+    let writer = DBExportWriter::create(&cmd.output_file, cmd.format)?;
+    let digest = delta.write(writer)?;
+
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        format: cmd.format,
+        block_number: cmd.to,
+        block_hash: to,
+        state_root: client.get_header(to).await?.state_root,
+        prefixes: vec!["0x".to_string()],
+        counts: digest.counts,
+        content_hash: digest.content_hash,
+        delta_from: Some(BlockRef {
+            block_number: from_block,
+            block_hash: from,
+        }),
+    };
+    manifest.write(&cmd.output_file)
+}
+
+/// Apply the deltas to the base export, checking that each of them applies to the block of the
+/// previous one.
+pub fn apply_deltas(cmd: DBApplyCmd) -> Result<()> {
+    let mut block = Manifest::load(&cmd.base)?.map(|manifest| BlockRef {
+        block_number: manifest.block_number,
+        block_hash: manifest.block_hash,
+    });
+    let mut export = DBExport::load(&cmd.base)?;
+    let mut state_root = None;
+
+    for delta in &cmd.deltas {
+        let manifest =
+            Manifest::load(delta)?.ok_or_else(|| anyhow::format_err!("{delta} has no manifest"))?;
+        let Some(delta_from) = manifest.delta_from else {
+            anyhow::bail!("{delta} is not a delta export");
+        };
+
+        // a base exported before manifests existed can not be checked
+        if let Some(block) = block {
+            if block.block_hash != delta_from.block_hash {
+                anyhow::bail!(
+                    "{delta} applies to block {}, not to block {}",
+                    delta_from.block_number,
+                    block.block_number
+                );
+            }
+        }
+
+        export.apply(DBExport::load(delta)?);
+        block = Some(BlockRef {
+            block_number: manifest.block_number,
+            block_hash: manifest.block_hash,
+        });
+        state_root = Some(manifest.state_root);
+    }
+
+    let (Some(block), Some(state_root)) = (block, state_root) else {
+        unreachable!("at least one delta is applied");
+    };
+
+    let writer = DBExportWriter::create(&cmd.output_file, cmd.format)?;
+    let digest = export.write(writer)?;
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        format: cmd.format,
+        block_number: block.block_number,
+        block_hash: block.block_hash,
+        state_root,
+        prefixes: vec!["0x".to_string()],
+        counts: digest.counts,
+        content_hash: digest.content_hash,
+        delta_from: None,
+    };
+    manifest.write(&cmd.output_file)
+}
+
+/// Fetch the entries of a child trie.
+async fn child_trie_entries(
+    client: &NodeClient,
+    child_key: &StorageKey,
+    block_hash: H256,
+    concurrency: usize,
+) -> Result<TrieEntries> {
+    client
+        .stream_child_storage_pairs(
+            child_key.clone(),
+            None,
+            None,
+            PAGE_SIZE,
+            block_hash.into(),
+            concurrency,
+        )
+        .try_collect()
+        .await
+}
+
+/// The delta export of a diff: the value after each change, `None` for removed keys.
+fn delta_export(diff: &DBDiff) -> DBExport {
+    let entries = |diff: &TrieDiff| {
+        diff.0
+            .iter()
+            .map(|change| (change.key.clone(), change.after.clone()))
+            .collect::<TrieEntries>()
+    };
+
+    DBExport {
+        root: entries(&diff.root),
+        child_tries: diff
+            .child_tries
+            .iter()
+            .map(|(key, diff)| (key.clone(), entries(diff)))
+            .collect(),
+    }
+}
@@ -0,0 +1,431 @@
+//! Compare two database exports, or the state of two live blocks.
+
+use crate::db_export::{
+    stream_state, ChildTries, DBExport, Manifest, StateFilter, StatePosition, TrieEntries,
+};
+use crate::decode::StorageDecoder;
+use crate::node_client::{to_hex, NodeClient};
+use crate::rpc::NodeOptions;
+use anyhow::Result;
+use clap::Parser;
+use itertools::{EitherOrBoth, Itertools};
+use sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use std::collections::BTreeMap;
+use subxt::rpc::types::StorageData;
+use subxt::storage::StorageKey;
+
+#[test]
+fn test_diff_exports() {
+    let a = DBExport::load("db-1.json").unwrap();
+    let b = DBExport::load("db-2.json").unwrap();
+    let diff = DBDiff::new(&a, &b);
+
+    // two contracts were instantiated at block 2, each with its own child trie
+    assert_eq!(
+        diff.root.counts(),
+        Counts {
+            added: 11,
+            removed: 2,
+            changed: 12
+        }
+    );
+    assert_eq!(diff.child_tries.len(), 2);
+    for (key, changes) in &diff.child_tries {
+        assert!(!a.root.contains_key(key) && b.root.contains_key(key));
+        assert_eq!(
+            changes.counts(),
+            Counts {
+                added: 1,
+                removed: 0,
+                changed: 0
+            }
+        );
+    }
+
+    assert!(DBDiff::new(&b, &b).is_empty());
+
+    let decoder = StorageDecoder::new(crate::decode::test_metadata());
+    let labels = Labels {
+        before: None,
+        after: Some(&decoder),
+    };
+    let groups = labels.group(&diff.root);
+    let counts = |name: &str| Counts::of(groups[name].iter().copied());
+    assert_eq!(
+        counts("System::Account"),
+        Counts {
+            added: 4,
+            removed: 0,
+            changed: 1
+        }
+    );
+    assert_eq!(
+        counts("Contracts::ContractInfoOf"),
+        Counts {
+            added: 2,
+            removed: 0,
+            changed: 0
+        }
+    );
+    assert_eq!(
+        counts(":child_storage:default:"),
+        Counts {
+            added: 2,
+            removed: 0,
+            changed: 0
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_diff_blocks_from_node() {
+    let node = crate::mock_node::MockNode::fixtures().await;
+    let cmd = DBDiffCmd::parse_from(["db-diff", "--at-a", "1", "--at-b", "2", "--decode"]);
+    diff_db(node.options(), cmd).await.unwrap();
+
+    // exports are decoded with the metadata of the block in their manifest
+    let client = node.client().await;
+    let mut files = Vec::new();
+    for block in ["1", "2"] {
+        let file = crate::mock_node::temp_path(&format!("diff-{block}.json"));
+        let file = file.to_str().unwrap().to_string();
+        let cmd = crate::db_export::DBExportCmd::parse_from(["db-export", &file, block]);
+        crate::db_export::export_db(&client, cmd).await.unwrap();
+        files.push(file);
+    }
+    let cmd = DBDiffCmd::parse_from(["db-diff", &files[0], &files[1], "--decode"]);
+    diff_db(node.options(), cmd).await.unwrap();
+
+    let cmd = DBDiffCmd::parse_from(["db-diff", "db-1.json", "db-2.json", "--decode"]);
+    let err = diff_db(node.options(), cmd).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "db-1.json has no manifest, its block is unknown"
+    );
+}
+
+/// Compare two database exports, or the state at two blocks, and print the added, removed and
+/// changed keys.
+#[derive(Parser, Debug)]
+pub struct DBDiffCmd {
+    /// The export to compare from
+    #[clap(requires = "b", required_unless_present = "at_a")]
+    a: Option<String>,
+    /// The export to compare to
+    b: Option<String>,
+    /// Compare the live state from this block, instead of two exports
+    #[clap(long, requires = "at_b", conflicts_with = "a")]
+    at_a: Option<u32>,
+    /// Compare the live state to this block
+    #[clap(long, requires = "at_a")]
+    at_b: Option<u32>,
+    /// The maximum number of requests in flight, when comparing live blocks
+    #[clap(
+        long,
+        default_value_t = 8,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrency: usize,
+    #[clap(flatten)]
+    filter: StateFilter,
+    /// Decode the map keys and the values before and after the change
+    #[clap(long)]
+    decode: bool,
+}
+
+/// Compare the two states and print the changes.
+///
+/// The node is only used for live blocks, or to fetch the metadata with `--decode` or a filter:
+/// exports are decoded with the metadata of the block in their manifest, which `--offline` can
+/// serve. Filters decode the `Contracts::ContractInfoOf` values to keep the child tries they
+/// reference.
+pub async fn diff_db(node: NodeOptions, cmd: DBDiffCmd) -> Result<()> {
+    let prefixes = cmd.filter.prefixes()?;
+
+    let (a, b, metadata) = match (&cmd.a, &cmd.b, cmd.at_a, cmd.at_b) {
+        (Some(a_file), Some(b_file), _, _) => {
+            let mut a = DBExport::load(a_file)?;
+            let mut b = DBExport::load(b_file)?;
+
+            // the whole state holds the root entries of every child trie
+            let filtered = prefixes != [Vec::<u8>::new()];
+            let metadata = if cmd.decode || filtered {
+                // decode each export with the runtime of its own block
+                let client = NodeClient::connect(node).await?;
+                let mut metadata = Vec::new();
+                for export in [a_file, b_file] {
+                    let manifest = Manifest::load(export)?.ok_or_else(|| {
+                        anyhow::format_err!("{export} has no manifest, its block is unknown")
+                    })?;
+                    metadata.push(client.get_metadata(Some(manifest.block_hash)).await?);
+                }
+                let b = metadata.pop().expect("two exports");
+                let a = metadata.pop().expect("two exports");
+                Some((a, b))
+            } else {
+                None
+            };
+            if let (true, Some((metadata_a, metadata_b))) = (filtered, &metadata) {
+                a.retain_prefixes(&prefixes, metadata_a);
+                b.retain_prefixes(&prefixes, metadata_b);
+            }
+            (a, b, metadata.filter(|_| cmd.decode))
+        }
+        (_, _, Some(at_a), Some(at_b)) => {
+            let client = NodeClient::connect(node).await?;
+            let hash_a = client.get_blockhash(at_a).await?;
+            let hash_b = client.get_blockhash(at_b).await?;
+
+            let mut states = Vec::new();
+            for block_hash in [hash_a, hash_b] {
+                let child_tries = ChildTries::fetch(&client, block_hash).await?;
+                let items = stream_state(
+                    &client,
+                    block_hash,
+                    &child_tries,
+                    &prefixes,
+                    cmd.concurrency,
+                    None,
+                    StatePosition::default(),
+                );
+                states.push(DBExport::collect(items).await?);
+            }
+            let b = states.pop().expect("two states");
+            let a = states.pop().expect("two states");
+
+            let metadata = if cmd.decode {
+                Some((
+                    client.get_metadata(hash_a.into()).await?,
+                    client.get_metadata(hash_b.into()).await?,
+                ))
+            } else {
+                None
+            };
+            (a, b, metadata)
+        }
+        _ => anyhow::bail!("expected two exports, or --at-a and --at-b"),
+    };
+
+    let decoders = metadata.map(|(a, b)| (StorageDecoder::new(a), StorageDecoder::new(b)));
+    let labels = Labels {
+        before: decoders.as_ref().map(|(a, _)| a),
+        after: decoders.as_ref().map(|(_, b)| b),
+    };
+
+    let diff = DBDiff::new(&a, &b);
+    if diff.is_empty() {
+        println!("No changes");
+        return Ok(());
+    }
+
+    println!("Root: {}", diff.root.counts());
+    for (group, changes) in labels.group(&diff.root) {
+        println!("  {group}");
+        for change in changes {
+            println!("    {}", labels.describe(change, true));
+        }
+    }
+
+    for (key, changes) in &diff.child_tries {
+        println!("Child trie {}: {}", to_hex(key), changes.counts());
+        for change in changes {
+            println!("    {}", labels.describe(change, false));
+        }
+    }
+
+    Ok(())
+}
+
+/// A key whose value differs between the two states, `None` if the key is missing.
+#[derive(Debug)]
+pub struct KeyDiff {
+    pub key: StorageKey,
+    pub before: Option<StorageData>,
+    pub after: Option<StorageData>,
+}
+
+/// The number of added, removed and changed keys.
+#[derive(Debug, Default, PartialEq)]
+pub struct Counts {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+}
+
+impl Counts {
+    pub fn of<'a>(changes: impl IntoIterator<Item = &'a KeyDiff>) -> Self {
+        changes
+            .into_iter()
+            .fold(Self::default(), |mut counts, change| {
+                match (&change.before, &change.after) {
+                    (None, _) => counts.added += 1,
+                    (_, None) => counts.removed += 1,
+                    _ => counts.changed += 1,
+                }
+                counts
+            })
+    }
+}
+
+impl std::fmt::Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            added,
+            removed,
+            changed,
+        } = self;
+        write!(f, "{added} added, {removed} removed, {changed} changed")
+    }
+}
+
+/// The changes of a trie, sorted by key.
+#[derive(Debug, Default)]
+pub struct TrieDiff(pub Vec<KeyDiff>);
+
+impl TrieDiff {
+    /// Compare the entries of a trie, a `null` value is the same as a missing key.
+    pub fn new(a: &TrieEntries, b: &TrieEntries) -> Self {
+        let changes = a
+            .iter()
+            .merge_join_by(b, |(a, _), (b, _)| a.cmp(b))
+            .filter_map(|entries| {
+                let (key, before, after) = match entries {
+                    EitherOrBoth::Left((key, before)) => (key, before, &None),
+                    EitherOrBoth::Right((key, after)) => (key, &None, after),
+                    EitherOrBoth::Both((key, before), (_, after)) => (key, before, after),
+                };
+
+                (before != after).then(|| KeyDiff {
+                    key: key.clone(),
+                    before: before.clone(),
+                    after: after.clone(),
+                })
+            })
+            .collect();
+        Self(changes)
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts::of(self)
+    }
+}
+
+impl<'a> IntoIterator for &'a TrieDiff {
+    type Item = &'a KeyDiff;
+    type IntoIter = std::slice::Iter<'a, KeyDiff>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// The changes between two database states.
+#[derive(Debug, Default)]
+pub struct DBDiff {
+    pub root: TrieDiff,
+    /// The changed child tries, a child trie missing from one state is compared as empty.
+    pub child_tries: BTreeMap<StorageKey, TrieDiff>,
+}
+
+impl DBDiff {
+    pub fn new(a: &DBExport, b: &DBExport) -> Self {
+        let empty = TrieEntries::new();
+        let child_tries = a
+            .child_tries
+            .iter()
+            .merge_join_by(&b.child_tries, |(a, _), (b, _)| a.cmp(b))
+            .filter_map(|tries| {
+                let (key, a, b) = match tries {
+                    EitherOrBoth::Left((key, a)) => (key, a, &empty),
+                    EitherOrBoth::Right((key, b)) => (key, &empty, b),
+                    EitherOrBoth::Both((key, a), (_, b)) => (key, a, b),
+                };
+                let diff = TrieDiff::new(a, b);
+                (!diff.0.is_empty()).then(|| (key.clone(), diff))
+            })
+            .collect();
+
+        Self {
+            root: TrieDiff::new(&a.root, &b.root),
+            child_tries,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.0.is_empty() && self.child_tries.is_empty()
+    }
+}
+
+/// Label the changes with the metadata of each state, when decoding.
+struct Labels<'a> {
+    before: Option<&'a StorageDecoder>,
+    after: Option<&'a StorageDecoder>,
+}
+
+impl Labels<'_> {
+    /// Group the root changes by storage item, keys outside of the metadata are grouped by
+    /// well-known key or by their first 32 bytes.
+    fn group<'d>(&self, diff: &'d TrieDiff) -> BTreeMap<String, Vec<&'d KeyDiff>> {
+        diff.0
+            .iter()
+            .map(|change| (self.group_of(&change.key.0), change))
+            .into_group_map()
+            .into_iter()
+            .collect()
+    }
+
+    fn group_of(&self, key: &[u8]) -> String {
+        let item = [self.after, self.before]
+            .into_iter()
+            .flatten()
+            .find_map(|decoder| decoder.storage_item(key));
+
+        if let Some((pallet, storage)) = item {
+            format!("{pallet}::{storage}")
+        } else if key.starts_with(DEFAULT_CHILD_STORAGE_KEY_PREFIX) {
+            String::from_utf8_lossy(DEFAULT_CHILD_STORAGE_KEY_PREFIX).into_owned()
+        } else if key.starts_with(b":") {
+            String::from_utf8_lossy(key).into_owned()
+        } else {
+            to_hex(&key[..key.len().min(32)])
+        }
+    }
+
+    /// Describe a change on a single line, e.g. `~ 0x1234: 0x01 -> 0x02`.
+    ///
+    /// Root entries are decoded, child trie entries are opaque contract storage.
+    fn describe(&self, change: &KeyDiff, decode: bool) -> String {
+        let key = &change.key.0;
+        let format = |decoder: Option<&StorageDecoder>, value: &StorageData| {
+            decoder
+                .filter(|_| decode)
+                .and_then(|decoder| decoder.decode(key, Some(&value.0)))
+                .and_then(|entry| entry.decoded_value)
+                .map(|value| value.to_string())
+                .unwrap_or_else(|| to_hex(&value.0))
+        };
+
+        let mut label = to_hex(key);
+        let decoded_keys = [self.after, self.before]
+            .into_iter()
+            .flatten()
+            .filter(|_| decode)
+            .find_map(|decoder| decoder.decode(key, None))
+            .map(|entry| entry.keys)
+            .unwrap_or_default();
+        if !decoded_keys.is_empty() {
+            label = format!("{label} {}", serde_json::Value::from(decoded_keys));
+        }
+
+        match (&change.before, &change.after) {
+            (None, Some(after)) => format!("+ {label}: {}", format(self.after, after)),
+            (Some(before), None) => format!("- {label}: {}", format(self.before, before)),
+            (Some(before), Some(after)) => format!(
+                "~ {label}: {} -> {}",
+                format(self.before, before),
+                format(self.after, after)
+            ),
+            (None, None) => unreachable!("unchanged keys are not part of the diff"),
+        }
+    }
+}
@@ -0,0 +1,1211 @@
+//! Incremental writer and loader for database exports.
+//!
+//! Entries are written to disk as soon as they are fetched, so the memory used by an export does
+//! not depend on the size of the state. Exports are canonical: root entries, child tries and their
+//! entries are sorted by key, so two exports of the same state are byte-identical.
+
+use crate::checkpoint;
+use crate::decode::{DecodedEntry, StorageDecoder};
+use crate::node_client::{to_hex, NodeClient, StoragePair, PAGE_SIZE};
+use anyhow::Result;
+use clap::Parser;
+use frame_support::storage::storage_prefix;
+use futures::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sp_core::hashing::twox_128;
+use sp_core::storage::well_known_keys::{
+    CHILD_STORAGE_KEY_PREFIX, DEFAULT_CHILD_STORAGE_KEY_PREFIX,
+};
+use sp_core::H256;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use subxt::ext::frame_metadata::StorageEntryType;
+use subxt::ext::scale_decode::DecodeAsType;
+use subxt::metadata::Metadata;
+use subxt::rpc::types::StorageData;
+use subxt::storage::StorageKey;
+
+#[test]
+fn test_json_export() {
+    let entry =
+        |key: u8, value: u8| DBEntry::new((StorageKey(vec![key]), Some(StorageData(vec![value]))));
+
+    let mut writer = DBExportWriter::new(Vec::new(), ExportFormat::Json).unwrap();
+    writer.write_entry(&entry(1, 2)).unwrap();
+    writer.write_entry(&entry(3, 4)).unwrap();
+    writer.begin_child_trie(StorageKey(vec![5])).unwrap();
+    writer.write_entry(&entry(6, 7)).unwrap();
+    writer.begin_child_trie(StorageKey(vec![8])).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_slice(&writer.finish_into_inner().unwrap()).unwrap();
+
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "version": FORMAT_VERSION,
+            "root": [{ "key": "0x01", "value": "0x02" }, { "key": "0x03", "value": "0x04" }],
+            "child_tries": { "0x05": [{ "key": "0x06", "value": "0x07" }], "0x08": [] }
+        })
+    );
+}
+
+#[test]
+fn test_jsonl_export() {
+    let mut writer = DBExportWriter::new(Vec::new(), ExportFormat::Jsonl).unwrap();
+    writer
+        .write_entry(&DBEntry::new((StorageKey(vec![1]), None)))
+        .unwrap();
+    writer.begin_child_trie(StorageKey(vec![2])).unwrap();
+    writer
+        .write_entry(&DBEntry::new((
+            StorageKey(vec![3]),
+            Some(StorageData(vec![4])),
+        )))
+        .unwrap();
+
+    let output = String::from_utf8(writer.finish_into_inner().unwrap()).unwrap();
+    assert_eq!(
+        output,
+        "{\"version\":1}\n{\"key\":\"0x01\",\"value\":null}\n{\"child_trie\":\"0x02\",\"key\":\"0x03\",\"value\":\"0x04\"}\n"
+    );
+}
+
+#[test]
+fn test_canonical_export() {
+    let entry = |key: u8| DBEntry::new((StorageKey(vec![key]), None));
+
+    let mut writer = DBExportWriter::new(Vec::new(), ExportFormat::Json).unwrap();
+    writer.write_entry(&entry(1)).unwrap();
+    assert!(writer.write_entry(&entry(1)).is_err());
+    writer.write_entry(&entry(2)).unwrap();
+    writer.begin_child_trie(StorageKey(vec![4])).unwrap();
+    writer.write_entry(&entry(1)).unwrap();
+    assert!(writer.begin_child_trie(StorageKey(vec![3])).is_err());
+
+    let (output, digest) = writer.finish_into_parts().unwrap();
+    assert_eq!(
+        digest.counts,
+        EntryCounts {
+            root_entries: 2,
+            child_tries: 1,
+            child_trie_entries: 1
+        }
+    );
+    assert_eq!(
+        digest.content_hash.0,
+        <[u8; 32]>::from(Sha256::digest(output))
+    );
+}
+
+#[test]
+fn test_resume_export() {
+    let path = crate::mock_node::temp_path("resume-db.json");
+    let path = path.to_string_lossy().into_owned();
+
+    let entry = |key: u8| DBEntry::new((StorageKey(vec![key]), Some(StorageData(vec![key]))));
+    let items = || {
+        vec![
+            ExportItem::Entry(entry(1)),
+            ExportItem::Entry(entry(2)),
+            ExportItem::ChildTrie(StorageKey(vec![3])),
+            ExportItem::Entry(entry(4)),
+            ExportItem::Entry(entry(5)),
+        ]
+    };
+    fn write<W: Write>(writer: &mut DBExportWriter<W>, item: &ExportItem) {
+        match item {
+            ExportItem::Entry(entry) => writer.write_entry(entry).unwrap(),
+            ExportItem::ChildTrie(key) => writer.begin_child_trie(key.clone()).unwrap(),
+        }
+    }
+    let child_tries = ChildTries::new([], &crate::decode::test_metadata());
+
+    for interrupted_at in 0..5 {
+        let mut writer = DBExportWriter::create(&path, ExportFormat::Json).unwrap();
+        let mut position = StatePosition::default();
+        let mut written = items().into_iter();
+        for item in written.by_ref().take(interrupted_at) {
+            write(&mut writer, &item);
+            position.advance(&item, &child_tries);
+        }
+        let offset = writer.flush().unwrap();
+        let counts = writer.counts;
+        // written after the checkpoint, and dropped on resume
+        if let Some(item) = written.next() {
+            write(&mut writer, &item);
+        }
+        drop(writer);
+
+        let mut writer =
+            DBExportWriter::resume(&path, ExportFormat::Json, offset, counts, &position).unwrap();
+        for item in items().iter().skip(interrupted_at) {
+            write(&mut writer, item);
+        }
+        let digest = writer.finish().unwrap();
+
+        let content = std::fs::read(&path).unwrap();
+        let mut expected = DBExportWriter::new(Vec::new(), ExportFormat::Json).unwrap();
+        for item in &items() {
+            write(&mut expected, item);
+        }
+        assert_eq!(content, expected.finish_into_inner().unwrap());
+        assert_eq!(digest.content_hash, H256(Sha256::digest(&content).into()));
+    }
+}
+
+#[test]
+fn test_load_export() {
+    let export = |format| {
+        let entry = |key: u8, value: Option<u8>| {
+            DBEntry::new((StorageKey(vec![key]), value.map(|v| StorageData(vec![v]))))
+        };
+
+        let mut writer = DBExportWriter::new(Vec::new(), format).unwrap();
+        writer.write_entry(&entry(1, Some(2))).unwrap();
+        writer.write_entry(&entry(3, None)).unwrap();
+        writer.begin_child_trie(StorageKey(vec![5])).unwrap();
+        writer.write_entry(&entry(6, Some(7))).unwrap();
+        let output = writer.finish_into_inner().unwrap();
+        let content = std::str::from_utf8(&output).unwrap();
+        let export = DBExport::parse(content, None).unwrap();
+        assert_eq!(DBExport::parse(content, Some(format)).unwrap(), export);
+        export
+    };
+
+    let json = export(ExportFormat::Json);
+    assert_eq!(json, export(ExportFormat::Jsonl));
+    assert_eq!(
+        json.root,
+        TrieEntries::from([
+            (StorageKey(vec![1]), Some(StorageData(vec![2]))),
+            (StorageKey(vec![3]), None)
+        ])
+    );
+    assert_eq!(
+        json.child_tries[&StorageKey(vec![5])],
+        TrieEntries::from([(StorageKey(vec![6]), Some(StorageData(vec![7])))])
+    );
+
+    assert!(DBExport::parse("{\"version\":2}\n", None).is_err());
+    // a json export on a single line is not mistaken for a jsonl header
+    let compact = "{\"version\":1,\"root\":[],\"child_tries\":{}}";
+    assert_eq!(DBExport::parse(compact, None).unwrap(), DBExport::default());
+
+    // the fixtures are unversioned json exports
+    let fixture = DBExport::load("db-1.json").unwrap();
+    assert_eq!((fixture.root.len(), fixture.child_tries.len()), (55, 1));
+}
+
+#[test]
+fn test_export_prefixes() {
+    let cmd = DBExportCmd::parse_from([
+        "db-export",
+        "db.json",
+        "0",
+        "--storage",
+        "System::Account",
+        "--pallet",
+        "System",
+        "--prefix",
+        "0x3a636f6465",
+    ]);
+
+    let mut system = twox_128(b"System").to_vec();
+    let prefixes = cmd.filter.prefixes().unwrap();
+    assert_eq!(prefixes, vec![system.clone(), b":code".to_vec()]);
+
+    system.extend(twox_128(b"Account"));
+    let cmd =
+        DBExportCmd::parse_from(["db-export", "db.json", "0", "--storage", "System::Account"]);
+    assert_eq!(cmd.filter.prefixes().unwrap(), vec![system]);
+
+    let cmd = DBExportCmd::parse_from(["db-export", "db.json", "0"]);
+    assert_eq!(cmd.filter.prefixes().unwrap(), vec![Vec::<u8>::new()]);
+}
+
+#[tokio::test]
+async fn test_export_db_from_node() {
+    let node = crate::mock_node::MockNode::fixtures().await;
+    let client = node.client().await;
+    let output_file = crate::mock_node::temp_path("db-3.jsonl");
+    let output_file = output_file.to_str().unwrap();
+
+    let cmd = DBExportCmd::parse_from(["db-export", output_file, "3", "--format", "jsonl"]);
+    export_db(&client, cmd).await.unwrap();
+    assert_eq!(
+        DBExport::load(output_file).unwrap(),
+        DBExport::load("db-3.json").unwrap()
+    );
+    let manifest = Manifest::load(output_file).unwrap().unwrap();
+    assert_eq!(manifest.block_hash, client.get_blockhash(3).await.unwrap());
+    assert!(manifest.is_full());
+
+    // only the storage of the given pallet
+    let cmd = DBExportCmd::parse_from(["db-export", output_file, "3", "--pallet", "System"]);
+    export_db(&client, cmd).await.unwrap();
+    let mut expected = DBExport::load("db-3.json").unwrap();
+    let metadata = client.get_metadata(None).await.unwrap();
+    expected.retain_prefixes(&[twox_128(b"System").to_vec()], &metadata);
+    assert_eq!(DBExport::load(output_file).unwrap(), expected);
+}
+
+#[test]
+fn test_referenced_child_tries() {
+    // the contracts of the fixtures, with their child tries
+    let export = DBExport::load("db-4.json").unwrap();
+    let metadata = crate::decode::test_metadata();
+    let child_tries = ChildTries::new(export.child_tries.keys().cloned(), &metadata);
+
+    let referenced = |key: &StorageKey, value: Option<&StorageData>| {
+        let entry = DBEntry::new((key.clone(), value.cloned()));
+        child_tries
+            .referenced_by(&entry)
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let prefix = storage_prefix(b"Contracts", b"ContractInfoOf");
+    let contract_infos = export
+        .root
+        .iter()
+        .filter(|(key, _)| key.0.starts_with(&prefix))
+        .collect::<Vec<_>>();
+    // each contract references its own child trie
+    let mut contract_tries = contract_infos
+        .iter()
+        .flat_map(|(key, value)| referenced(key, value.as_ref()))
+        .collect::<Vec<_>>();
+    contract_tries.sort();
+    assert_eq!(
+        contract_tries,
+        export.child_tries.keys().cloned().collect::<Vec<_>>()
+    );
+
+    // the root entry of a child trie references it
+    let (child_key, _) = export.child_tries.iter().next().unwrap();
+    let root = export.root[child_key].as_ref();
+    assert_eq!(referenced(child_key, root), vec![child_key.clone()]);
+
+    // a trie id embedded in another value is not a reference
+    let (key, value) = contract_infos[0];
+    let other_key = StorageKey(storage_prefix(b"System", b"Account").to_vec());
+    assert!(referenced(&other_key, value.as_ref()).is_empty());
+    assert!(referenced(key, None).is_empty());
+}
+
+/// Export the database at the given block, including child tries.
+#[derive(Parser, Debug)]
+pub struct DBExportCmd {
+    output_file: String,
+    at_block: u32,
+    #[clap(long, value_enum, default_value_t)]
+    format: ExportFormat,
+    /// The maximum number of requests in flight
+    #[clap(
+        long,
+        default_value_t = 8,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrency: usize,
+    #[clap(flatten)]
+    filter: StateFilter,
+    /// Label the root entries with their pallet, storage item, map keys and decoded value
+    #[clap(long)]
+    decode: bool,
+    /// Continue an interrupted export from its checkpoint
+    #[clap(long)]
+    resume: bool,
+}
+
+/// Restrict the exported state to some pallets, storage items or key prefixes.
+#[derive(clap::Args, Debug)]
+pub struct StateFilter {
+    /// Only export the storage of the given pallet, e.g. `Contracts`
+    #[clap(long)]
+    pallet: Vec<String>,
+    /// Only export the given storage item, e.g. `Contracts::ContractInfoOf`
+    #[clap(long)]
+    storage: Vec<String>,
+    /// Only export the keys starting with the given hex prefix
+    #[clap(long)]
+    prefix: Vec<String>,
+}
+
+impl StateFilter {
+    /// The sorted, non-overlapping key prefixes to export.
+    pub fn prefixes(&self) -> Result<Vec<Vec<u8>>> {
+        let mut prefixes = BTreeSet::new();
+
+        for pallet in &self.pallet {
+            prefixes.insert(twox_128(pallet.as_bytes()).to_vec());
+        }
+
+        for storage in &self.storage {
+            let (pallet, item) = storage.split_once("::").ok_or_else(|| {
+                anyhow::format_err!("invalid storage item {storage}, expected Pallet::Item")
+            })?;
+            prefixes.insert([twox_128(pallet.as_bytes()), twox_128(item.as_bytes())].concat());
+        }
+
+        for prefix in &self.prefix {
+            let prefix = hex::decode(prefix.trim_start_matches("0x"))
+                .map_err(|reason| anyhow::format_err!("invalid prefix {prefix}: {reason}"))?;
+            prefixes.insert(prefix);
+        }
+
+        if prefixes.is_empty() {
+            return Ok(vec![Vec::new()]);
+        }
+
+        // drop the prefixes already covered by a shorter one
+        let mut result: Vec<Vec<u8>> = Vec::new();
+        for prefix in prefixes {
+            if !result.last().is_some_and(|last| prefix.starts_with(last)) {
+                result.push(prefix);
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// The number of items exported between two checkpoints.
+const CHECKPOINT_INTERVAL: usize = 1000;
+
+/// The progress of [`export_db`], see [`crate::checkpoint`].
+#[derive(Serialize, Deserialize)]
+struct ExportCheckpoint {
+    block_hash: H256,
+    format: ExportFormat,
+    prefixes: Vec<String>,
+    decode: bool,
+    /// The number of bytes of the output written up to `position`.
+    offset: u64,
+    counts: EntryCounts,
+    position: StatePosition,
+}
+
+/// Export the database, streaming the root entries matching the requested prefixes, followed by
+/// the child tries they reference.
+pub async fn export_db(client: &NodeClient, cmd: DBExportCmd) -> Result<()> {
+    let block_hash = client.get_blockhash(cmd.at_block).await?;
+    let prefixes = cmd.filter.prefixes()?;
+    let hex_prefixes = prefixes.iter().map(to_hex).collect::<Vec<_>>();
+
+    let (mut writer, mut position) = if cmd.resume {
+        let checkpoint: ExportCheckpoint = checkpoint::load(&cmd.output_file)?
+            .ok_or_else(|| anyhow::format_err!("no checkpoint found for {}", cmd.output_file))?;
+        if checkpoint.block_hash != block_hash
+            || checkpoint.format != cmd.format
+            || checkpoint.prefixes != hex_prefixes
+            || checkpoint.decode != cmd.decode
+        {
+            anyhow::bail!(
+                "the checkpoint of {} was written with other arguments",
+                cmd.output_file
+            );
+        }
+
+        let writer = DBExportWriter::resume(
+            &cmd.output_file,
+            cmd.format,
+            checkpoint.offset,
+            checkpoint.counts,
+            &checkpoint.position,
+        )?;
+        (writer, checkpoint.position)
+    } else {
+        let writer = DBExportWriter::create(&cmd.output_file, cmd.format)?;
+        (writer, StatePosition::default())
+    };
+
+    let decoder = if cmd.decode {
+        Some(StorageDecoder::new(
+            client.get_metadata(block_hash.into()).await?,
+        ))
+    } else {
+        None
+    };
+
+    let child_tries = ChildTries::fetch(client, block_hash).await?;
+    let items = stream_state(
+        client,
+        block_hash,
+        &child_tries,
+        &prefixes,
+        cmd.concurrency,
+        decoder.as_ref(),
+        position.clone(),
+    );
+    tokio::pin!(items);
+
+    let mut exported = 0;
+    while let Some(item) = items.try_next().await? {
+        match &item {
+            ExportItem::Entry(entry) => writer.write_entry(entry)?,
+            ExportItem::ChildTrie(key) => writer.begin_child_trie(key.clone())?,
+        }
+        position.advance(&item, &child_tries);
+
+        exported += 1;
+        if exported % CHECKPOINT_INTERVAL == 0 {
+            let checkpoint = ExportCheckpoint {
+                block_hash,
+                format: cmd.format,
+                prefixes: hex_prefixes.clone(),
+                decode: cmd.decode,
+                offset: writer.flush()?,
+                counts: writer.counts,
+                position: position.clone(),
+            };
+            checkpoint::save(&cmd.output_file, &checkpoint)?;
+        }
+    }
+    let ExportDigest {
+        counts,
+        content_hash,
+    } = writer.finish()?;
+
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        format: cmd.format,
+        block_number: cmd.at_block,
+        block_hash,
+        state_root: client.get_header(block_hash).await?.state_root,
+        prefixes: hex_prefixes,
+        counts,
+        content_hash,
+        delta_from: None,
+    };
+    manifest.write(&cmd.output_file)?;
+    checkpoint::remove(&cmd.output_file)
+}
+
+/// An item of the state, in export order.
+pub enum ExportItem {
+    /// An entry of the root, or of the last started child trie.
+    Entry(DBEntry),
+    /// The start of a child trie.
+    ChildTrie(StorageKey),
+}
+
+/// A position in a streamed state, to resume streaming from.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct StatePosition {
+    /// The child trie being streamed, `None` while streaming the root.
+    pub child_trie: Option<StorageKey>,
+    /// The last key streamed from the current trie.
+    pub last_key: Option<StorageKey>,
+    /// The child tries referenced by the root entries streamed so far.
+    pub referenced: BTreeSet<StorageKey>,
+}
+
+impl StatePosition {
+    /// Move past the given item.
+    pub fn advance(&mut self, item: &ExportItem, child_tries: &ChildTries) {
+        match item {
+            ExportItem::Entry(entry) => {
+                if self.child_trie.is_none() {
+                    self.referenced
+                        .extend(child_tries.referenced_by(entry).cloned());
+                }
+                self.last_key = Some(entry.key.clone());
+            }
+            ExportItem::ChildTrie(key) => {
+                self.child_trie = Some(key.clone());
+                self.last_key = None;
+            }
+        }
+    }
+}
+
+/// Stream the root entries matching the given prefixes, followed by the child tries they
+/// reference, starting after the given position. Root entries are decoded when a decoder is given.
+pub fn stream_state<'a>(
+    client: &'a NodeClient,
+    block_hash: H256,
+    child_tries: &'a ChildTries,
+    prefixes: &'a [Vec<u8>],
+    concurrency: usize,
+    decoder: Option<&'a StorageDecoder>,
+    from: StatePosition,
+) -> impl Stream<Item = Result<ExportItem>> + 'a {
+    async_stream::try_stream! {
+        let mut position = from.clone();
+
+        if from.child_trie.is_none() {
+            for prefix in prefixes {
+                // prefixes are sorted, so a prefix before the last key is already streamed
+                let start_key = match &from.last_key {
+                    Some(key) if key.0.starts_with(prefix) => Some(key.clone()),
+                    Some(key) if key.0.as_slice() > prefix.as_slice() => continue,
+                    _ => None,
+                };
+
+                let keys = client.stream_keys(Some(prefix), start_key, PAGE_SIZE, block_hash.into());
+                let root = client.stream_storage_pairs(keys, block_hash.into(), concurrency);
+                tokio::pin!(root);
+
+                while let Some(pair) = root.try_next().await? {
+                    let mut entry = DBEntry::new(pair);
+                    if let Some(decoder) = decoder {
+                        let value = entry.value.as_ref().map(|v| v.0.as_slice());
+                        entry.decoded = decoder.decode(&entry.key.0, value);
+                    }
+                    let item = ExportItem::Entry(entry);
+                    position.advance(&item, child_tries);
+                    yield item;
+                }
+            }
+        }
+
+        for child_key in position.referenced {
+            // the child trie being streamed was already started
+            let start_key = match &from.child_trie {
+                Some(key) if child_key < *key => continue,
+                Some(key) if child_key == *key => from.last_key.clone(),
+                _ => {
+                    yield ExportItem::ChildTrie(child_key.clone());
+                    None
+                }
+            };
+
+            let pairs = client.stream_child_storage_pairs(
+                child_key,
+                None,
+                start_key,
+                PAGE_SIZE,
+                block_hash.into(),
+                concurrency,
+            );
+            tokio::pin!(pairs);
+
+            while let Some(pair) = pairs.try_next().await? {
+                yield ExportItem::Entry(DBEntry::new(pair));
+            }
+        }
+    }
+}
+
+/// The `pallet_contracts::ContractInfo` fields we care about.
+#[derive(Debug, DecodeAsType)]
+#[decode_as_type(crate_path = "subxt::ext::scale_decode")]
+struct ContractInfo {
+    trie_id: Vec<u8>,
+}
+
+/// The child tries of the state, indexed by their trie id.
+pub struct ChildTries {
+    by_id: HashMap<Vec<u8>, StorageKey>,
+    /// The metadata and the value type of `Contracts::ContractInfoOf`, if the runtime has it.
+    contract_info: Option<(Metadata, u32)>,
+}
+
+impl ChildTries {
+    /// Fetch the child tries of the state at the given block.
+    pub async fn fetch(client: &NodeClient, block_hash: H256) -> Result<Self> {
+        let keys = client
+            .stream_keys(
+                Some(CHILD_STORAGE_KEY_PREFIX),
+                None,
+                PAGE_SIZE,
+                block_hash.into(),
+            )
+            .try_collect::<Vec<_>>()
+            .await?;
+        let metadata = client.get_metadata(block_hash.into()).await?;
+        Ok(Self::new(keys, &metadata))
+    }
+
+    /// Index the given child tries, decoding the root entries with the given metadata.
+    pub fn new(keys: impl IntoIterator<Item = StorageKey>, metadata: &Metadata) -> Self {
+        let by_id = keys
+            .into_iter()
+            .map(|key| (trie_id(&key).to_vec(), key))
+            .collect::<HashMap<_, _>>();
+        let contract_info = metadata
+            .pallet("Contracts")
+            .and_then(|pallet| pallet.storage("ContractInfoOf"))
+            .ok()
+            .and_then(|entry| match &entry.ty {
+                StorageEntryType::Map { value, .. } => Some((metadata.clone(), value.id)),
+                StorageEntryType::Plain(_) => None,
+            });
+        Self {
+            by_id,
+            contract_info,
+        }
+    }
+
+    /// The child tries referenced by a root entry: either the root entry of the child trie itself,
+    /// or the `trie_id` of a `Contracts::ContractInfoOf` entry.
+    pub fn referenced_by<'a>(&'a self, entry: &'a DBEntry) -> impl Iterator<Item = &'a StorageKey> {
+        let own_key = self
+            .by_id
+            .get(trie_id(&entry.key))
+            .filter(|key| **key == entry.key);
+
+        let contract_key = self.contract_info.as_ref().and_then(|(metadata, type_id)| {
+            let prefix = storage_prefix(b"Contracts", b"ContractInfoOf");
+            let value = entry.value.as_ref()?;
+            if !entry.key.0.starts_with(&prefix) {
+                return None;
+            }
+            let info =
+                ContractInfo::decode_as_type(&mut value.0.as_slice(), *type_id, metadata.types())
+                    .ok()?;
+            self.by_id.get(&info.trie_id)
+        });
+
+        own_key.into_iter().chain(contract_key)
+    }
+}
+
+fn trie_id(key: &StorageKey) -> &[u8] {
+    key.0
+        .strip_prefix(DEFAULT_CHILD_STORAGE_KEY_PREFIX)
+        .unwrap_or(&key.0)
+}
+
+/// A database key-value entry
+#[derive(Debug, Serialize)]
+pub struct DBEntry {
+    pub key: StorageKey,
+    pub value: Option<StorageData>,
+    /// The human readable labels of the entry, when exported with `--decode`.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub decoded: Option<DecodedEntry>,
+}
+
+impl DBEntry {
+    pub fn new((key, value): StoragePair) -> Self {
+        Self {
+            key,
+            value,
+            decoded: None,
+        }
+    }
+}
+
+/// The version of the export format, bumped on incompatible changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The file format of a database export.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// A single json object, `{ "root": [..], "child_tries": { "<key>": [..] } }`.
+    #[default]
+    Json,
+    /// A `{ "version": .. }` header followed by one json entry per line, child trie entries are
+    /// tagged with their `child_trie` key.
+    Jsonl,
+}
+
+/// The description of an export, written next to it as `<output_file>.manifest.json`.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    pub format: ExportFormat,
+    pub block_number: u32,
+    pub block_hash: H256,
+    pub state_root: H256,
+    /// The exported key prefixes, `0x` for the whole state.
+    pub prefixes: Vec<String>,
+    #[serde(flatten)]
+    pub counts: EntryCounts,
+    /// The sha256 hash of the export file.
+    pub content_hash: H256,
+    /// The block a delta export applies to, see `db-delta`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta_from: Option<BlockRef>,
+}
+
+/// A block, by number and hash.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct BlockRef {
+    pub block_number: u32,
+    pub block_hash: H256,
+}
+
+impl Manifest {
+    pub fn path(output_file: &str) -> String {
+        format!("{output_file}.manifest.json")
+    }
+
+    /// Load the manifest of the given export, if any.
+    pub fn load(output_file: &str) -> Result<Option<Self>> {
+        match std::fs::read_to_string(Self::path(output_file)) {
+            Ok(manifest) => Ok(Some(serde_json::from_str(&manifest)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the manifest of the given export.
+    pub fn write(&self, output_file: &str) -> Result<()> {
+        let file = BufWriter::new(File::create(Self::path(output_file))?);
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Whether the export holds the whole state, rather than some prefixes.
+    pub fn is_full(&self) -> bool {
+        self.prefixes == ["0x"]
+    }
+}
+
+/// The number of entries of an export.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct EntryCounts {
+    pub root_entries: usize,
+    pub child_tries: usize,
+    pub child_trie_entries: usize,
+}
+
+/// What a [`DBExportWriter`] wrote.
+#[derive(Debug)]
+pub struct ExportDigest {
+    pub counts: EntryCounts,
+    /// The sha256 hash of the output.
+    pub content_hash: H256,
+}
+
+/// The entries of a trie, indexed by key.
+pub type TrieEntries = BTreeMap<StorageKey, Option<StorageData>>;
+
+/// A database export loaded in memory.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DBExport {
+    pub root: TrieEntries,
+    pub child_tries: BTreeMap<StorageKey, TrieEntries>,
+}
+
+/// An entry read back from an export, the decoded labels are ignored.
+#[derive(Deserialize)]
+struct ExportedEntry {
+    #[serde(default)]
+    child_trie: Option<StorageKey>,
+    key: StorageKey,
+    value: Option<StorageData>,
+}
+
+/// The first line of a [`ExportFormat::Jsonl`] export.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonlHeader {
+    version: u32,
+}
+
+/// The layout of a [`ExportFormat::Json`] export.
+#[derive(Deserialize)]
+struct JsonExport {
+    /// Missing from the exports written before the format was versioned.
+    #[serde(default)]
+    version: u32,
+    root: Vec<ExportedEntry>,
+    child_tries: BTreeMap<StorageKey, Vec<ExportedEntry>>,
+}
+
+impl DBExport {
+    /// Load an export written in any of the [`ExportFormat`]s, in the format of its manifest if
+    /// any. The whole file is read into memory.
+    pub fn load(path: &str) -> Result<Self> {
+        let format = Manifest::load(path)?.map(|manifest| manifest.format);
+        let content = std::fs::read_to_string(path)
+            .map_err(|reason| anyhow::format_err!("failed to read {path}: {reason}"))?;
+        Self::parse(&content, format)
+            .map_err(|reason| anyhow::format_err!("invalid export {path}: {reason}"))
+    }
+
+    /// Parse an export in the given format. Without one, a jsonl export is recognized by its
+    /// `{"version":..}` header line.
+    fn parse(content: &str, format: Option<ExportFormat>) -> Result<Self> {
+        let mut lines = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .peekable();
+        let header = lines
+            .peek()
+            .and_then(|line| serde_json::from_str::<JsonlHeader>(line).ok());
+        let format = format.unwrap_or(match header {
+            Some(_) => ExportFormat::Jsonl,
+            None => ExportFormat::Json,
+        });
+
+        let mut export = Self::default();
+        if format == ExportFormat::Jsonl {
+            if let Some(JsonlHeader { version }) = header {
+                check_version(version)?;
+                lines.next();
+            }
+
+            for line in lines {
+                export.insert(serde_json::from_str(line)?);
+            }
+        } else {
+            let JsonExport {
+                version,
+                root,
+                child_tries,
+            } = serde_json::from_str(content)?;
+            check_version(version)?;
+            export.root = root.into_iter().map(|e| (e.key, e.value)).collect();
+            export.child_tries = child_tries
+                .into_iter()
+                .map(|(key, entries)| {
+                    (key, entries.into_iter().map(|e| (e.key, e.value)).collect())
+                })
+                .collect();
+        }
+        Ok(export)
+    }
+
+    /// Collect a streamed state in memory.
+    pub async fn collect(items: impl Stream<Item = Result<ExportItem>>) -> Result<Self> {
+        tokio::pin!(items);
+        let mut export = Self::default();
+        let mut child_trie = None;
+
+        while let Some(item) = items.try_next().await? {
+            match item {
+                ExportItem::Entry(DBEntry { key, value, .. }) => export.insert(ExportedEntry {
+                    child_trie: child_trie.clone(),
+                    key,
+                    value,
+                }),
+                ExportItem::ChildTrie(key) => {
+                    export.child_tries.entry(key.clone()).or_default();
+                    child_trie = Some(key);
+                }
+            }
+        }
+        Ok(export)
+    }
+
+    /// Write the export, child tries are only written when they have entries.
+    pub fn write<W: Write>(&self, mut writer: DBExportWriter<W>) -> Result<ExportDigest> {
+        for (key, value) in &self.root {
+            writer.write_entry(&DBEntry::new((key.clone(), value.clone())))?;
+        }
+        for (child_key, entries) in self.child_tries.iter().filter(|(_, e)| !e.is_empty()) {
+            writer.begin_child_trie(child_key.clone())?;
+            for (key, value) in entries {
+                writer.write_entry(&DBEntry::new((key.clone(), value.clone())))?;
+            }
+        }
+        writer.finish()
+    }
+
+    /// Apply a delta export on top of this one: `null` values remove their key, and child tries
+    /// whose root entry is removed are dropped.
+    pub fn apply(&mut self, delta: DBExport) {
+        fn apply_entries(entries: &mut TrieEntries, delta: TrieEntries) {
+            for (key, value) in delta {
+                match value {
+                    Some(value) => entries.insert(key, Some(value)),
+                    None => entries.remove(&key),
+                };
+            }
+        }
+
+        apply_entries(&mut self.root, delta.root);
+        for (key, entries) in delta.child_tries {
+            apply_entries(self.child_tries.entry(key).or_default(), entries);
+        }
+
+        let root = &self.root;
+        self.child_tries
+            .retain(|key, entries| root.contains_key(key) && !entries.is_empty());
+    }
+
+    /// Only keep the root entries matching the given prefixes, and the child tries they reference,
+    /// decoded with the given metadata.
+    pub fn retain_prefixes(&mut self, prefixes: &[Vec<u8>], metadata: &Metadata) {
+        self.root
+            .retain(|key, _| prefixes.iter().any(|prefix| key.0.starts_with(prefix)));
+
+        let child_tries = ChildTries::new(self.child_tries.keys().cloned(), metadata);
+        let mut referenced = BTreeSet::new();
+        for pair in &self.root {
+            let entry = DBEntry::new((pair.0.clone(), pair.1.clone()));
+            referenced.extend(child_tries.referenced_by(&entry).cloned());
+        }
+        self.child_tries.retain(|key, _| referenced.contains(key));
+    }
+
+    fn insert(&mut self, entry: ExportedEntry) {
+        let trie = match entry.child_trie {
+            Some(key) => self.child_tries.entry(key).or_default(),
+            None => &mut self.root,
+        };
+        trie.insert(entry.key, entry.value);
+    }
+}
+
+/// An entry of a [`ExportFormat::Jsonl`] export.
+#[derive(Serialize)]
+struct JsonlEntry<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    child_trie: Option<&'a StorageKey>,
+    #[serde(flatten)]
+    entry: &'a DBEntry,
+}
+
+/// The section of the export being written.
+#[derive(Debug, Clone)]
+enum Section {
+    Root,
+    ChildTrie(StorageKey),
+}
+
+/// Fail on the exports written with a newer version of the format.
+fn check_version(version: u32) -> Result<()> {
+    if version > FORMAT_VERSION {
+        anyhow::bail!("unsupported export version {version}, expected at most {FORMAT_VERSION}");
+    }
+    Ok(())
+}
+
+/// A writer hashing everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    /// The number of bytes written.
+    written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write a database export, one entry at a time.
+///
+/// Root entries must all be written before the first child trie is started, and all the entries
+/// of a child trie must be written before the next one is started. Entries and child tries must
+/// be written in increasing key order, which keeps the export canonical.
+pub struct DBExportWriter<W: Write> {
+    out: HashingWriter<W>,
+    format: ExportFormat,
+    section: Section,
+    /// The last key written in the current section.
+    last_key: Option<StorageKey>,
+    /// Whether no child trie has been started yet.
+    first_child_trie: bool,
+    counts: EntryCounts,
+}
+
+impl DBExportWriter<BufWriter<File>> {
+    /// Create the output file and start the export.
+    pub fn create(path: &str, format: ExportFormat) -> Result<Self> {
+        let file = File::create(path)?;
+        Self::new(BufWriter::new(file), format)
+    }
+
+    /// Reopen an interrupted export, dropping what was written after the given position.
+    ///
+    /// `offset` and `counts` are those of the writer once the item at `position` was written.
+    pub fn resume(
+        path: &str,
+        format: ExportFormat,
+        offset: u64,
+        counts: EntryCounts,
+        position: &StatePosition,
+    ) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|reason| anyhow::format_err!("failed to open {path}: {reason}"))?;
+
+        // hash the kept part of the output again
+        let mut hasher = Sha256::new();
+        let kept = std::io::copy(&mut (&mut file).take(offset), &mut hasher)?;
+        if kept != offset {
+            anyhow::bail!("{path} is shorter than its checkpoint");
+        }
+        file.set_len(offset)?;
+        file.seek(SeekFrom::End(0))?;
+
+        let section = match &position.child_trie {
+            Some(key) => Section::ChildTrie(key.clone()),
+            None => Section::Root,
+        };
+        Ok(Self {
+            out: HashingWriter {
+                inner: BufWriter::new(file),
+                hasher,
+                written: offset,
+            },
+            format,
+            section,
+            last_key: position.last_key.clone(),
+            first_child_trie: position.child_trie.is_none(),
+            counts,
+        })
+    }
+}
+
+impl<W: Write> DBExportWriter<W> {
+    /// Start an export into the given writer.
+    pub fn new(out: W, format: ExportFormat) -> Result<Self> {
+        let mut out = HashingWriter {
+            inner: out,
+            hasher: Sha256::new(),
+            written: 0,
+        };
+        match format {
+            ExportFormat::Json => {
+                write!(out, "{{\n  \"version\": {FORMAT_VERSION},\n  \"root\": [")?
+            }
+            ExportFormat::Jsonl => {
+                let header = JsonlHeader {
+                    version: FORMAT_VERSION,
+                };
+                serde_json::to_writer(&mut out, &header)?;
+                writeln!(out)?;
+            }
+        }
+
+        Ok(Self {
+            out,
+            format,
+            section: Section::Root,
+            last_key: None,
+            first_child_trie: true,
+            counts: EntryCounts::default(),
+        })
+    }
+
+    /// Write an entry of the current section.
+    pub fn write_entry(&mut self, entry: &DBEntry) -> Result<()> {
+        if let Some(last_key) = &self.last_key {
+            if *last_key >= entry.key {
+                anyhow::bail!(
+                    "entry {} written after {}, keys must be increasing",
+                    to_hex(&entry.key),
+                    to_hex(last_key)
+                );
+            }
+        }
+
+        match self.format {
+            ExportFormat::Json => {
+                let indent = match self.section {
+                    Section::Root => "    ",
+                    Section::ChildTrie(_) => "      ",
+                };
+                let separator = if self.last_key.is_none() { "" } else { "," };
+                write!(self.out, "{separator}\n{indent}")?;
+                serde_json::to_writer(&mut self.out, entry)?;
+            }
+            ExportFormat::Jsonl => {
+                let child_trie = match &self.section {
+                    Section::Root => None,
+                    Section::ChildTrie(key) => Some(key),
+                };
+                serde_json::to_writer(&mut self.out, &JsonlEntry { child_trie, entry })?;
+                writeln!(self.out)?;
+            }
+        }
+
+        match self.section {
+            Section::Root => self.counts.root_entries += 1,
+            Section::ChildTrie(_) => self.counts.child_trie_entries += 1,
+        }
+        self.last_key = Some(entry.key.clone());
+        Ok(())
+    }
+
+    /// Close the current section and start writing the entries of the given child trie.
+    pub fn begin_child_trie(&mut self, key: StorageKey) -> Result<()> {
+        if let Section::ChildTrie(last_key) = &self.section {
+            if *last_key >= key {
+                anyhow::bail!(
+                    "child trie {} started after {}, keys must be increasing",
+                    to_hex(&key),
+                    to_hex(last_key)
+                );
+            }
+        }
+
+        if let ExportFormat::Json = self.format {
+            self.close_section()?;
+            if self.first_child_trie {
+                write!(self.out, ",\n  \"child_tries\": {{")?;
+            } else {
+                write!(self.out, ",")?;
+            }
+            write!(self.out, "\n    ")?;
+            serde_json::to_writer(&mut self.out, &key)?;
+            write!(self.out, ": [")?;
+        }
+
+        self.section = Section::ChildTrie(key);
+        self.last_key = None;
+        self.first_child_trie = false;
+        self.counts.child_tries += 1;
+        Ok(())
+    }
+
+    /// Flush the output, and return the number of bytes written so far.
+    pub fn flush(&mut self) -> Result<u64> {
+        self.out.flush()?;
+        Ok(self.out.written)
+    }
+
+    /// Terminate the export and flush it.
+    pub fn finish(self) -> Result<ExportDigest> {
+        let (_, digest) = self.finish_into_parts()?;
+        Ok(digest)
+    }
+
+    /// Terminate the export, flush it and return the underlying writer.
+    #[cfg(test)]
+    fn finish_into_inner(self) -> Result<W> {
+        let (out, _) = self.finish_into_parts()?;
+        Ok(out)
+    }
+
+    fn finish_into_parts(mut self) -> Result<(W, ExportDigest)> {
+        if let ExportFormat::Json = self.format {
+            self.close_section()?;
+            if self.first_child_trie {
+                write!(self.out, ",\n  \"child_tries\": {{}}")?;
+            } else {
+                write!(self.out, "\n  }}")?;
+            }
+            writeln!(self.out, "\n}}")?;
+        }
+
+        self.out.flush()?;
+        let digest = ExportDigest {
+            counts: self.counts,
+            content_hash: H256(self.out.hasher.finalize().into()),
+        };
+        Ok((self.out.inner, digest))
+    }
+
+    /// Close the json array of the current section.
+    fn close_section(&mut self) -> Result<()> {
+        let indent = match self.section {
+            Section::Root => "  ",
+            Section::ChildTrie(_) => "    ",
+        };
+        if self.last_key.is_none() {
+            write!(self.out, "]")?;
+        } else {
+            write!(self.out, "\n{indent}]")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! Decode raw storage entries with the runtime metadata.
+
+use crate::node_client::to_hex;
+use scale_info::{PortableRegistry, TypeDef, TypeDefPrimitive};
+use serde::Serialize;
+use serde_json::json;
+use sp_core::hashing::twox_128;
+use std::collections::HashMap;
+use subxt::ext::frame_metadata::{StorageEntryType, StorageHasher};
+use subxt::ext::scale_value::{self, Composite, Primitive, Value, ValueDef};
+use subxt::metadata::Metadata;
+
+/// Load the metadata of the node the `db-*.json` fixtures were exported from.
+///
+/// Generated with `subxt metadata -f bytes > metadata.scale`.
+#[cfg(test)]
+pub fn test_metadata() -> Metadata {
+    use codec::Decode;
+    use subxt::ext::frame_metadata::RuntimeMetadataPrefixed;
+
+    let bytes = std::fs::read("metadata.scale").unwrap();
+    let metadata = RuntimeMetadataPrefixed::decode(&mut bytes.as_slice()).unwrap();
+    metadata.try_into().unwrap()
+}
+
+#[test]
+fn test_decode_entries() {
+    let decoder = StorageDecoder::new(test_metadata());
+    let decode = |key: &str, value: &str| {
+        let key = hex::decode(key).unwrap();
+        let value = hex::decode(value).unwrap();
+        serde_json::to_value(decoder.decode(&key, Some(&value))).unwrap()
+    };
+
+    assert_eq!(
+        decode(
+            "f0c365c3cf59d671eb72da0e7a4113c49f1f0515f462cdcf84e0f1d6045dfcbb",
+            "7b34d8c388010000"
+        ),
+        json!({ "pallet": "Timestamp", "storage": "Now", "decoded_value": 1686912906363u64 })
+    );
+
+    assert_eq!(
+        decode(
+            "4342193e496fab7ec59d615ed0dc553053f13fd319a03c211337c76e0fe776df\
+             5a90a9367a2bc43435492790c51121890d583190beb5b6dc2714f61bce196ff8",
+            "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d13000004ba0991c45f04"
+        ),
+        json!({
+            "pallet": "Contracts",
+            "storage": "OwnerInfoOf",
+            "keys": ["0x5a90a9367a2bc43435492790c51121890d583190beb5b6dc2714f61bce196ff8"],
+            "decoded_value": {
+                "owner": "0xd43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d",
+                "deposit": 6900800000000000000u64,
+                "refcount": 1
+            }
+        })
+    );
+
+    // undecodable values fall back to hex, unknown keys are not labelled
+    assert_eq!(
+        decode(
+            "f0c365c3cf59d671eb72da0e7a4113c49f1f0515f462cdcf84e0f1d6045dfcbb",
+            "7b"
+        )["decoded_value"],
+        json!("0x7b")
+    );
+    assert_eq!(decode("3a636f6465", "00"), json!(null));
+}
+
+/// The human readable labels of a storage entry.
+#[derive(Debug, Clone, Serialize)]
+pub struct DecodedEntry {
+    pub pallet: String,
+    pub storage: String,
+    /// The decoded map keys, or their hash for opaque hashers.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<serde_json::Value>,
+    /// The decoded value, or its hex encoding if it could not be decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoded_value: Option<serde_json::Value>,
+}
+
+/// A storage item of the metadata.
+struct StorageItem {
+    pallet: String,
+    storage: String,
+    ty: StorageEntryType<scale_info::form::PortableForm>,
+}
+
+/// Decode storage entries, using the metadata to find the storage item of each key.
+pub struct StorageDecoder {
+    metadata: Metadata,
+    /// The storage items, indexed by their `twox128(pallet) ++ twox128(item)` prefix.
+    items: HashMap<[u8; 32], StorageItem>,
+}
+
+impl StorageDecoder {
+    pub fn new(metadata: Metadata) -> Self {
+        let mut items = HashMap::new();
+        for pallet in &metadata.runtime_metadata().pallets {
+            let Some(storage) = &pallet.storage else {
+                continue;
+            };
+
+            for entry in &storage.entries {
+                let mut prefix = [0u8; 32];
+                prefix[..16].copy_from_slice(&twox_128(storage.prefix.as_bytes()));
+                prefix[16..].copy_from_slice(&twox_128(entry.name.as_bytes()));
+                let item = StorageItem {
+                    pallet: pallet.name.clone(),
+                    storage: entry.name.clone(),
+                    ty: entry.ty.clone(),
+                };
+                items.insert(prefix, item);
+            }
+        }
+
+        Self { metadata, items }
+    }
+
+    /// Label the given entry with its storage item, map keys and decoded value.
+    ///
+    /// Returns `None` if the key does not belong to a storage item of the metadata.
+    pub fn decode(&self, key: &[u8], value: Option<&[u8]>) -> Option<DecodedEntry> {
+        let item = self.item(key)?;
+        let mut key = &key[32..];
+
+        let (keys, value_ty) = match &item.ty {
+            StorageEntryType::Plain(ty) => (Vec::new(), ty.id),
+            StorageEntryType::Map {
+                hashers,
+                key: key_ty,
+                value,
+            } => (self.decode_keys(&mut key, hashers, key_ty.id), value.id),
+        };
+
+        Some(DecodedEntry {
+            pallet: item.pallet.clone(),
+            storage: item.storage.clone(),
+            keys,
+            decoded_value: value.map(|value| self.decode_value(value, value_ty)),
+        })
+    }
+
+    /// Decode the given bytes as the given type, falling back to hex.
+    pub fn decode_value(&self, bytes: &[u8], type_id: u32) -> serde_json::Value {
+        let mut input = bytes;
+        match scale_value::scale::decode_as_type(&mut input, type_id, self.metadata.types()) {
+            Ok(value) if input.is_empty() => value_to_json(&value, self.metadata.types()),
+            _ => json!(to_hex(bytes)),
+        }
+    }
+
+    /// The pallet and storage item names of the given key.
+    pub fn storage_item(&self, key: &[u8]) -> Option<(&str, &str)> {
+        self.item(key)
+            .map(|item| (item.pallet.as_str(), item.storage.as_str()))
+    }
+
+    fn item(&self, key: &[u8]) -> Option<&StorageItem> {
+        let prefix: [u8; 32] = key.get(..32)?.try_into().ok()?;
+        self.items.get(&prefix)
+    }
+
+    /// Decode the map keys, one per hasher. Opaque hashes are returned as hex.
+    fn decode_keys(
+        &self,
+        key: &mut &[u8],
+        hashers: &[StorageHasher],
+        key_ty: u32,
+    ) -> Vec<serde_json::Value> {
+        let types = self.metadata.types();
+        let key_types = match self.metadata.resolve_type(key_ty).map(|ty| &ty.type_def) {
+            Some(TypeDef::Tuple(tuple)) if hashers.len() > 1 => {
+                tuple.fields.iter().map(|f| f.id).collect()
+            }
+            _ => vec![key_ty],
+        };
+
+        let mut keys = Vec::new();
+        for (hasher, type_id) in hashers.iter().zip(key_types) {
+            let (hash_len, concat) = match hasher {
+                StorageHasher::Blake2_128 | StorageHasher::Twox128 => (16, false),
+                StorageHasher::Blake2_256 | StorageHasher::Twox256 => (32, false),
+                StorageHasher::Blake2_128Concat => (16, true),
+                StorageHasher::Twox64Concat => (8, true),
+                StorageHasher::Identity => (0, true),
+            };
+
+            if key.len() < hash_len {
+                break;
+            }
+            let (hash, rest) = key.split_at(hash_len);
+            *key = rest;
+
+            if !concat {
+                keys.push(json!(to_hex(hash)));
+                continue;
+            }
+
+            match scale_value::scale::decode_as_type(key, type_id, types) {
+                Ok(value) => keys.push(value_to_json(&value, types)),
+                Err(_) => break,
+            }
+        }
+
+        // keep whatever could not be decoded as hex
+        if !key.is_empty() {
+            keys.push(json!(to_hex(*key)));
+        }
+        keys
+    }
+}
+
+/// Convert a decoded value to json.
+///
+/// Byte arrays, such as account ids or hashes, are rendered as hex, and integers that do not fit
+/// in a json number are rendered as strings.
+pub fn value_to_json(value: &Value<u32>, types: &PortableRegistry) -> serde_json::Value {
+    if let Some(bytes) = as_bytes(value, types) {
+        return json!(to_hex(bytes));
+    }
+
+    match &value.value {
+        ValueDef::Composite(composite) => composite_to_json(composite, types),
+        ValueDef::Variant(variant) => match &variant.values {
+            Composite::Unnamed(values) if values.is_empty() => json!(variant.name),
+            values => json!({ variant.name.clone(): composite_to_json(values, types) }),
+        },
+        ValueDef::BitSequence(bits) => json!(bits.iter().map(u8::from).collect::<Vec<_>>()),
+        ValueDef::Primitive(primitive) => match primitive {
+            Primitive::Bool(b) => json!(b),
+            Primitive::Char(c) => json!(c),
+            Primitive::String(s) => json!(s),
+            Primitive::U128(n) => u64::try_from(*n)
+                .map(|n| json!(n))
+                .unwrap_or_else(|_| json!(n.to_string())),
+            Primitive::I128(n) => i64::try_from(*n)
+                .map(|n| json!(n))
+                .unwrap_or_else(|_| json!(n.to_string())),
+            Primitive::U256(bytes) | Primitive::I256(bytes) => json!(to_hex(bytes)),
+        },
+    }
+}
+
+fn composite_to_json(composite: &Composite<u32>, types: &PortableRegistry) -> serde_json::Value {
+    match composite {
+        Composite::Named(fields) => fields
+            .iter()
+            .map(|(name, value)| (name.clone(), value_to_json(value, types)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        // unwrap new types
+        Composite::Unnamed(values) => match values.as_slice() {
+            [value] => value_to_json(value, types),
+            values => values.iter().map(|v| value_to_json(v, types)).collect(),
+        },
+    }
+}
+
+/// Get the bytes of a sequence or array of `u8`s, looking through new types such as
+/// `AccountId32([u8; 32])`.
+pub fn as_bytes(value: &Value<u32>, types: &PortableRegistry) -> Option<Vec<u8>> {
+    let ValueDef::Composite(Composite::Unnamed(values)) = &value.value else {
+        return None;
+    };
+
+    let is_byte = |value: &Value<u32>| {
+        matches!(
+            types.resolve(value.context).map(|ty| &ty.type_def),
+            Some(TypeDef::Primitive(TypeDefPrimitive::U8))
+        )
+    };
+
+    match values.as_slice() {
+        [] => None,
+        [inner] if !is_byte(inner) => as_bytes(inner, types),
+        values => values
+            .iter()
+            .map(|value| match value.value {
+                ValueDef::Primitive(Primitive::U128(n)) if is_byte(value) => u8::try_from(n).ok(),
+                _ => None,
+            })
+            .collect(),
+    }
+}
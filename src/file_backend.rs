@@ -0,0 +1,422 @@
+//! A [`Backend`] serving the chain data from a directory of exports, so that subcommands can run
+//! without a node.
+//!
+//! The directory holds the blocks exported with `block-export` in `blocks.json`, the state of
+//! some of these blocks exported with `db-export` in `db-<block number>.json`, and the metadata of
+//! the runtime in `metadata.scale`, as written by `subxt metadata -f bytes`.
+
+use crate::backend::{Backend, BackendFuture, BackendStream, Header};
+use crate::db_export::{DBExport, TrieEntries};
+use crate::node_client::StoragePair;
+use crate::verify;
+use anyhow::Result;
+use codec::Decode;
+use futures::StreamExt;
+use sp_core::H256;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Mutex;
+use subxt::config::Header as _;
+use subxt::ext::frame_metadata::RuntimeMetadataPrefixed;
+use subxt::metadata::Metadata;
+use subxt::rpc::types::{ChainBlock, RuntimeVersion, StorageChangeSet};
+use subxt::storage::StorageKey;
+use subxt::PolkadotConfig;
+
+#[tokio::test]
+async fn test_file_backend() {
+    let backend = FileBackend::load(Path::new(".")).unwrap();
+    assert_eq!(backend.blocks.len(), 5);
+
+    let latest = backend.header(None).await.unwrap().unwrap();
+    assert_eq!(latest.number, 4);
+    let hash = backend.block_hash(Some(4)).await.unwrap().unwrap();
+    assert_eq!(hash, latest.hash());
+    assert_eq!(
+        backend.runtime_version(hash).await.unwrap().other["stateVersion"],
+        1
+    );
+    // the trie of the block is only rebuilt once
+    assert_eq!(backend.state_versions.lock().unwrap()[&4], Some(1));
+
+    // page through the keys of the root
+    let export = DBExport::load("db-4.json").unwrap();
+    let first_page = backend
+        .storage_keys_paged(None, &[], 10, None, Some(hash))
+        .await
+        .unwrap();
+    let second_page = backend
+        .storage_keys_paged(None, &[], 10, first_page.last().cloned(), Some(hash))
+        .await
+        .unwrap();
+    let keys = export.root.keys().take(20).cloned().collect::<Vec<_>>();
+    assert_eq!([first_page, second_page].concat(), keys);
+
+    let (child_key, entries) = export.child_tries.iter().next().unwrap();
+    let child_keys = backend
+        .storage_keys_paged(Some(child_key), &[], 100, None, Some(hash))
+        .await
+        .unwrap();
+    let values = backend
+        .storage_values(Some(child_key), child_keys, Some(hash))
+        .await
+        .unwrap();
+    assert_eq!(values.into_iter().collect::<TrieEntries>(), *entries);
+
+    // the first change set holds the values at the first block
+    let key = export.root.keys().next().unwrap().clone();
+    let from = backend.block_hash(Some(0)).await.unwrap().unwrap();
+    let changes = backend
+        .query_storage(vec![key.clone()], from, Some(hash))
+        .await
+        .unwrap();
+    assert_eq!(changes[0].block, from);
+    assert!(changes
+        .iter()
+        .all(|change_set| change_set.changes.len() == 1));
+    assert_eq!(
+        changes.last().unwrap().changes[0].1,
+        export.root[&key].clone()
+    );
+}
+
+#[tokio::test]
+async fn test_offline_client() {
+    use crate::db_export::{stream_state, ChildTries, StatePosition};
+    use crate::node_client::NodeClient;
+
+    let backend = FileBackend::load(Path::new(".")).unwrap();
+    let client = NodeClient::new(Box::new(backend)).await.unwrap();
+
+    let info = client.get_block_info(Some(4)).await.unwrap();
+    assert_eq!(info.version, 9);
+    assert!(!info.migration_in_progress);
+
+    let alice = sp_keyring::AccountKeyring::Alice.to_account_id();
+    let account = client
+        .get_account_info(alice.into(), Some(info.block_hash))
+        .await
+        .unwrap();
+    assert_eq!(account.unwrap().providers, 1);
+
+    // the state streamed from the exports is the exported state
+    let child_tries = ChildTries::fetch(&client, info.block_hash).await.unwrap();
+    let prefixes = [Vec::new()];
+    let items = stream_state(
+        &client,
+        info.block_hash,
+        &child_tries,
+        &prefixes,
+        1,
+        None,
+        StatePosition::default(),
+    );
+    let export = DBExport::collect(items).await.unwrap();
+    assert_eq!(export, DBExport::load("db-4.json").unwrap());
+}
+
+/// The blocks, states and metadata of a directory of exports.
+pub struct FileBackend {
+    blocks: Vec<ChainBlock<PolkadotConfig>>,
+    /// The number of each block, by hash.
+    numbers: HashMap<H256, u32>,
+    states: BTreeMap<u32, DBExport>,
+    /// The metadata of each runtime, by the first block whose state holds it.
+    runtimes: BTreeMap<u32, Metadata>,
+    /// The state version of each block whose state was rebuilt, by number.
+    state_versions: Mutex<HashMap<u32, Option<u8>>>,
+}
+
+impl FileBackend {
+    /// Load the exports of the given directory.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            std::fs::read(&path).map_err(|reason| {
+                anyhow::format_err!("failed to read {}: {reason}", path.display())
+            })
+        };
+
+        let blocks: Vec<ChainBlock<PolkadotConfig>> = serde_json::from_slice(&read("blocks.json")?)
+            .map_err(|reason| anyhow::format_err!("invalid blocks.json: {reason}"))?;
+
+        let mut states = BTreeMap::new();
+        for block in &blocks {
+            let path = dir.join(format!("db-{}.json", block.header.number));
+            if path.exists() {
+                states.insert(
+                    block.header.number,
+                    DBExport::load(&path.to_string_lossy())?,
+                );
+            }
+        }
+
+        let metadata = read_metadata(&dir.join("metadata.scale"))?;
+        Ok(Self::new(blocks, states, metadata))
+    }
+
+    /// Serve the given blocks, and the states of some of them by block number.
+    pub fn new(
+        blocks: Vec<ChainBlock<PolkadotConfig>>,
+        states: BTreeMap<u32, DBExport>,
+        metadata: Metadata,
+    ) -> Self {
+        let numbers = blocks
+            .iter()
+            .map(|block| (block.header.hash(), block.header.number))
+            .collect();
+        Self {
+            blocks,
+            numbers,
+            states,
+            runtimes: BTreeMap::from([(0, metadata)]),
+            state_versions: Mutex::default(),
+        }
+    }
+
+    /// Upgrade the runtime at the given block, whose state then holds a runtime with the given
+    /// metadata and the next spec version.
+    #[cfg(test)]
+    pub fn with_upgrade(mut self, block_number: u32, metadata: Metadata) -> Self {
+        self.runtimes.insert(block_number, metadata);
+        self
+    }
+
+    /// The spec version and metadata of the runtime at the given block, the spec version being
+    /// the number of upgrades up to the block.
+    fn runtime(&self, block_hash: H256) -> Result<(u32, &Metadata)> {
+        let number = self.number_of(Some(block_hash))?;
+        let runtimes = self.runtimes.range(..=number);
+        let spec_version = runtimes.clone().count() as u32 - 1;
+        let (_, metadata) = runtimes.last().expect("the genesis runtime is known");
+        Ok((spec_version, metadata))
+    }
+
+    fn block_at(&self, block_hash: Option<H256>) -> Option<&ChainBlock<PolkadotConfig>> {
+        match block_hash {
+            Some(hash) => {
+                let number = self.numbers.get(&hash)?;
+                self.blocks.iter().find(|b| b.header.number == *number)
+            }
+            None => self.blocks.iter().max_by_key(|b| b.header.number),
+        }
+    }
+
+    fn number_of(&self, block_hash: Option<H256>) -> Result<u32> {
+        self.block_at(block_hash)
+            .map(|block| block.header.number)
+            .ok_or_else(|| anyhow::format_err!("block {block_hash:?} not found"))
+    }
+
+    /// The state of the given block, or of the latest block.
+    fn state(&self, block_hash: Option<H256>) -> Result<&DBExport> {
+        let number = self.number_of(block_hash)?;
+        self.states
+            .get(&number)
+            .ok_or_else(|| anyhow::format_err!("no export of the state at block {number}"))
+    }
+
+    /// The state version reproducing the state root of the given block, if its state is exported.
+    ///
+    /// Rebuilding the trie is slow, so the version is only computed once per block.
+    fn state_version(&self, block_hash: H256) -> Option<u8> {
+        let header = &self.block_at(Some(block_hash))?.header;
+        if let Some(version) = self.state_versions.lock().unwrap().get(&header.number) {
+            return *version;
+        }
+
+        let version = self.state(Some(block_hash)).ok().and_then(|state| {
+            [1, 0]
+                .into_iter()
+                .find(|version| verify::verify(state, *version).is_valid(header.state_root))
+        });
+        self.state_versions
+            .lock()
+            .unwrap()
+            .insert(header.number, version);
+        version
+    }
+
+    /// The entries of the given trie, an unknown child trie is empty.
+    fn trie<'a>(
+        &'a self,
+        child_trie: Option<&StorageKey>,
+        block_hash: Option<H256>,
+    ) -> Result<Option<&'a TrieEntries>> {
+        let state = self.state(block_hash)?;
+        Ok(match child_trie {
+            Some(key) => state.child_tries.get(key),
+            None => Some(&state.root),
+        })
+    }
+}
+
+/// Read the metadata written by `subxt metadata -f bytes`.
+pub fn read_metadata(path: &Path) -> Result<Metadata> {
+    let bytes = std::fs::read(path)
+        .map_err(|reason| anyhow::format_err!("failed to read {}: {reason}", path.display()))?;
+    let metadata = RuntimeMetadataPrefixed::decode(&mut bytes.as_slice())?;
+    Ok(metadata.try_into()?)
+}
+
+impl Backend for FileBackend {
+    fn block_hash(&self, block_number: Option<u32>) -> BackendFuture<'_, Option<H256>> {
+        let block = match block_number {
+            Some(number) => self.blocks.iter().find(|b| b.header.number == number),
+            None => self.block_at(None),
+        };
+        let hash = block.map(|block| block.header.hash());
+        Box::pin(async move { Ok(hash) })
+    }
+
+    fn header(&self, block_hash: Option<H256>) -> BackendFuture<'_, Option<Header>> {
+        let header = self.block_at(block_hash).map(|block| block.header.clone());
+        Box::pin(async move { Ok(header) })
+    }
+
+    fn block(
+        &self,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'_, Option<ChainBlock<PolkadotConfig>>> {
+        let block = self.block_at(block_hash).map(|block| ChainBlock {
+            header: block.header.clone(),
+            extrinsics: block.extrinsics.clone(),
+        });
+        Box::pin(async move { Ok(block) })
+    }
+
+    /// The runtime whose state version is the one reproducing the state root of the block.
+    fn runtime_version(&self, block_hash: H256) -> BackendFuture<'_, RuntimeVersion> {
+        Box::pin(async move {
+            let (spec_version, _) = self.runtime(block_hash)?;
+
+            let mut other = HashMap::new();
+            if let Some(version) = self.state_version(block_hash) {
+                other.insert("stateVersion".to_string(), version.into());
+            }
+            Ok(RuntimeVersion {
+                spec_version,
+                transaction_version: 0,
+                other,
+            })
+        })
+    }
+
+    fn metadata(&self, block_hash: H256) -> BackendFuture<'_, Metadata> {
+        let metadata = self
+            .runtime(block_hash)
+            .map(|(_, metadata)| metadata.clone());
+        Box::pin(async move { metadata })
+    }
+
+    fn storage_keys_paged<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        prefix: &'a [u8],
+        count: u32,
+        start_key: Option<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'a, Vec<StorageKey>> {
+        Box::pin(async move {
+            let Some(trie) = self.trie(child_trie, block_hash)? else {
+                return Ok(Vec::new());
+            };
+            let start = match start_key {
+                Some(key) if key.0.as_slice() >= prefix => Bound::Excluded(key),
+                _ => Bound::Included(StorageKey(prefix.to_vec())),
+            };
+            Ok(trie
+                .range((start, Bound::Unbounded))
+                .map(|(key, _)| key)
+                .take_while(|key| key.0.starts_with(prefix))
+                .take(count as usize)
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn storage_values<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        keys: Vec<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> BackendFuture<'a, Vec<StoragePair>> {
+        Box::pin(async move {
+            let trie = self.trie(child_trie, block_hash)?;
+            Ok(keys
+                .into_iter()
+                .map(|key| {
+                    let value = trie.and_then(|trie| trie.get(&key).cloned().flatten());
+                    (key, value)
+                })
+                .collect())
+        })
+    }
+
+    /// Replay the changes of the exported states, every block of the range must be exported.
+    fn query_storage(
+        &self,
+        keys: Vec<StorageKey>,
+        from: H256,
+        to: Option<H256>,
+    ) -> BackendFuture<'_, Vec<StorageChangeSet<H256>>> {
+        Box::pin(async move {
+            let from = self.number_of(Some(from))?;
+            let to = self.number_of(to)?;
+
+            let mut change_sets = Vec::new();
+            let mut last_values = HashMap::new();
+            for number in from..=to {
+                let block = self.block_hash(Some(number)).await?;
+                let state = self.state(block)?;
+                let changes = keys
+                    .iter()
+                    .filter_map(|key| {
+                        let value = state.root.get(key).cloned().flatten();
+                        let changed = last_values.get(key) != Some(&value);
+                        last_values.insert(key.clone(), value.clone());
+                        changed.then(|| (key.clone(), value))
+                    })
+                    .collect::<Vec<_>>();
+
+                if !changes.is_empty() {
+                    change_sets.push(StorageChangeSet {
+                        block: block.expect("exported blocks have a hash"),
+                        changes,
+                    });
+                }
+            }
+            Ok(change_sets)
+        })
+    }
+
+    /// All the nodes of the trie, rebuilt from the export.
+    fn read_proof<'a>(
+        &'a self,
+        child_trie: Option<&'a StorageKey>,
+        _key: &'a [u8],
+        block_hash: H256,
+    ) -> BackendFuture<'a, Vec<Vec<u8>>> {
+        Box::pin(async move {
+            let state_version = self.state_version(block_hash).ok_or_else(|| {
+                anyhow::format_err!("the state of block {block_hash:?} is not exported")
+            })?;
+            let entries = self.trie(child_trie, Some(block_hash))?.cloned();
+            verify::trie_nodes(&entries.unwrap_or_default(), state_version)
+        })
+    }
+
+    /// The exported blocks, in order: the exports never change, so their whole history is
+    /// replayed as it was imported.
+    fn subscribe_heads(&self, _finalized: bool) -> BackendFuture<'_, BackendStream<Header>> {
+        let mut headers = self
+            .blocks
+            .iter()
+            .map(|block| block.header.clone())
+            .collect::<Vec<_>>();
+        headers.sort_by_key(|header| header.number);
+        let heads: BackendStream<Header> = Box::pin(futures::stream::iter(headers).map(Ok));
+        Box::pin(async move { Ok(heads) })
+    }
+}
@@ -0,0 +1,286 @@
+//! The history of a storage value: the blocks where it changed, found by bisection, with its
+//! decoded values before and after each change.
+
+use crate::bisect::{self, ProbeCache};
+use crate::decode::StorageDecoder;
+use crate::node_client::{to_hex, NodeClient};
+use crate::output::{self, CsvRecord, OutputFormat};
+use anyhow::Result;
+use clap::Parser;
+use serde::Serialize;
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sp_core::H256;
+use subxt::dynamic::Value;
+
+#[tokio::test]
+async fn test_storage_history() {
+    use crate::mock_node::{set_migration_in_progress, MockNode, Scenario};
+    use frame_support::storage::storage_prefix;
+    use sp_core::hashing::blake2_128;
+
+    // a migration runs from block 6 to block 10
+    let scenario = Scenario::fixtures()
+        .push_blocks(5, |_| {})
+        .push_block(|state| set_migration_in_progress(state, Some(vec![1])))
+        .push_blocks(4, |_| {})
+        .push_block(|state| set_migration_in_progress(state, None))
+        .push_blocks(3, |_| {});
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+    let head = client.get_blockhash(14).await.unwrap();
+
+    let key = storage_key(&client, "Contracts::MigrationInProgress", &[], head)
+        .await
+        .unwrap();
+    let history = storage_history(&client, &key, 0, 14, 1).await.unwrap();
+    let changes = history
+        .changes
+        .iter()
+        .map(|change| {
+            let value = |value: &Option<serde_json::Value>| value.as_ref().map(|v| v.to_string());
+            (
+                change.block_number,
+                value(&change.old_value),
+                value(&change.new_value),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        changes,
+        [
+            (6, None, Some("\"0x01\"".to_string())),
+            (11, Some("\"0x01\"".to_string()), None),
+        ]
+    );
+    assert_eq!(history.storage.as_deref(), Some("MigrationInProgress"));
+
+    // no change within the range
+    let history = storage_history(&client, &key, 7, 10, 4).await.unwrap();
+    assert!(history.changes.is_empty());
+
+    // map keys are encoded with the hashers of the storage item
+    let alice = AccountId32::from_ss58check("5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY");
+    let alice = alice.unwrap();
+    let args = [alice.to_ss58check()];
+    let key = storage_key(&client, "System::Account", &args, head)
+        .await
+        .unwrap();
+    let prefix = storage_prefix(b"System", b"Account");
+    assert_eq!(
+        key,
+        [&prefix[..], &blake2_128(alice.as_ref()), alice.as_ref()].concat()
+    );
+}
+
+#[derive(Parser, Debug)]
+pub struct StorageHistoryCmd {
+    /// The storage key as hex, or a `Pallet::Item` storage item followed by its map keys
+    #[clap(short, long)]
+    key: String,
+    /// The map keys of the storage item, as hex, numbers or ss58 addresses
+    args: Vec<String>,
+    /// The first block searched
+    #[clap(long, default_value_t = 0)]
+    start_block: u32,
+    /// The last block searched, the latest block by default
+    #[clap(long)]
+    end_block: Option<u32>,
+    /// The number of blocks probed at once when searching for a change
+    #[clap(long, default_value_t = 1)]
+    parallel_probes: usize,
+}
+
+/// A change of a storage value.
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageChange {
+    /// The first block holding the new value.
+    pub block_number: u32,
+    pub block_hash: H256,
+    /// The timestamp of the block, in RFC 3339 format.
+    pub timestamp: String,
+    /// The values before and after the block, decoded with the metadata of their block, or as
+    /// hex for keys unknown to the metadata. `None` when the value is absent.
+    pub old_value: Option<serde_json::Value>,
+    pub new_value: Option<serde_json::Value>,
+}
+
+impl CsvRecord for StorageChange {
+    const HEADER: &'static [&'static str] = &[
+        "block_number",
+        "block_hash",
+        "timestamp",
+        "old_value",
+        "new_value",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        let value = |value: &Option<serde_json::Value>| {
+            value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+        };
+        vec![
+            self.block_number.to_string(),
+            format!("{:?}", self.block_hash),
+            self.timestamp.clone(),
+            value(&self.old_value),
+            value(&self.new_value),
+        ]
+    }
+}
+
+/// The changes of a storage value, from the oldest.
+#[derive(Debug, Serialize)]
+pub struct StorageHistory {
+    pub key: String,
+    /// The storage item of the key, if the metadata knows it.
+    pub pallet: Option<String>,
+    pub storage: Option<String>,
+    pub changes: Vec<StorageChange>,
+}
+
+/// Parse a map key of a storage item: hex bytes, a number or an ss58 address.
+fn parse_map_key(arg: &str) -> Result<Value> {
+    if let Some(hex) = arg.strip_prefix("0x") {
+        return Ok(Value::from_bytes(hex::decode(hex)?));
+    }
+    if let Ok(number) = arg.parse::<u128>() {
+        return Ok(Value::u128(number));
+    }
+    let account = AccountId32::from_ss58check(arg).map_err(|_| {
+        anyhow::format_err!("invalid map key {arg}, expected hex, a number or an ss58 address")
+    })?;
+    Ok(Value::from_bytes(account))
+}
+
+/// Get the storage key of `--key` and its map keys, using the metadata at the given block.
+async fn storage_key(
+    client: &NodeClient,
+    key: &str,
+    args: &[String],
+    block_hash: H256,
+) -> Result<Vec<u8>> {
+    if let Some(hex) = key.strip_prefix("0x") {
+        if !args.is_empty() {
+            anyhow::bail!("map keys are only supported with a Pallet::Item key");
+        }
+        return Ok(hex::decode(hex)?);
+    }
+
+    let (pallet, item) = key
+        .split_once("::")
+        .ok_or_else(|| anyhow::format_err!("invalid key {key}, expected hex or Pallet::Item"))?;
+    let keys = args
+        .iter()
+        .map(|arg| parse_map_key(arg))
+        .collect::<Result<_>>()?;
+    client.get_storage_key(pallet, item, keys, block_hash).await
+}
+
+/// Decode a value with the metadata at the given block.
+async fn decode_value(
+    client: &NodeClient,
+    key: &[u8],
+    value: Option<&[u8]>,
+    block_hash: H256,
+) -> Result<Option<serde_json::Value>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let decoder = StorageDecoder::new(client.get_metadata(Some(block_hash)).await?);
+    let decoded = decoder
+        .decode(key, Some(value))
+        .and_then(|entry| entry.decoded_value);
+    Ok(Some(decoded.unwrap_or_else(|| to_hex(value).into())))
+}
+
+/// Find the blocks from `start_block` to `end_block` where the value of the key changed, see
+/// [`bisect::find_changes`].
+pub async fn storage_history(
+    client: &NodeClient,
+    key: &[u8],
+    start_block: u32,
+    end_block: u32,
+    parallel_probes: usize,
+) -> Result<StorageHistory> {
+    // the value of each block probed, as each search resumes where the previous one ended
+    let values = ProbeCache::default();
+    let fetch = |block_number| async move {
+        let block_hash = client.get_blockhash(block_number).await?;
+        let value = client.get_storage_value(key, Some(block_hash)).await?;
+        Ok(value.map(|data| data.0))
+    };
+    let probe = |block_number| values.probe(block_number, fetch);
+
+    let mut changes = Vec::new();
+    let found = bisect::find_changes(start_block, end_block, parallel_probes, probe).await?;
+    for (block_number, old_value, new_value) in found {
+        let [previous_hash, block_hash] =
+            [block_number - 1, block_number].map(|n| client.get_blockhash(n));
+        let (previous_hash, block_hash) = futures::try_join!(previous_hash, block_hash)?;
+        changes.push(StorageChange {
+            block_number,
+            block_hash,
+            timestamp: client.get_timestamp(block_hash).await?.to_rfc3339(),
+            old_value: decode_value(client, key, old_value.as_deref(), previous_hash).await?,
+            new_value: decode_value(client, key, new_value.as_deref(), block_hash).await?,
+        });
+    }
+
+    let block_hash = client.get_blockhash(end_block).await?;
+    let decoder = StorageDecoder::new(client.get_metadata(Some(block_hash)).await?);
+    let item = decoder.storage_item(key);
+    Ok(StorageHistory {
+        key: to_hex(key),
+        pallet: item.map(|(pallet, _)| pallet.to_string()),
+        storage: item.map(|(_, storage)| storage.to_string()),
+        changes,
+    })
+}
+
+/// Print the blocks where the value of the key changed, with the values before and after.
+pub async fn print_storage_history(
+    client: &NodeClient,
+    cmd: StorageHistoryCmd,
+    output: OutputFormat,
+) -> Result<()> {
+    let end_block = match cmd.end_block {
+        Some(end_block) => end_block,
+        None => client.get_blocknumber().await?,
+    };
+    let block_hash = client.get_blockhash(end_block).await?;
+    let key = storage_key(client, &cmd.key, &cmd.args, block_hash).await?;
+    let history = storage_history(
+        client,
+        &key,
+        cmd.start_block,
+        end_block,
+        cmd.parallel_probes,
+    )
+    .await?;
+
+    if output == OutputFormat::Text {
+        let value = |value: &Option<serde_json::Value>| match value {
+            Some(value) => value.to_string(),
+            None => "None".to_string(),
+        };
+        match (&history.pallet, &history.storage) {
+            (Some(pallet), Some(storage)) => println!("{} ({pallet}::{storage}):", history.key),
+            _ => println!("{}:", history.key),
+        }
+        for change in &history.changes {
+            println!(
+                "Block {} ({}): {} -> {}",
+                change.block_number,
+                change.timestamp,
+                value(&change.old_value),
+                value(&change.new_value)
+            );
+        }
+        if history.changes.is_empty() {
+            println!(
+                "No change from block {} to block {end_block}",
+                cmd.start_block
+            );
+        }
+    }
+    output::print_report(output, &history, &history.changes)
+}
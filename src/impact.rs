@@ -0,0 +1,591 @@
+//! The `Contracts` extrinsics that failed in a range of blocks, such as the calls rejected with
+//! `MigrationInProgress` while a migration runs, and the fees their callers paid for them.
+
+use crate::decode::as_bytes;
+use crate::migration::MigrationReport;
+use crate::node_client::{NodeClient, Phase};
+use crate::output::{self, CsvRecord, OutputFormat};
+use anyhow::Result;
+use clap::Parser;
+use itertools::Itertools;
+use serde::Serialize;
+use sp_core::crypto::AccountId32;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use subxt::ext::scale_value::{self, At, Composite, Value, ValueDef};
+use subxt::metadata::Metadata;
+
+#[tokio::test]
+async fn test_impact_report() {
+    use crate::decode::test_metadata;
+    use crate::mock_node::{
+        fixture_contracts, set_contract_version, set_events, set_migration_in_progress,
+        signed_extrinsic, MockNode, Scenario,
+    };
+
+    let metadata = test_metadata();
+    let contracts = fixture_contracts();
+    let [alice, bob] = [[1; 32], [2; 32]].map(AccountId32::new);
+
+    let call = |contract: &AccountId32| {
+        let args = Value::named_variant(
+            "call",
+            [
+                (
+                    "dest",
+                    Value::unnamed_variant("Id", [Value::from_bytes(contract)]),
+                ),
+                ("value", Value::u128(0)),
+                (
+                    "gas_limit",
+                    Value::named_composite([
+                        ("ref_time", Value::u128(1)),
+                        ("proof_size", Value::u128(1)),
+                    ]),
+                ),
+                ("storage_deposit_limit", Value::unnamed_variant("None", [])),
+                ("data", Value::from_bytes([])),
+            ],
+        );
+        Value::unnamed_variant("Contracts", [args])
+    };
+    let transfer = Value::unnamed_variant(
+        "Balances",
+        [Value::named_variant(
+            "transfer",
+            [
+                (
+                    "dest",
+                    Value::unnamed_variant("Id", [Value::from_bytes(&bob)]),
+                ),
+                ("value", Value::u128(1)),
+            ],
+        )],
+    );
+
+    // the errors of the contracts pallet, e.g. 27 for `MigrationInProgress` and 11 for
+    // `ContractTrapped` in the fixtures
+    let module_error = |error: u8| {
+        Value::unnamed_variant(
+            "Module",
+            [Value::named_composite([
+                ("index", Value::u128(40)),
+                ("error", Value::from_bytes([error, 0, 0, 0])),
+            ])],
+        )
+    };
+    let failed = |error| {
+        let dispatch_info = Value::named_composite([
+            (
+                "weight",
+                Value::named_composite([
+                    ("ref_time", Value::u128(1)),
+                    ("proof_size", Value::u128(1)),
+                ]),
+            ),
+            ("class", Value::unnamed_variant("Normal", [])),
+            ("pays_fee", Value::unnamed_variant("Yes", [])),
+        ]);
+        Value::unnamed_variant(
+            "System",
+            [Value::named_variant(
+                "ExtrinsicFailed",
+                [("dispatch_error", error), ("dispatch_info", dispatch_info)],
+            )],
+        )
+    };
+    let fee_paid = |who: &AccountId32, fee: u128| {
+        Value::unnamed_variant(
+            "TransactionPayment",
+            [Value::named_variant(
+                "TransactionFeePaid",
+                [
+                    ("who", Value::from_bytes(who)),
+                    ("actual_fee", Value::u128(fee)),
+                    ("tip", Value::u128(0)),
+                ],
+            )],
+        )
+    };
+
+    // a failed call of each contract in block 3, and a failed transfer and a trapped call in
+    // block 4, when the migration completes
+    let block_3 = vec![
+        signed_extrinsic(&metadata, &alice, call(&contracts[0])),
+        signed_extrinsic(&metadata, &bob, call(&contracts[1])),
+    ];
+    let block_4 = vec![
+        signed_extrinsic(&metadata, &alice, transfer),
+        signed_extrinsic(&metadata, &alice, call(&contracts[0])),
+    ];
+    let scenario = Scenario::fixtures()
+        .push_blocks(2, |_| {})
+        .push_block_with_extrinsics(block_3, |state| {
+            set_migration_in_progress(state, Some(vec![0]));
+            let events = vec![
+                (0, fee_paid(&alice, 100)),
+                (0, failed(module_error(27))),
+                (1, fee_paid(&bob, 200)),
+                (1, failed(module_error(27))),
+            ];
+            set_events(state, &metadata, events);
+        })
+        .push_block_with_extrinsics(block_4, |state| {
+            set_contract_version(state, 10);
+            set_migration_in_progress(state, None);
+            let events = vec![
+                (0, failed(Value::unnamed_variant("BadOrigin", []))),
+                (1, fee_paid(&alice, 300)),
+                (1, failed(module_error(11))),
+            ];
+            set_events(state, &metadata, events);
+        })
+        .push_block(|state| set_events(state, &metadata, vec![]));
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    let report = impact_report(&client, vec![3..=5]).await.unwrap();
+    assert_eq!((report.block_count, report.contract_calls), (3, 3));
+    let calls = report
+        .failed_calls
+        .iter()
+        .map(|call| (call.block_number, call.extrinsic_index, call.error.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        calls,
+        [
+            (3, 0, "Contracts::MigrationInProgress"),
+            (3, 1, "Contracts::MigrationInProgress"),
+            (4, 1, "Contracts::ContractTrapped"),
+        ]
+    );
+    assert_eq!(report.failed_calls[1].caller, Some(bob.to_string()));
+    assert_eq!(
+        report.failed_calls[1].contract,
+        Some(contracts[1].to_string())
+    );
+    assert_eq!(report.fees, 600);
+
+    let count = |counts: &[FailureCount]| {
+        counts
+            .iter()
+            .map(|count| (count.key.clone(), count.calls, count.fees))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        count(&report.by_error),
+        [
+            ("Contracts::MigrationInProgress".to_string(), 2, 300),
+            ("Contracts::ContractTrapped".to_string(), 1, 300),
+        ]
+    );
+    assert_eq!(
+        count(&report.by_caller),
+        [(alice.to_string(), 2, 400), (bob.to_string(), 1, 200)]
+    );
+
+    // the migration completes in block 4, after its calls were rejected
+    let ranges = migration_ranges(&client, None, 9, 1).await.unwrap();
+    assert_eq!(ranges, [3..=4]);
+}
+
+#[derive(Parser, Debug)]
+pub struct MigrationImpactCmd {
+    /// Scan the blocks of the migrations until this version is reached
+    #[clap(short, long, required_unless_present = "start_block")]
+    target_version: Option<u16>,
+    /// The block to search for the migrations from, the latest block by default
+    #[clap(short, long)]
+    from_block_number: Option<u32>,
+    /// Scan the blocks from this block to `--end-block` instead of the migrations
+    #[clap(
+        long,
+        requires = "end_block",
+        conflicts_with_all = ["target_version", "from_block_number"]
+    )]
+    start_block: Option<u32>,
+    #[clap(long, requires = "start_block")]
+    end_block: Option<u32>,
+    /// The number of blocks probed at once when searching for a migration
+    #[clap(long, default_value_t = 1)]
+    parallel_probes: usize,
+}
+
+/// A `Contracts` extrinsic that failed.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedCall {
+    pub block_number: u32,
+    pub extrinsic_index: u32,
+    /// The name of the call, e.g. `call` or `instantiate`.
+    pub call: String,
+    pub caller: Option<String>,
+    /// The contract called, for the calls with a `dest`.
+    pub contract: Option<String>,
+    /// The dispatch error, e.g. `Contracts::MigrationInProgress`.
+    pub error: String,
+    /// The fee paid for the extrinsic, tip included, as reported by `TransactionFeePaid`.
+    pub fee: Option<u128>,
+}
+
+impl fmt::Display for FailedCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = "unknown".to_string();
+        write!(
+            f,
+            "Block {}, extrinsic {}: Contracts::{} from {}",
+            self.block_number,
+            self.extrinsic_index,
+            self.call,
+            self.caller.as_ref().unwrap_or(&unknown)
+        )?;
+        if let Some(contract) = &self.contract {
+            write!(f, " to {contract}")?;
+        }
+        write!(f, " failed with {}, fee ", self.error)?;
+        match self.fee {
+            Some(fee) => write!(f, "{fee}"),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+impl CsvRecord for FailedCall {
+    const HEADER: &'static [&'static str] = &[
+        "block_number",
+        "extrinsic_index",
+        "call",
+        "caller",
+        "contract",
+        "error",
+        "fee",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.block_number.to_string(),
+            self.extrinsic_index.to_string(),
+            self.call.clone(),
+            self.caller.clone().unwrap_or_default(),
+            self.contract.clone().unwrap_or_default(),
+            self.error.clone(),
+            self.fee.map(|fee| fee.to_string()).unwrap_or_default(),
+        ]
+    }
+}
+
+/// The failed calls sharing an error, a caller or a contract.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailureCount {
+    pub key: String,
+    pub calls: u32,
+    /// The fees paid for the calls, when known.
+    pub fees: u128,
+}
+
+/// The failed `Contracts` extrinsics of the blocks scanned.
+#[derive(Debug, Serialize)]
+pub struct ImpactReport {
+    pub block_count: u32,
+    /// The number of `Contracts` extrinsics in the blocks scanned.
+    pub contract_calls: u32,
+    pub failed_calls: Vec<FailedCall>,
+    /// The fees paid for the failed calls.
+    pub fees: u128,
+    /// The failed calls counted by error, caller and contract, the most frequent first.
+    pub by_error: Vec<FailureCount>,
+    pub by_caller: Vec<FailureCount>,
+    pub by_contract: Vec<FailureCount>,
+}
+
+/// Count the failed calls by the given key, the most frequent first.
+fn count_by(
+    calls: &[FailedCall],
+    key: impl Fn(&FailedCall) -> Option<&String>,
+) -> Vec<FailureCount> {
+    let mut counts = BTreeMap::<&String, (u32, u128)>::new();
+    for call in calls {
+        if let Some(key) = key(call) {
+            let (count, fees) = counts.entry(key).or_default();
+            *count += 1;
+            *fees += call.fee.unwrap_or_default();
+        }
+    }
+    counts
+        .into_iter()
+        .map(|(key, (calls, fees))| FailureCount {
+            key: key.clone(),
+            calls,
+            fees,
+        })
+        .sorted_by_key(|count| std::cmp::Reverse(count.calls))
+        .collect()
+}
+
+/// The signer of an extrinsic and the call it dispatches.
+struct Extrinsic {
+    signer: Option<AccountId32>,
+    /// The runtime call, a variant for the pallet holding a variant for the call.
+    call: Value<u32>,
+}
+
+impl Extrinsic {
+    /// The pallet and the name of the call.
+    fn name(&self) -> Option<(&str, &str)> {
+        let ValueDef::Variant(pallet) = &self.call.value else {
+            return None;
+        };
+        let ValueDef::Variant(call) = &pallet.values.values().next()?.value else {
+            return None;
+        };
+        Some((&pallet.name, &call.name))
+    }
+}
+
+/// Decode an extrinsic with the types of the `UncheckedExtrinsic` of the metadata.
+fn decode_extrinsic(metadata: &Metadata, mut bytes: &[u8]) -> Result<Extrinsic> {
+    let extrinsic_ty = metadata
+        .resolve_type(metadata.runtime_metadata().extrinsic.ty.id)
+        .ok_or_else(|| anyhow::format_err!("extrinsic type not found"))?;
+    let param = |name: &str| {
+        extrinsic_ty
+            .type_params
+            .iter()
+            .find(|param| param.name == name)
+            .and_then(|param| param.ty)
+            .map(|ty| ty.id)
+            .ok_or_else(|| anyhow::format_err!("extrinsic type parameter {name} not found"))
+    };
+    let version = version_byte(&mut bytes)?;
+    let mut decode = |name: &str| -> Result<Value<u32>> {
+        Ok(scale_value::scale::decode_as_type(
+            &mut bytes,
+            param(name)?,
+            metadata.types(),
+        )?)
+    };
+
+    let signer = if version & 0b1000_0000 != 0 {
+        let address = decode("Address")?;
+        decode("Signature")?;
+        decode("Extra")?;
+        account(&address, metadata)
+    } else {
+        None
+    };
+    let call = decode("Call")?;
+    Ok(Extrinsic { signer, call })
+}
+
+/// Pop the version byte of an extrinsic.
+fn version_byte(bytes: &mut &[u8]) -> Result<u8> {
+    let (version, rest) = bytes
+        .split_first()
+        .ok_or_else(|| anyhow::format_err!("empty extrinsic"))?;
+    *bytes = rest;
+    Ok(*version)
+}
+
+/// The account of an `AccountId32`, or of the `Id` of a `MultiAddress`.
+fn account(value: &Value<u32>, metadata: &Metadata) -> Option<AccountId32> {
+    let value = match &value.value {
+        ValueDef::Variant(variant) if variant.name == "Id" => variant.values.values().next()?,
+        _ => value,
+    };
+    let bytes: [u8; 32] = as_bytes(value, metadata.types())?.try_into().ok()?;
+    Some(AccountId32::new(bytes))
+}
+
+/// The name of a dispatch error, e.g. `BadOrigin`, `Token::FundsUnavailable`, or the pallet error
+/// of a module error, e.g. `Contracts::MigrationInProgress`.
+fn dispatch_error(value: &Value<u32>, metadata: &Metadata) -> String {
+    let ValueDef::Variant(variant) = &value.value else {
+        return value.to_string();
+    };
+
+    if variant.name == "Module" {
+        // the error is an index, or the 4 bytes of the encoded error in recent runtimes
+        let index = value.at(0).at("index").and_then(Value::as_u128);
+        let error = value
+            .at(0)
+            .at("error")
+            .and_then(|error| match &error.value {
+                ValueDef::Composite(Composite::Unnamed(_)) => error.at(0).and_then(Value::as_u128),
+                _ => error.as_u128(),
+            });
+        if let Some((index, error)) = index.zip(error) {
+            if let Ok(error) = metadata.error(index as u8, error as u8) {
+                return format!("{}::{}", error.pallet(), error.error());
+            }
+            return format!("Module({index}, {error})");
+        }
+    }
+
+    match variant.values.values().next() {
+        Some(inner) if matches!(inner.value, ValueDef::Variant(_)) => {
+            format!("{}::{}", variant.name, dispatch_error(inner, metadata))
+        }
+        _ => variant.name.clone(),
+    }
+}
+
+/// Find the failed `Contracts` extrinsics of the given block, and count its `Contracts`
+/// extrinsics.
+async fn scan_block(client: &NodeClient, block_number: u32) -> Result<(u32, Vec<FailedCall>)> {
+    let block_hash = client.get_blockhash(block_number).await?;
+    let (block, events, metadata) = futures::try_join!(
+        client.get_block(Some(block_hash)),
+        client.get_events(block_hash),
+        client.get_metadata(Some(block_hash)),
+    )?;
+
+    let mut contract_calls = 0;
+    let mut failed_calls = Vec::new();
+    for (index, extrinsic) in block.extrinsics.iter().enumerate() {
+        let extrinsic = decode_extrinsic(&metadata, &extrinsic.0).map_err(|err| {
+            anyhow::format_err!("failed to decode extrinsic {index} of block {block_number}: {err}")
+        })?;
+        let Some(("Contracts", call)) = extrinsic.name() else {
+            continue;
+        };
+        contract_calls += 1;
+
+        let index = index as u32;
+        let find = |name: (&str, &str)| {
+            events.iter().find(|event| {
+                event.phase == Phase::ApplyExtrinsic(index) && event.name() == Some(name)
+            })
+        };
+        let Some(error) =
+            find(("System", "ExtrinsicFailed")).and_then(|event| event.field("dispatch_error"))
+        else {
+            continue;
+        };
+        let fee = find(("TransactionPayment", "TransactionFeePaid"))
+            .and_then(|event| event.field("actual_fee"))
+            .and_then(Value::as_u128);
+
+        let contract = extrinsic
+            .call
+            .at(0)
+            .at("dest")
+            .and_then(|dest| account(dest, &metadata));
+        failed_calls.push(FailedCall {
+            block_number,
+            extrinsic_index: index,
+            call: call.to_string(),
+            caller: extrinsic.signer.as_ref().map(ToString::to_string),
+            contract: contract.as_ref().map(ToString::to_string),
+            error: dispatch_error(error, &metadata),
+            fee,
+        });
+    }
+    Ok((contract_calls, failed_calls))
+}
+
+/// Get the failed `Contracts` extrinsics of the given ranges of blocks.
+pub async fn impact_report(
+    client: &NodeClient,
+    ranges: Vec<RangeInclusive<u32>>,
+) -> Result<ImpactReport> {
+    let mut block_count = 0;
+    let mut contract_calls = 0;
+    let mut failed_calls = Vec::new();
+    for block_number in ranges.into_iter().flatten() {
+        let (calls, failed) = scan_block(client, block_number).await?;
+        block_count += 1;
+        contract_calls += calls;
+        failed_calls.extend(failed);
+    }
+
+    Ok(ImpactReport {
+        block_count,
+        contract_calls,
+        fees: failed_calls.iter().filter_map(|call| call.fee).sum(),
+        by_error: count_by(&failed_calls, |call| Some(&call.error)),
+        by_caller: count_by(&failed_calls, |call| call.caller.as_ref()),
+        by_contract: count_by(&failed_calls, |call| call.contract.as_ref()),
+        failed_calls,
+    })
+}
+
+/// The blocks of the migrations until the target version is reached, from the oldest.
+///
+/// Each range includes the block completing its step: the migration runs in `on_idle`, after
+/// the extrinsics of the block, so they are still rejected while it completes.
+pub async fn migration_ranges(
+    client: &NodeClient,
+    from_block_number: Option<u32>,
+    target_version: u16,
+    parallel_probes: usize,
+) -> Result<Vec<RangeInclusive<u32>>> {
+    let migration =
+        MigrationReport::fetch(client, from_block_number, target_version, parallel_probes).await?;
+    let head = client.get_blocknumber().await?;
+
+    let mut ranges: Vec<RangeInclusive<u32>> = Vec::new();
+    for step in migration.transitions.iter().rev() {
+        let end_block = (step.end_block + 1).min(head);
+        match ranges.last_mut() {
+            // the block completing a step starts the next one
+            Some(last) if step.start_block <= *last.end() => {
+                *last = *last.start()..=end_block.max(*last.end());
+            }
+            _ => ranges.push(step.start_block..=end_block),
+        }
+    }
+    Ok(ranges)
+}
+
+/// Print the `Contracts` extrinsics that failed during the migrations, or in the given range of
+/// blocks, and count them by error, caller and contract.
+pub async fn migration_impact(
+    client: &NodeClient,
+    cmd: MigrationImpactCmd,
+    output: OutputFormat,
+) -> Result<()> {
+    let ranges = match (cmd.start_block, cmd.end_block, cmd.target_version) {
+        (Some(start), Some(end), _) => vec![start..=end],
+        (_, _, Some(target_version)) => {
+            migration_ranges(
+                client,
+                cmd.from_block_number,
+                target_version,
+                cmd.parallel_probes,
+            )
+            .await?
+        }
+        _ => anyhow::bail!("either --target-version or --start-block must be provided"),
+    };
+    let report = impact_report(client, ranges).await?;
+
+    if output == OutputFormat::Text {
+        for call in &report.failed_calls {
+            println!("{call}");
+        }
+        println!(
+            "Scanned {} block(s): {} of {} Contracts extrinsic(s) failed, paying {} in fees",
+            report.block_count,
+            report.failed_calls.len(),
+            report.contract_calls,
+            report.fees
+        );
+        for (title, counts) in [
+            ("Errors", &report.by_error),
+            ("Callers", &report.by_caller),
+            ("Contracts", &report.by_contract),
+        ] {
+            if counts.is_empty() {
+                continue;
+            }
+            println!("{title}:");
+            for count in counts {
+                println!(
+                    "    {}: {} call(s), {} in fees",
+                    count.key, count.calls, count.fees
+                );
+            }
+        }
+    }
+    output::print_report(output, &report, &report.failed_calls)
+}
@@ -21,33 +21,242 @@
 //! Version 09 -> 10 took 03 block(s), from blocks 2738929 to 2738931
 //! Version 08 -> 09 took 06 block(s), from blocks 2738923 to 2738928
 //! ```
+//!
+//! Each migration is searched for by walking back from the previous one, then bisecting. Pass
+//! `--parallel-probes 4` to probe 4 blocks at once, and save round trips on a slow connection.
+//!
+//! Each block migrating is followed by the progress of its running step, decoded from the
+//! `MigrationInProgress` cursor: the last contract or code migrated, and the share of the items
+//! migrated so far.
+//!
+//! With `--output json`, the blocks and the version transitions are printed as a json report, each
+//! transition with its start and end blocks and timestamps, and its duration. `--output jsonl` and
+//! `--output csv` print one line per transition.
+//! ```bash
+//! > contracts-query print-migrating-blocks --target-version 8 --output csv
+//! ```
+//!
+//! `--output` is supported by `print-migrating-blocks`, `migration-weights`, `migration-impact`,
+//! `storage-history` and `pallet-versions`. The other subcommands only print text, and fail when
+//! another format is selected.
+//!
+//! ## Report the weight of a migration
+//!
+//! Print the weight consumed by each block of the migrations, its length and its extrinsic count,
+//! then the weight consumed by each step, against the `max_block` weight of the runtime, to tell
+//! whether the migration left room for user transactions. Up to `--concurrency` blocks are fetched
+//! at once, 8 by default.
+//! ```bash
+//! > contracts-query migration-weights --target-version 8
+//! ```
+//!
+//! ## Report the calls rejected during a migration
+//!
+//! List the `Contracts` extrinsics that failed while the migrations ran, e.g. with
+//! `MigrationInProgress`, from the `System::ExtrinsicFailed` events of their blocks, with the fee
+//! paid for each of them. The failed calls are then counted by error, caller and contract. Pass
+//! `--start-block` and `--end-block` to scan a range of blocks instead.
+//! ```bash
+//! > contracts-query migration-impact --target-version 8
+//! > contracts-query migration-impact --start-block 2738900 --end-block 2738940 --output csv
+//! ```
+//!
+//! ## Print the history of a storage value
+//!
+//! Find the blocks where a storage value changed, from `--start-block` to `--end-block` or the latest
+//! block, by bisection. Each change is printed with the timestamp of its block and the decoded
+//! values before and after it. The key is given as hex, or as a `Pallet::Item` storage item
+//! followed by its map keys, e.g. to tell when the code hash of a contract changed.
+//! ```bash
+//! > contracts-query storage-history --key Contracts::ContractInfoOf 5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY
+//! > contracts-query storage-history --key 0xf0c365c3cf59d671eb72da0e7a4113c49f1f0515f462cdcf84e0f1d6045dfcbb --start-block 1
+//! ```
+//!
+//! ## Print the storage version of the pallets
+//!
+//! Print the on-chain storage version of every pallet of the runtime, at `--block-number` or the
+//! latest block. With `--start-block`, also find by bisection the blocks where each version changed,
+//! e.g. to tell when a runtime upgrade bumped the version of a pallet.
+//! ```bash
+//! > contracts-query pallet-versions
+//! > contracts-query pallet-versions --start-block 2700000 --parallel-probes 4 --output csv
+//! ```
+//!
+//! ## Watch a migration
+//!
+//! Follow the new blocks, or the finalized blocks with `--finalized`, and print each change of the
+//! storage version or of the migration cursor, until the target version is reached.
+//! ```bash
+//! > contracts-query watch-migration --target-version 11
+//! ```
+//!
+//! ## Export the state of a block
+//!
+//! Export the storage of a block, including its child tries, to a json file. The entries are written
+//! as they are fetched, so the state does not have to fit in memory. Pass `--format jsonl` to write
+//! a header line followed by one entry per line, the entries of child tries tagged with their
+//! `child_trie` key.
+//! The values are fetched in batches, with up to `--concurrency` requests in flight, 8 by default.
+//!
+//! Pass `--pallet`, `--storage` or `--prefix`, each as many times as needed, to only export the
+//! storage of some pallets, storage items or hex key prefixes.
+//! ```bash
+//! > contracts-query db-export db-2738931.json 2738931
+//! > contracts-query db-export db-2738931.jsonl 2738931 --format jsonl
+//! > contracts-query db-export db-2738931.json 2738931 --concurrency 32
+//! > contracts-query db-export contracts.json 2738931 --pallet Contracts --storage System::Account
+//! ```
+//!
+//! ## Compare two exports
+//!
+//! Print the keys added, removed and changed between two exports, or between the states of two
+//! blocks with `--at-a` and `--at-b`, grouped by storage item, then the changes of each child trie.
+//! The `db-export` filters restrict the comparison to some pallets, storage items or key prefixes.
+//! With `--decode`, the map keys and the values are decoded with the metadata of their block: the
+//! block of an export is read from its manifest, and its metadata from the node, or from `--offline`
+//! exports. Filtering exports needs this metadata too, to find the child tries of the contracts.
+//! ```bash
+//! > contracts-query db-diff db-2738931.json db-2738932.json
+//! > contracts-query --offline . db-diff db-2738931.json db-2738932.json --decode
+//! > contracts-query db-diff --at-a 2738931 --at-b 2738932 --pallet Contracts --decode
+//! ```
+//!
+//! ## Verify an export
+//!
+//! Check that an export is complete and untampered, by rebuilding the state root of its block from
+//! its entries, and comparing it to the header of the block. The block is read from the manifest
+//! written next to the export as `<output_file>.manifest.json`, or given with `--at-block`. The
+//! header is fetched from the node, or read from a `block-export` file with `--blocks-file`. When the
+//! roots differ, the trie nodes of the block are fetched to find the first subtree of the export that
+//! disagrees with the node.
+//! ```bash
+//! > contracts-query verify-export db-2738931.json
+//! > contracts-query verify-export db-4.json --at-block 4 --blocks-file blocks.json
+//! ```
+//!
+//! ## Export the changes between two blocks
+//!
+//! Export only the entries that changed between two blocks with `db-delta`, from a full export or a
+//! block number to `--to`, removed keys having a `null` value. `db-apply` then rebuilds the full
+//! export of the last block from a base export and a chain of deltas, checking with their manifests
+//! that each delta applies to the block of the previous one.
+//! ```bash
+//! > contracts-query db-delta delta-2738932.json --from db-2738931.json --to 2738932
+//! > contracts-query db-delta delta-2738933.json --from 2738932 --to 2738933
+//! > contracts-query db-apply db-2738931.json delta-2738932.json delta-2738933.json -o db-2738933.json
+//! ```
+//!
+//! ## Resume an interrupted export
+//!
+//! `db-export`, `block-export` and `change-sets` save their progress next to their output file, as
+//! `<output_file>.checkpoint.json`. Run the same command again with `--resume` to continue an
+//! interrupted export from its last checkpoint. The checkpoint is removed once the export completes.
+//! ```bash
+//! > contracts-query db-export db-2738931.json 2738931 --resume
+//! > contracts-query block-export blocks.json 2738922 2738932 --resume
+//! ```
+//!
+//! ## Retry failed requests
+//!
+//! The requests the node could not answer, because they timed out or the node is busy or rate
+//! limiting, are retried up to `--max-retries` times, 5 by default. The first retry waits
+//! `--retry-delay-ms`, 500 by default, and the delay doubles after each retry, with some random
+//! jitter. `--request-timeout` sets how many seconds to wait for a response, 60 by default. Other
+//! errors, such as invalid params or an internal error of the node, fail right away. When the
+//! connection drops, the client reconnects before retrying, and resubscribes to the heads followed
+//! by `watch-migration`.
+//! ```bash
+//! > contracts-query --max-retries 10 --retry-delay-ms 1000 --request-timeout 120 db-export db-2738931.json 2738931
+//! ```
+//!
+//! ## Run without a node
+//!
+//! Serve the chain data from a directory of exports, holding the `blocks.json` written by
+//! `block-export`, the `db-<block number>.json` written by `db-export` and the runtime
+//! `metadata.scale`, such as the fixtures of this repository generated by `export.sh`.
+//! ```bash
+//! > contracts-query --offline . print-account-info -a 5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY -t 8
+//! ```
+//!
+//! ## Record and replay a session
+//!
+//! Record the JSON-RPC requests sent to the node and its responses in a cassette, to replay them
+//! later without the node, e.g. to reproduce a bug report. The heads notified to `watch-migration`
+//! are recorded too, and notified again in the same order.
+//! ```bash
+//! > contracts-query --record migration.jsonl print-migrating-blocks --target-version 8
+//! > contracts-query --replay migration.jsonl print-migrating-blocks --target-version 8
+//! ```
+//!
+//! ## Cache the node responses
+//!
+//! Keep the responses to the queries at a given block hash on disk, under `--cache-dir` or
+//! `$XDG_CACHE_HOME/contracts-query`, so that running a command again does not query the node for
+//! them.
+//! ```bash
+//! > contracts-query --cache print-migrating-blocks --target-version 8
+//! > contracts-query cache stats
+//! > contracts-query cache clear
+//! ```
+mod backend;
+mod bisect;
+mod cache;
+mod cassette;
+mod checkpoint;
+mod db_delta;
+mod db_diff;
+mod db_export;
+mod decode;
+mod file_backend;
+mod history;
+mod impact;
+mod migration;
+#[cfg(test)]
+mod mock_node;
 mod node_client;
-
-use crate::node_client::{BlockInfo, NodeClient};
+mod output;
+mod rpc;
+mod verify;
+mod versions;
+mod watch;
+mod weights;
+
+use crate::cache::CacheCmd;
+use crate::checkpoint::JsonArrayOutput;
+use crate::db_delta::{DBApplyCmd, DBDeltaCmd};
+use crate::db_diff::DBDiffCmd;
+use crate::db_export::DBExportCmd;
+use crate::history::StorageHistoryCmd;
+use crate::impact::MigrationImpactCmd;
+use crate::migration::{MigratingBlock, MigrationReport, StepTotals};
+use crate::node_client::{BlockInfo, NodeClient, PAGE_SIZE};
+use crate::output::OutputFormat;
+use crate::rpc::NodeOptions;
+use crate::verify::VerifyExportCmd;
+use crate::versions::PalletVersionsCmd;
+use crate::watch::WatchMigrationCmd;
+use crate::weights::MigrationWeightsCmd;
 use anyhow::Result;
 use clap::Parser;
 use futures::TryStreamExt;
-use itertools::Itertools;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
 use sp_core::crypto::{AccountId32, Ss58Codec};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use sp_core::H256;
 use subxt::rpc::types::ChainBlock;
-use subxt::rpc::types::{ChainBlockExtrinsic, StorageData};
-use subxt::storage::StorageKey;
+use subxt::rpc::types::ChainBlockExtrinsic;
 use subxt::{Config, PolkadotConfig};
 
 // Parsed command instructions from the command line
 #[derive(Parser)]
 #[clap(author, about, version)]
 struct CliCommand {
-    #[clap(
-        short,
-        long,
-        default_value = "wss://rococo-contracts-rpc.polkadot.io:443"
-    )]
-    url: String,
+    #[clap(flatten)]
+    node: NodeOptions,
+
+    /// The format of the reports, only the subcommands printing a report support other formats than
+    /// text
+    #[clap(long, global = true, value_enum, default_value_t)]
+    output: OutputFormat,
 
     /// the command to execute
     #[clap(subcommand)]
@@ -60,6 +269,9 @@ struct PrintBlocksCmd {
     from_block_number: Option<u32>,
     #[clap(short, long)]
     target_version: u16,
+    /// The number of blocks probed at once when searching for a migration
+    #[clap(long, default_value_t = 1)]
+    parallel_probes: usize,
 }
 
 #[derive(Parser, Debug)]
@@ -70,21 +282,44 @@ struct PrintAccountInfoCmd {
     from_block_number: Option<u32>,
     #[clap(short, long)]
     target_version: u16,
+    /// The number of blocks probed at once when searching for a migration
+    #[clap(long, default_value_t = 1)]
+    parallel_probes: usize,
 }
 
 /// The subcommand to execute
 #[derive(Parser, Debug)]
 enum SubCommand {
     /// Export the change sets for all the keys since block 0
-    ChangeSets { output_file: String },
+    ChangeSets {
+        output_file: String,
+        /// Continue an interrupted export from its checkpoint
+        #[clap(long)]
+        resume: bool,
+    },
+
+    /// Export the database, including child tries as a json file, along with its manifest
+    DBExport(DBExportCmd),
 
-    /// Export the database, including child tries as a json file
-    DBExport { output_file: String, at_block: u32 },
+    /// Compare two database exports, or the state at two blocks
+    DBDiff(DBDiffCmd),
+
+    /// Export the database entries changed between two blocks
+    DBDelta(DBDeltaCmd),
+
+    /// Rebuild a database export from a base export and a chain of deltas
+    DBApply(DBApplyCmd),
+
+    /// Check that a database export is complete, by rebuilding its state root
+    VerifyExport(VerifyExportCmd),
 
     /// Export the specified blocks as a json file
     BlockExport {
         output_file: String,
         blocks: Vec<u32>,
+        /// Continue an interrupted export from its checkpoint
+        #[clap(long)]
+        resume: bool,
     },
 
     /// Print each block until the target version is reached.
@@ -92,20 +327,42 @@ enum SubCommand {
 
     /// Print Providers count after each migration
     PrintAccountInfo(PrintAccountInfoCmd),
-}
 
-/// A database key-value entry
-#[derive(Debug, Serialize)]
-struct DBEntry {
-    key: StorageKey,
-    value: Option<StorageData>,
+    /// Print the weight consumed by each block of the migrations until the target version is
+    /// reached, against the block weight limit.
+    MigrationWeights(MigrationWeightsCmd),
+
+    /// Print the Contracts extrinsics that failed during the migrations until the target version is
+    /// reached, or in a range of blocks, with the fees paid for them.
+    MigrationImpact(MigrationImpactCmd),
+
+    /// Print the blocks where a storage value changed, with its values before and after.
+    StorageHistory(StorageHistoryCmd),
+
+    /// Print the storage version of every pallet, and the blocks where they changed.
+    PalletVersions(PalletVersionsCmd),
+
+    /// Follow the new blocks, and print each change of the migration until the target version is
+    /// reached.
+    WatchMigration(WatchMigrationCmd),
+
+    /// Inspect or clear the disk cache of `--cache`
+    #[clap(subcommand)]
+    Cache(CacheCmd),
 }
 
-/// The database export
-#[derive(Debug, Serialize)]
-struct DBExport {
-    root: Vec<DBEntry>,
-    child_tries: HashMap<StorageKey, Vec<DBEntry>>,
+impl SubCommand {
+    /// Whether the subcommand prints its report in the format selected with `--output`.
+    fn supports_output(&self) -> bool {
+        matches!(
+            self,
+            Self::PrintMigratingBlocks(_)
+                | Self::MigrationWeights(_)
+                | Self::MigrationImpact(_)
+                | Self::StorageHistory(_)
+                | Self::PalletVersions(_)
+        )
+    }
 }
 
 /// A wrapper to serialize a `ChainBlock` as a json object
@@ -133,168 +390,315 @@ pub fn vec_chain_block_extrinsic<S: Serializer>(
     }))
 }
 
-/// Serialize to JSON and write to file
-fn write_to_file<T: Serialize>(value: &T, file: String) -> Result<()> {
-    let json = serde_json::to_string_pretty(value)?;
-    let mut file = File::create(file)?;
-    file.write_all(json.as_bytes())?;
-    Ok(())
+/// The number of blocks queried at once by [`export_change_sets`].
+const CHANGE_SETS_BLOCK_RANGE: u32 = 1000;
+
+/// The progress of [`export_change_sets`].
+#[derive(Serialize, Deserialize)]
+struct ChangeSetsCheckpoint {
+    /// The block the keys are taken from, and the last block queried.
+    to_block: u32,
+    to_block_hash: H256,
+    /// The last block whose change set was exported.
+    last_block: Option<u32>,
+    /// The number of change sets exported.
+    change_sets: usize,
+}
+
+/// Export the change sets for all the keys of the last block since block 0, querying
+/// [`CHANGE_SETS_BLOCK_RANGE`] blocks at a time.
+async fn export_change_sets(client: &NodeClient, output_file: &str, resume: bool) -> Result<()> {
+    let (mut progress, mut output) = if resume {
+        let progress: ChangeSetsCheckpoint = checkpoint::load(output_file)?
+            .ok_or_else(|| anyhow::format_err!("no checkpoint found for {output_file}"))?;
+        let output = JsonArrayOutput::resume(output_file, progress.change_sets)?;
+        (progress, output)
+    } else {
+        let to_block = client.get_blocknumber().await?;
+        let progress = ChangeSetsCheckpoint {
+            to_block,
+            to_block_hash: client.get_blockhash(to_block).await?,
+            last_block: None,
+            change_sets: 0,
+        };
+        (progress, JsonArrayOutput::create(output_file)?)
+    };
+
+    // get all the keys at the last block
+    let keys = client
+        .stream_keys(None, None, PAGE_SIZE, progress.to_block_hash.into())
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    while progress.last_block != Some(progress.to_block) {
+        let from = progress.last_block.unwrap_or_default();
+        let to = progress.to_block.min(from + CHANGE_SETS_BLOCK_RANGE);
+        let from_hash = client.get_blockhash(from).await?;
+        let to_hash = client.get_blockhash(to).await?;
+        let change_sets = client
+            .query_storage_value(keys.clone(), from_hash, to_hash.into())
+            .await?;
+
+        // the first change set holds all the values at `from`, already exported with the
+        // previous range
+        let skip_first = progress.last_block.is_some();
+        for change_set in change_sets {
+            if skip_first && change_set.block == from_hash {
+                continue;
+            }
+            output.push(&change_set)?;
+        }
+
+        progress.last_block = Some(to);
+        progress.change_sets = output.len();
+        checkpoint::save(output_file, &progress)?;
+    }
+
+    output.finish()?;
+    checkpoint::remove(output_file)
+}
+
+/// The progress of [`export_blocks`].
+#[derive(Serialize, Deserialize)]
+struct BlockExportCheckpoint {
+    blocks: Vec<u32>,
+    /// The number of blocks exported.
+    exported: usize,
+}
+
+/// Print each block until the target version is reached, and the blocks each version
+/// transition took.
+async fn print_migrating_blocks(
+    client: &NodeClient,
+    cmd: PrintBlocksCmd,
+    output: OutputFormat,
+) -> Result<()> {
+    let migrating_blocks = client.stream_migrating_blocks(
+        cmd.from_block_number,
+        cmd.target_version,
+        cmd.parallel_probes,
+    );
+    tokio::pin!(migrating_blocks);
+
+    let mut blocks = vec![];
+    let totals = StepTotals::default();
+    while let Some(info) = migrating_blocks.try_next().await? {
+        let time = client.get_timestamp(info.block_hash).await?;
+        let progress = migration::get_progress(client, &totals, info.block_hash).await?;
+        if output == OutputFormat::Text {
+            println!("{time} -> {info:?}");
+            if let Some(progress) = &progress {
+                println!("    {progress}");
+            }
+        }
+        blocks.push(MigratingBlock::new(&info, time, progress.as_ref()));
+    }
+
+    if blocks.is_empty() {
+        return Ok(());
+    }
+    let report = MigrationReport::new(client, blocks).await?;
+    if output == OutputFormat::Text {
+        println!("Summary:");
+        for transition in &report.transitions {
+            println!("{transition}");
+        }
+        println!("Probed {} block(s)", client.probes());
+    }
+    output::print_report(output, &report, &report.transitions)
+}
+
+/// Export the given blocks, one at a time.
+async fn export_blocks(
+    client: &NodeClient,
+    output_file: &str,
+    blocks: Vec<u32>,
+    resume: bool,
+) -> Result<()> {
+    #[derive(Serialize)]
+    struct Helper(#[serde(with = "ChainBlockRef")] ChainBlock<PolkadotConfig>);
+
+    let (mut progress, mut output) = if resume {
+        let progress: BlockExportCheckpoint = checkpoint::load(output_file)?
+            .ok_or_else(|| anyhow::format_err!("no checkpoint found for {output_file}"))?;
+        if progress.blocks != blocks {
+            anyhow::bail!(
+                "the checkpoint of {output_file} is for blocks {:?}",
+                progress.blocks
+            );
+        }
+        let output = JsonArrayOutput::resume(output_file, progress.exported)?;
+        (progress, output)
+    } else {
+        let progress = BlockExportCheckpoint {
+            blocks,
+            exported: 0,
+        };
+        (progress, JsonArrayOutput::create(output_file)?)
+    };
+
+    while let Some(block_number) = progress.blocks.get(progress.exported) {
+        let hash = client.get_blockhash(*block_number).await?;
+        let block = client.get_block(Some(hash)).await?;
+        output.push(&Helper(block))?;
+
+        progress.exported = output.len();
+        checkpoint::save(output_file, &progress)?;
+    }
+
+    output.finish()?;
+    checkpoint::remove(output_file)
 }
 
 #[tokio::test]
 async fn test_child_state() {
-    let client = NodeClient::from_url("ws://127.0.0.1:9944").await.unwrap();
+    use crate::db_export::{DBExport, TrieEntries};
+    use sp_core::storage::well_known_keys::CHILD_STORAGE_KEY_PREFIX;
+
+    let node = mock_node::MockNode::fixtures().await;
+    let client = node.client().await;
 
-    let root_keys = client.get_keys(None).await.unwrap();
-    let result = client
-        .get_all_child_storage_pairs(root_keys, None)
+    let child_keys = client
+        .stream_keys(Some(CHILD_STORAGE_KEY_PREFIX), None, PAGE_SIZE, None)
+        .try_collect::<Vec<_>>()
         .await
         .unwrap();
-    let json = serde_json::to_string(&result).unwrap();
 
-    println!("{:?}", json);
+    let mut child_tries = std::collections::BTreeMap::new();
+    for child_key in child_keys {
+        let pairs = client
+            .stream_child_storage_pairs(child_key.clone(), None, None, PAGE_SIZE, None, 1)
+            .try_collect::<TrieEntries>()
+            .await
+            .unwrap();
+        child_tries.insert(child_key, pairs);
+    }
+
+    assert_eq!(
+        child_tries,
+        DBExport::load("db-4.json").unwrap().child_tries
+    );
 }
 
 #[tokio::test]
 async fn test_storage_version() {
-    let client = NodeClient::from_url("wss://rococo-contracts-rpc.polkadot.io:443")
+    let node = mock_node::MockNode::fixtures().await;
+    let client = node.client().await;
+
+    let version = client.get_contract_version(None).await.unwrap();
+
+    assert_eq!(version, 9);
+}
+
+#[tokio::test]
+async fn test_export_blocks() {
+    let node = mock_node::MockNode::fixtures().await;
+    let client = node.client().await;
+    let output_file = mock_node::temp_path("blocks.json");
+    let output_file = output_file.to_str().unwrap();
+
+    export_blocks(&client, output_file, (0..=4).collect(), false)
         .await
         .unwrap();
 
-    let version = client.get_contract_version(None).await;
+    let read_json = |path| {
+        let json = std::fs::read_to_string(path).unwrap();
+        serde_json::from_str::<serde_json::Value>(&json).unwrap()
+    };
+    assert_eq!(read_json(output_file), read_json("blocks.json"));
+}
+
+#[tokio::test]
+async fn test_export_change_sets() {
+    use crate::db_export::{DBExport, TrieEntries};
+    use subxt::rpc::types::StorageChangeSet;
+
+    let node = mock_node::MockNode::fixtures().await;
+    let client = node.client().await;
+    let output_file = mock_node::temp_path("change-sets.json");
+    let output_file = output_file.to_str().unwrap();
 
-    println!("{:?}", version);
+    export_change_sets(&client, output_file, false)
+        .await
+        .unwrap();
+
+    // replaying the changes of the keys of the last block gives its state
+    let json = std::fs::read_to_string(output_file).unwrap();
+    let change_sets: Vec<StorageChangeSet<H256>> = serde_json::from_str(&json).unwrap();
+    assert_eq!(change_sets[0].block, client.get_blockhash(0).await.unwrap());
+    let mut state = TrieEntries::new();
+    for change_set in change_sets {
+        state.extend(change_set.changes);
+    }
+    state.retain(|_, value| value.is_some());
+    assert_eq!(state, DBExport::load("db-4.json").unwrap().root);
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let CliCommand { url, command } = CliCommand::parse();
-    let client = NodeClient::from_url(&url).await?;
+    let CliCommand {
+        node,
+        output,
+        command,
+    } = CliCommand::parse();
+    if output != OutputFormat::Text && !command.supports_output() {
+        anyhow::bail!(
+            "--output is only supported by print-migrating-blocks, migration-weights, \
+             migration-impact, storage-history and pallet-versions"
+        );
+    }
 
-    match command {
-        SubCommand::ChangeSets { output_file } => {
-            // get all the keys at the last block
-            let keys = client.get_keys(None).await?;
+    // only connect to the node when these commands need it
+    let command = match command {
+        SubCommand::DBDiff(cmd) => return db_diff::diff_db(node, cmd).await,
+        SubCommand::VerifyExport(cmd) => return verify::verify_export(node, cmd).await,
+        SubCommand::DBApply(cmd) => return db_delta::apply_deltas(cmd),
+        SubCommand::Cache(cmd) => return cache::cache_command(&node, cmd),
+        command => command,
+    };
 
-            // get change sets for all these keys since block 0
-            let block_0_hash = client.get_blockhash(0).await?;
-            let change_sets = client
-                .query_storage_value(keys.clone(), block_0_hash)
-                .await?;
+    let client = NodeClient::connect(node).await?;
 
-            write_to_file(&change_sets, output_file)?;
-        }
-        SubCommand::DBExport {
+    match command {
+        SubCommand::ChangeSets {
             output_file,
-            at_block,
+            resume,
         } => {
-            let block_hash = client.get_blockhash(at_block).await?;
-            let keys = client.get_keys(block_hash.into()).await?;
-            let mut db_entries = Vec::new();
-            for key in &keys {
-                let value = client.get_storage_value(&key, block_hash.into()).await?;
-                db_entries.push(DBEntry {
-                    key: key.clone(),
-                    value,
-                });
-            }
-
-            let child_tries = client
-                .get_all_child_storage_pairs(keys, block_hash.into())
-                .await?;
-
-            let child_tries = child_tries
-                .into_iter()
-                .map(|(key, values)| {
-                    let values = values
-                        .into_iter()
-                        .map(|(key, value)| DBEntry { key, value })
-                        .collect();
-                    (key, values)
-                })
-                .collect();
-
-            let db_export = DBExport {
-                child_tries,
-                root: db_entries,
-            };
-
-            write_to_file(&db_export, output_file)?;
+            export_change_sets(&client, &output_file, resume).await?;
+        }
+        SubCommand::DBExport(cmd) => {
+            db_export::export_db(&client, cmd).await?;
+        }
+        SubCommand::DBDelta(cmd) => {
+            db_delta::export_delta(&client, cmd).await?;
+        }
+        SubCommand::DBDiff(_)
+        | SubCommand::VerifyExport(_)
+        | SubCommand::DBApply(_)
+        | SubCommand::Cache(_) => {
+            unreachable!("handled before connecting")
         }
         SubCommand::BlockExport {
             output_file,
             blocks,
+            resume,
         } => {
-            #[derive(Serialize)]
-            struct Helper(#[serde(with = "ChainBlockRef")] ChainBlock<PolkadotConfig>);
-
-            use futures::stream::{self, StreamExt, TryStreamExt};
-
-            let client = &client;
-            let blocks = stream::iter(blocks.clone())
-                .then(|block_number| async move {
-                    let hash = client.get_blockhash(block_number).await?;
-                    let block = client.get_block(Some(hash)).await?;
-                    Ok::<Helper, anyhow::Error>(Helper(block))
-                })
-                .try_collect::<Vec<_>>()
-                .await?;
-
-            write_to_file(&blocks, output_file)?;
+            export_blocks(&client, &output_file, blocks, resume).await?;
         }
-        SubCommand::PrintMigratingBlocks(PrintBlocksCmd {
-            from_block_number: block_number,
-            target_version,
-        }) => {
-            let migrating_blocks = client.stream_migrating_blocks(block_number, target_version);
-            tokio::pin!(migrating_blocks);
-
-            let mut infos = vec![];
-            while let Some(info) = migrating_blocks.try_next().await? {
-                let time = client.get_timestamp(info.block_hash).await?;
-                infos.push(info.clone());
-                println!("{time} -> {info:?}");
-            }
-
-            let last_version = match infos.first() {
-                Some(info) => info.version + 1,
-                _ => return Ok(()),
-            };
-
-            println!("Summary:");
-            infos
-                .iter()
-                .tuple_windows::<(_, _)>()
-                .fold(last_version, |version, (to, from)| {
-                    let to_block = if to.version > version {
-                        to.block_number - 1
-                    } else {
-                        to.block_number
-                    };
-                    let from_block = if from.version == version {
-                        from.block_number
-                    } else {
-                        from.block_number + 1
-                    };
-
-                    println!(
-                        "Version {:02} -> {:02} took {:02} block(s), from blocks {from_block} to {to_block}",
-                        version - 1,
-                        version,
-                        to_block - from_block + 1
-                    );
-                    version - 1
-                });
+        SubCommand::PrintMigratingBlocks(cmd) => {
+            print_migrating_blocks(&client, cmd, output).await?;
         }
         SubCommand::PrintAccountInfo(PrintAccountInfoCmd {
             account_id,
             from_block_number: block_number,
             target_version,
+            parallel_probes,
         }) => {
             // let account_id = hex::decode(account_id).unwrap();
             let account_id = AccountId32::from_ss58check(&account_id)?;
 
-            let migrating_blocks = client.stream_migrating_blocks(block_number, target_version);
+            let migrating_blocks =
+                client.stream_migrating_blocks(block_number, target_version, parallel_probes);
             tokio::pin!(migrating_blocks);
 
             while let Some(info) = migrating_blocks.try_next().await? {
@@ -310,6 +714,21 @@ async fn main() -> Result<()> {
                 println!("{version} -> providers: {:?}", account.map(|a| a.providers));
             }
         }
+        SubCommand::MigrationWeights(cmd) => {
+            weights::print_migration_weights(&client, cmd, output).await?;
+        }
+        SubCommand::MigrationImpact(cmd) => {
+            impact::migration_impact(&client, cmd, output).await?;
+        }
+        SubCommand::StorageHistory(cmd) => {
+            history::print_storage_history(&client, cmd, output).await?;
+        }
+        SubCommand::PalletVersions(cmd) => {
+            versions::print_pallet_versions(&client, cmd, output).await?;
+        }
+        SubCommand::WatchMigration(cmd) => {
+            watch::watch_migration(&client, cmd).await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,512 @@
+//! Decode the `Contracts::MigrationInProgress` cursor of the pallet-contracts migrations.
+//!
+//! While a migration runs, the cursor holds the encoded state of the running step, the migration
+//! from the current storage version to the next one. Most steps iterate over a storage map and
+//! keep the last key they migrated, which tells how far the step has gone.
+
+use crate::node_client::{BlockInfo, NodeClient};
+use crate::output::CsvRecord;
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use codec::DecodeAll;
+use frame_support::storage::storage_prefix;
+use futures::{future, TryStreamExt};
+use itertools::Itertools;
+use serde::Serialize;
+use sp_core::crypto::AccountId32;
+use sp_core::hashing::twox_64;
+use sp_core::H256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use subxt::storage::StorageKey;
+
+#[test]
+fn test_decode_cursor() {
+    use codec::Encode;
+
+    let account = AccountId32::new([1; 32]);
+    let code_hash = H256::repeat_byte(2);
+
+    let position = |version, cursor: &[u8]| decode_cursor(version, cursor).position;
+    assert_eq!(position(9, &None::<AccountId32>.encode()), Position::Start);
+    assert_eq!(
+        position(9, &Some(account.clone()).encode()),
+        Position::Contract(account)
+    );
+    assert_eq!(position(10, &[]), Position::Start);
+    assert_eq!(position(9, &[]), Position::Start);
+    assert_eq!(
+        position(13, &Some(code_hash).encode()),
+        Position::Code(code_hash)
+    );
+
+    // the cursors of unknown steps, or of another layout, are kept as is
+    assert_eq!(position(8, &[0]), Position::Unknown(vec![0]));
+    assert_eq!(position(9, &[1]), Position::Unknown(vec![1]));
+}
+
+#[tokio::test]
+async fn test_migration_progress() {
+    use crate::mock_node::{fixture_contracts, set_migration_in_progress, MockNode, Scenario};
+    use codec::Encode;
+
+    let contracts = fixture_contracts();
+    assert_eq!(contracts.len(), 2);
+
+    let scenario = Scenario::fixtures()
+        .push_block(|state| set_migration_in_progress(state, Some(None::<AccountId32>.encode())))
+        .push_block(|state| {
+            set_migration_in_progress(state, Some(Some(contracts[0].clone()).encode()))
+        });
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    let totals = StepTotals::default();
+    let mut progress = Vec::new();
+    for block_number in 0..3 {
+        let block_hash = client.get_blockhash(block_number).await.unwrap();
+        progress.push(get_progress(&client, &totals, block_hash).await.unwrap());
+    }
+    assert!(progress[0].is_none());
+    // the contracts are counted once for the step
+    assert_eq!(totals.0.lock().unwrap().get(&9), Some(&2));
+    let progress = progress[1..].iter().flatten().collect::<Vec<_>>();
+    assert_eq!(progress[0].items, Some((0, 2)));
+    assert_eq!(
+        progress[0].to_string(),
+        "step 09 -> 10, 0/2 contracts (0.0%)"
+    );
+    assert_eq!(progress[1].items, Some((1, 2)));
+    assert_eq!(
+        progress[1].to_string(),
+        format!(
+            "step 09 -> 10, 1/2 contracts (50.0%), last contract {}",
+            contracts[0]
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_migration_report() {
+    use crate::mock_node::{set_contract_version, set_migration_in_progress, MockNode, Scenario};
+
+    let scenario = Scenario::fixtures()
+        .push_blocks(2, |_| {})
+        .push_block(|state| set_migration_in_progress(state, Some(vec![0])))
+        .push_block(|state| set_contract_version(state, 10))
+        .push_block(|_| {})
+        .push_block(|state| {
+            set_contract_version(state, 11);
+            set_migration_in_progress(state, None);
+        });
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    let mut blocks = Vec::new();
+    for info in client
+        .stream_migrating_blocks(None, 9, 1)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap()
+    {
+        let time = client.get_timestamp(info.block_hash).await.unwrap();
+        blocks.push(MigratingBlock::new(&info, time, None));
+    }
+    let report = MigrationReport::new(&client, blocks).await.unwrap();
+    assert_eq!(report.blocks.len(), 3);
+
+    let transitions = report
+        .transitions
+        .iter()
+        .map(|transition| (transition.to_string(), transition.duration_secs))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        transitions,
+        [
+            (
+                "Version 10 -> 11 took 02 block(s), from blocks 4 to 5".to_string(),
+                6
+            ),
+            (
+                "Version 09 -> 10 took 01 block(s), from blocks 3 to 3".to_string(),
+                0
+            ),
+        ]
+    );
+
+    let csv = crate::output::to_csv(&report.transitions);
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next(),
+        Some("from_version,to_version,start_block,end_block,block_count,start_time,end_time,duration_secs")
+    );
+    assert!(lines.next().unwrap().starts_with("10,11,4,5,2,"));
+}
+
+/// The items a migration step iterates over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Items {
+    /// Contracts, keyed by account id with `Twox64Concat`.
+    Contracts,
+    /// Codes, keyed by code hash with `Identity`.
+    Codes,
+}
+
+impl Items {
+    fn name(&self) -> &'static str {
+        match self {
+            Items::Contracts => "contracts",
+            Items::Codes => "codes",
+        }
+    }
+}
+
+/// The storage map iterated by the step to each version, if the step iterates over one.
+const STEPS: &[(u16, Option<(&str, Items)>)] = &[
+    (10, Some(("ContractInfoOf", Items::Contracts))),
+    (11, None),
+    (12, Some(("OwnerInfoOf", Items::Codes))),
+    (13, Some(("ContractInfoOf", Items::Contracts))),
+    (14, Some(("CodeInfoOf", Items::Codes))),
+    (15, Some(("ContractInfoOf", Items::Contracts))),
+    (16, Some(("ContractInfoOf", Items::Contracts))),
+];
+
+/// How far a migration step has gone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Position {
+    /// No item migrated yet, or a step migrating everything at once.
+    Start,
+    /// The last contract migrated.
+    Contract(AccountId32),
+    /// The last code migrated.
+    Code(H256),
+    /// The cursor of an unknown step, or that could not be decoded.
+    Unknown(Vec<u8>),
+}
+
+/// A decoded `MigrationInProgress` cursor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationCursor {
+    /// The storage version the running step migrates from.
+    pub version: u16,
+    pub position: Position,
+}
+
+impl MigrationCursor {
+    /// The storage map iterated by the running step.
+    fn items(&self) -> Option<(&'static str, Items)> {
+        STEPS
+            .iter()
+            .find(|(to_version, _)| *to_version == self.version + 1)
+            .and_then(|(_, items)| *items)
+    }
+
+    /// The storage key of the last item migrated.
+    fn last_key(&self) -> Option<Vec<u8>> {
+        let (map, _) = self.items()?;
+        let prefix = storage_prefix(b"Contracts", map.as_bytes()).to_vec();
+        match &self.position {
+            Position::Contract(account) => {
+                let account: &[u8] = account.as_ref();
+                Some([prefix, twox_64(account).to_vec(), account.to_vec()].concat())
+            }
+            Position::Code(code_hash) => Some([prefix, code_hash.0.to_vec()].concat()),
+            Position::Start | Position::Unknown(_) => None,
+        }
+    }
+}
+
+/// Decode the cursor of the step migrating from the given storage version.
+pub fn decode_cursor(version: u16, cursor: &[u8]) -> MigrationCursor {
+    let step = STEPS
+        .iter()
+        .find(|(to_version, _)| *to_version == version + 1);
+    let position = match step {
+        Some(_) if cursor.is_empty() => Some(Position::Start),
+        Some((_, Some((_, Items::Contracts)))) => Option::<AccountId32>::decode_all(&mut &*cursor)
+            .ok()
+            .map(|account| account.map_or(Position::Start, Position::Contract)),
+        Some((_, Some((_, Items::Codes)))) => Option::<H256>::decode_all(&mut &*cursor)
+            .ok()
+            .map(|code_hash| code_hash.map_or(Position::Start, Position::Code)),
+        _ => None,
+    };
+
+    MigrationCursor {
+        version,
+        position: position.unwrap_or_else(|| Position::Unknown(cursor.to_vec())),
+    }
+}
+
+/// The progress of a running migration step.
+#[derive(Debug, Clone)]
+pub struct MigrationProgress {
+    pub cursor: MigrationCursor,
+    /// The number of items migrated, and the number of items to migrate, when the step iterates
+    /// over a storage map.
+    pub items: Option<(u64, u64)>,
+}
+
+impl MigrationProgress {
+    /// The share of the items migrated, in percent.
+    pub fn percent(&self) -> Option<f64> {
+        match self.items {
+            Some((migrated, total)) if total > 0 => Some(migrated as f64 * 100.0 / total as f64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MigrationProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = self.cursor.version;
+        write!(f, "step {version:02} -> {:02}", version + 1)?;
+        if let (Some((migrated, total)), Some((_, items))) = (self.items, self.cursor.items()) {
+            write!(f, ", {migrated}/{total} {}", items.name())?;
+        }
+        if let Some(percent) = self.percent() {
+            write!(f, " ({percent:.1}%)")?;
+        }
+        match &self.cursor.position {
+            Position::Start => Ok(()),
+            Position::Contract(account) => write!(f, ", last contract {account}"),
+            Position::Code(code_hash) => write!(f, ", last code {code_hash:?}"),
+            Position::Unknown(cursor) => write!(f, ", cursor 0x{}", hex::encode(cursor)),
+        }
+    }
+}
+
+/// The number of items of each step, by the version it migrates from, counted at the first block
+/// seen running the step.
+#[derive(Debug, Default)]
+pub struct StepTotals(Mutex<HashMap<u16, u64>>);
+
+/// Get the progress of the given cursor at the given block, by locating its last key among the
+/// keys of the storage map iterated by the step.
+///
+/// Steps iterate in key order, so the items up to the last key are migrated. This is a rough
+/// estimate, as steps may insert or remove items along the way. The items of a step are only
+/// counted once, and kept in `totals`, later blocks only page through the migrated keys.
+pub async fn progress(
+    client: &NodeClient,
+    totals: &StepTotals,
+    cursor: MigrationCursor,
+    block_hash: H256,
+) -> Result<MigrationProgress> {
+    let items = match (cursor.items(), &cursor.position) {
+        (Some((map, _)), Position::Start | Position::Contract(_) | Position::Code(_)) => {
+            let prefix = storage_prefix(b"Contracts", map.as_bytes());
+            let last_key = cursor.last_key();
+            let is_migrated =
+                |key: &StorageKey| last_key.as_ref().is_some_and(|last| key.0 <= *last);
+            let keys = client.stream_keys(
+                Some(&prefix),
+                None,
+                crate::node_client::PAGE_SIZE,
+                Some(block_hash),
+            );
+
+            let total = totals.0.lock().unwrap().get(&cursor.version).copied();
+            let (migrated, total) = match total {
+                Some(total) => {
+                    let migrated = keys
+                        .try_take_while(|key| future::ready(Ok(is_migrated(key))))
+                        .try_fold(0, |migrated, _| future::ready(Ok(migrated + 1)))
+                        .await?;
+                    (migrated, total)
+                }
+                None => {
+                    let (migrated, total) = keys
+                        .try_fold((0, 0), |(migrated, total), key| {
+                            let migrated = migrated + is_migrated(&key) as u64;
+                            future::ready(Ok((migrated, total + 1)))
+                        })
+                        .await?;
+                    totals.0.lock().unwrap().insert(cursor.version, total);
+                    (migrated, total)
+                }
+            };
+            Some((migrated, total))
+        }
+        _ => None,
+    };
+    Ok(MigrationProgress { cursor, items })
+}
+
+/// Get the progress of the migration running at the given block, if any.
+pub async fn get_progress(
+    client: &NodeClient,
+    totals: &StepTotals,
+    block_hash: H256,
+) -> Result<Option<MigrationProgress>> {
+    let (version, cursor) = futures::try_join!(
+        client.get_contract_version(Some(block_hash)),
+        client.get_migration_cursor(block_hash),
+    )?;
+    match cursor {
+        Some(cursor) => {
+            let cursor = decode_cursor(version, &cursor);
+            Ok(Some(progress(client, totals, cursor, block_hash).await?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// A block found by [`NodeClient::stream_migrating_blocks`].
+#[derive(Debug, Clone, Serialize)]
+pub struct MigratingBlock {
+    pub block_number: u32,
+    pub block_hash: H256,
+    /// The timestamp of the block, in RFC 3339 format.
+    pub timestamp: String,
+    pub version: u16,
+    pub migration_in_progress: bool,
+    /// The progress of the running step, as printed in text reports.
+    pub progress: Option<String>,
+}
+
+impl MigratingBlock {
+    pub fn new(
+        info: &BlockInfo,
+        timestamp: DateTime<Local>,
+        progress: Option<&MigrationProgress>,
+    ) -> Self {
+        Self {
+            block_number: info.block_number,
+            block_hash: info.block_hash,
+            timestamp: timestamp.to_rfc3339(),
+            version: info.version,
+            migration_in_progress: info.migration_in_progress,
+            progress: progress.map(ToString::to_string),
+        }
+    }
+}
+
+/// The blocks migrating the storage from a version to the next.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VersionTransition {
+    pub from_version: u16,
+    pub to_version: u16,
+    pub start_block: u32,
+    pub end_block: u32,
+    pub block_count: u32,
+    /// The timestamps of the start and end blocks, in RFC 3339 format.
+    pub start_time: String,
+    pub end_time: String,
+    /// The time elapsed from the start block to the end block, in seconds.
+    pub duration_secs: i64,
+}
+
+impl fmt::Display for VersionTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Version {:02} -> {:02} took {:02} block(s), from blocks {} to {}",
+            self.from_version, self.to_version, self.block_count, self.start_block, self.end_block
+        )
+    }
+}
+
+impl CsvRecord for VersionTransition {
+    const HEADER: &'static [&'static str] = &[
+        "from_version",
+        "to_version",
+        "start_block",
+        "end_block",
+        "block_count",
+        "start_time",
+        "end_time",
+        "duration_secs",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.from_version.to_string(),
+            self.to_version.to_string(),
+            self.start_block.to_string(),
+            self.end_block.to_string(),
+            self.block_count.to_string(),
+            self.start_time.clone(),
+            self.end_time.clone(),
+            self.duration_secs.to_string(),
+        ]
+    }
+}
+
+/// The migrating blocks, from the latest, and the version transitions they delimit.
+#[derive(Debug, Serialize)]
+pub struct MigrationReport {
+    pub blocks: Vec<MigratingBlock>,
+    pub transitions: Vec<VersionTransition>,
+}
+
+impl MigrationReport {
+    /// Search the migrations until the target version is reached, and build their report.
+    pub async fn fetch(
+        client: &NodeClient,
+        from_block_number: Option<u32>,
+        target_version: u16,
+        parallel_probes: usize,
+    ) -> Result<Self> {
+        let infos = client
+            .stream_migrating_blocks(from_block_number, target_version, parallel_probes)
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut blocks = Vec::new();
+        for info in &infos {
+            let time = client.get_timestamp(info.block_hash).await?;
+            blocks.push(MigratingBlock::new(info, time, None));
+        }
+        Self::new(client, blocks).await
+    }
+
+    /// Build the report of the given blocks, as streamed from the latest.
+    pub async fn new(client: &NodeClient, blocks: Vec<MigratingBlock>) -> Result<Self> {
+        let mut transitions = Vec::new();
+        let Some(latest) = blocks.first() else {
+            return Ok(Self {
+                blocks,
+                transitions,
+            });
+        };
+
+        let mut version = latest.version + 1;
+        for (to, from) in blocks.iter().tuple_windows() {
+            let end_block = if to.version > version {
+                to.block_number - 1
+            } else {
+                to.block_number
+            };
+            let start_block = if from.version == version {
+                from.block_number
+            } else {
+                from.block_number + 1
+            };
+
+            let [start_time, end_time] = [start_block, end_block].map(|block_number| async move {
+                let block_hash = client.get_blockhash(block_number).await?;
+                client.get_timestamp(block_hash).await
+            });
+            let (start_time, end_time) = futures::try_join!(start_time, end_time)?;
+
+            transitions.push(VersionTransition {
+                from_version: version - 1,
+                to_version: version,
+                start_block,
+                end_block,
+                block_count: end_block - start_block + 1,
+                start_time: start_time.to_rfc3339(),
+                end_time: end_time.to_rfc3339(),
+                duration_secs: (end_time - start_time).num_seconds(),
+            });
+            version -= 1;
+        }
+
+        Ok(Self {
+            blocks,
+            transitions,
+        })
+    }
+}
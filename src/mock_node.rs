@@ -0,0 +1,607 @@
+//! A local node serving the JSON-RPC methods used by this tool, so that the subcommands can be
+//! tested end to end without a live node.
+//!
+//! The chain is served from a [`Backend`]: the fixtures of this repository loaded by a
+//! [`FileBackend`], or a chain built block by block with a [`Scenario`].
+
+use crate::backend::{Backend, Header};
+use crate::db_export::DBExport;
+use crate::file_backend::{read_metadata, FileBackend};
+use crate::node_client::NodeClient;
+use crate::rpc::NodeOptions;
+use crate::verify;
+use codec::{Compact, Decode, Encode};
+use frame_support::storage::storage_prefix;
+use jsonrpsee::core::Error;
+use jsonrpsee::server::{ServerBuilder, ServerHandle};
+use jsonrpsee::types::error::CallError;
+use jsonrpsee::{RpcModule, SubscriptionSink};
+use serde::Serialize;
+use sp_core::crypto::AccountId32;
+use sp_core::H256;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use subxt::config::Header as _;
+use subxt::ext::frame_metadata::{
+    RuntimeMetadata, RuntimeMetadataPrefixed, StorageEntryType, META_RESERVED,
+};
+use subxt::ext::scale_value::{self, Value};
+use subxt::metadata::Metadata;
+use subxt::rpc::types::{
+    Bytes, ChainBlock, ChainBlockExtrinsic, NumberOrHex, StorageChangeSet, StorageData,
+};
+use subxt::storage::StorageKey;
+use subxt::utils::MultiAddress;
+use subxt::PolkadotConfig;
+
+#[tokio::test]
+async fn test_mock_node() {
+    let node = MockNode::fixtures().await;
+    let client = node.client().await;
+    let offline = FileBackend::load(Path::new(".")).unwrap();
+
+    // the node serves the fixtures as the offline backend does
+    let hash = client.get_blockhash(4).await.unwrap();
+    assert_eq!(Some(hash), offline.block_hash(Some(4)).await.unwrap());
+    assert_eq!(
+        client.get_header(hash).await.unwrap(),
+        offline.header(Some(hash)).await.unwrap().unwrap()
+    );
+    assert_eq!(client.get_blocknumber().await.unwrap(), 4);
+    assert_eq!(client.get_state_version(hash).await.unwrap(), 1);
+    assert_eq!(
+        client.get_block(Some(hash)).await.unwrap().extrinsics.len(),
+        offline
+            .block(Some(hash))
+            .await
+            .unwrap()
+            .unwrap()
+            .extrinsics
+            .len()
+    );
+
+    // unknown blocks are reported as such
+    assert!(client.get_blockhash(5).await.is_err());
+}
+
+#[tokio::test]
+async fn test_scenario() {
+    let scenario = Scenario::fixtures()
+        .push_blocks(2, |_| {})
+        .push_block(|state| set_contract_version(state, 10));
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    let mut versions = Vec::new();
+    for number in 0..4 {
+        versions.push(client.get_block_info(Some(number)).await.unwrap().version);
+    }
+    assert_eq!(versions, [9, 9, 9, 10]);
+
+    // the state roots of the built blocks match their state
+    let hash = client.get_blockhash(3).await.unwrap();
+    assert_eq!(client.get_state_version(hash).await.unwrap(), 1);
+
+    let timestamps = [client.get_blockhash(2).await.unwrap(), hash];
+    let [before, after] = timestamps.map(|hash| client.get_timestamp(hash));
+    let elapsed = after.await.unwrap() - before.await.unwrap();
+    assert_eq!(elapsed.num_milliseconds(), BLOCK_TIME_MS as i64);
+}
+
+/// A path in the temporary directory, unique to the test process.
+pub fn temp_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("contracts-query-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+/// A node listening on a local port, until dropped.
+pub struct MockNode {
+    url: String,
+    _server: ServerHandle,
+}
+
+impl MockNode {
+    /// Serve the chain of the given backend.
+    pub async fn start(backend: impl Backend + 'static) -> Self {
+        let backend: Box<dyn Backend> = Box::new(backend);
+        let server = ServerBuilder::default().build("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let server = server.start(rpc_module(backend)).unwrap();
+        Self {
+            url,
+            _server: server,
+        }
+    }
+
+    /// Serve the fixtures of this repository, blocks 0 to 4.
+    pub async fn fixtures() -> Self {
+        Self::start(FileBackend::load(Path::new(".")).unwrap()).await
+    }
+
+    /// The options connecting to this node.
+    pub fn options(&self) -> NodeOptions {
+        NodeOptions::with_url(&self.url)
+    }
+
+    /// A client connected to this node.
+    pub async fn client(&self) -> NodeClient {
+        NodeClient::from_url(&self.url).await.unwrap()
+    }
+}
+
+/// The time between two blocks of a [`Scenario`].
+pub const BLOCK_TIME_MS: u64 = 6000;
+
+/// A chain built block by block, each state derived from the state of the previous block.
+pub struct Scenario {
+    blocks: Vec<ChainBlock<PolkadotConfig>>,
+    states: BTreeMap<u32, DBExport>,
+    metadata: Metadata,
+    /// The metadata of each runtime upgrade, by the block setting it.
+    upgrades: Vec<(u32, Metadata)>,
+}
+
+impl Scenario {
+    /// A chain whose genesis state is the state of the last block of the fixtures.
+    pub fn fixtures() -> Self {
+        let genesis = DBExport::load("db-4.json").unwrap();
+        Self::new(genesis, read_metadata(Path::new("metadata.scale")).unwrap())
+    }
+
+    pub fn new(genesis: DBExport, metadata: Metadata) -> Self {
+        let mut scenario = Self {
+            blocks: Vec::new(),
+            states: BTreeMap::new(),
+            metadata,
+            upgrades: Vec::new(),
+        };
+        scenario.seal(genesis, Vec::new());
+        scenario
+    }
+
+    /// Append a block whose state is the state of the previous block changed by `change`, with
+    /// its timestamp [`BLOCK_TIME_MS`] later.
+    pub fn push_block(self, change: impl FnOnce(&mut DBExport)) -> Self {
+        self.push_block_with_extrinsics(Vec::new(), change)
+    }
+
+    /// Append a block holding the given encoded extrinsics, see [`Self::push_block`].
+    pub fn push_block_with_extrinsics(
+        mut self,
+        extrinsics: Vec<Vec<u8>>,
+        change: impl FnOnce(&mut DBExport),
+    ) -> Self {
+        let mut state = self.states.values().last().unwrap().clone();
+        let now_key = storage_prefix(b"Timestamp", b"Now");
+        if let Some(Some(StorageData(now))) = state.root.get(&StorageKey(now_key.to_vec())) {
+            let now = u64::decode(&mut now.as_slice()).unwrap() + BLOCK_TIME_MS;
+            set_value(&mut state, &now_key, Some(now.encode()));
+        }
+        change(&mut state);
+        self.seal(state, extrinsics);
+        self
+    }
+
+    /// Append a block upgrading the runtime to one with the given metadata, see
+    /// [`FileBackend::with_upgrade`].
+    pub fn push_upgrade_block(
+        mut self,
+        metadata: Metadata,
+        change: impl FnOnce(&mut DBExport),
+    ) -> Self {
+        self = self.push_block(change);
+        let number = self.blocks.len() as u32 - 1;
+        self.upgrades.push((number, metadata));
+        self
+    }
+
+    /// Append `count` blocks, each changed by `change`.
+    pub fn push_blocks(mut self, count: u32, change: impl Fn(&mut DBExport)) -> Self {
+        for _ in 0..count {
+            self = self.push_block(&change);
+        }
+        self
+    }
+
+    /// Append the block holding the given state and extrinsics.
+    fn seal(&mut self, state: DBExport, extrinsics: Vec<Vec<u8>>) {
+        let parent = self.blocks.last().map(|block| &block.header);
+        let header = Header {
+            parent_hash: parent.map(|header| header.hash()).unwrap_or_default(),
+            number: parent.map_or(0, |header| header.number + 1),
+            state_root: verify::verify(&state, 1).root,
+            extrinsics_root: H256::zero(),
+            digest: Default::default(),
+        };
+        self.states.insert(header.number, state);
+        self.blocks.push(ChainBlock {
+            header,
+            extrinsics: extrinsics.into_iter().map(ChainBlockExtrinsic).collect(),
+        });
+    }
+
+    pub fn into_backend(self) -> FileBackend {
+        let backend = FileBackend::new(self.blocks, self.states, self.metadata);
+        self.upgrades
+            .into_iter()
+            .fold(backend, |backend, (number, metadata)| {
+                backend.with_upgrade(number, metadata)
+            })
+    }
+}
+
+/// The contracts of the fixtures, in the order of their `ContractInfoOf` keys.
+pub fn fixture_contracts() -> Vec<AccountId32> {
+    let prefix = storage_prefix(b"Contracts", b"ContractInfoOf");
+    DBExport::load("db-4.json")
+        .unwrap()
+        .root
+        .into_keys()
+        .filter(|key| key.0.starts_with(&prefix))
+        .map(|key| AccountId32::new(key.0[prefix.len() + 8..].try_into().unwrap()))
+        .collect()
+}
+
+/// Set or remove the value of a root entry.
+pub fn set_value(state: &mut DBExport, key: &[u8], value: Option<Vec<u8>>) {
+    let key = StorageKey(key.to_vec());
+    match value {
+        Some(value) => state.root.insert(key, Some(StorageData(value))),
+        None => state.root.remove(&key),
+    };
+}
+
+/// Set the storage version of pallet-contracts.
+pub fn set_contract_version(state: &mut DBExport, version: u16) {
+    let key = storage_prefix(b"Contracts", b":__STORAGE_VERSION__:");
+    set_value(state, &key, Some(version.encode()));
+}
+
+/// Set the `[ref_time, proof_size]` weight consumed by the normal and mandatory dispatch classes.
+pub fn set_block_weight(state: &mut DBExport, normal: [u64; 2], mandatory: [u64; 2]) {
+    let weight = |[ref_time, proof_size]: [u64; 2]| (Compact(ref_time), Compact(proof_size));
+    let consumed = (weight(normal), weight([0, 0]), weight(mandatory));
+    let key = storage_prefix(b"System", b"BlockWeight");
+    set_value(state, &key, Some(consumed.encode()));
+}
+
+/// Set the cursor of the migration in progress, or remove it once the migration completed.
+pub fn set_migration_in_progress(state: &mut DBExport, cursor: Option<Vec<u8>>) {
+    let key = storage_prefix(b"Contracts", b"MigrationInProgress");
+    set_value(state, &key, cursor.map(|cursor| cursor.encode()));
+}
+
+/// Set the events of the block, each a value of the runtime event type emitted by the extrinsic
+/// at the given index.
+pub fn set_events(state: &mut DBExport, metadata: &Metadata, events: Vec<(u32, Value)>) {
+    let records = events.into_iter().map(|(index, event)| {
+        Value::named_composite([
+            (
+                "phase",
+                Value::unnamed_variant("ApplyExtrinsic", [Value::u128(index.into())]),
+            ),
+            ("event", event),
+            ("topics", Value::unnamed_composite([])),
+        ])
+    });
+    let entry = metadata
+        .pallet("System")
+        .unwrap()
+        .storage("Events")
+        .unwrap();
+    let StorageEntryType::Plain(ty) = &entry.ty else {
+        panic!("System::Events is not a plain storage item");
+    };
+    let mut bytes = Vec::new();
+    let records = Value::unnamed_composite(records);
+    scale_value::scale::encode_as_type(&records, ty.id, metadata.types(), &mut bytes).unwrap();
+    set_value(state, &storage_prefix(b"System", b"Events"), Some(bytes));
+}
+
+/// An immortal extrinsic of `signer`, with a zero nonce, tip and signature, dispatching the given
+/// value of the runtime call type.
+pub fn signed_extrinsic(metadata: &Metadata, signer: &AccountId32, call: Value) -> Vec<u8> {
+    let ty = metadata
+        .resolve_type(metadata.runtime_metadata().extrinsic.ty.id)
+        .unwrap();
+    let call_ty = ty
+        .type_params
+        .iter()
+        .find(|param| param.name == "Call")
+        .and_then(|param| param.ty)
+        .unwrap();
+
+    let signature = (1u8, [0u8; 64]);
+    let (era, nonce, tip) = (0u8, Compact(0u32), Compact(0u128));
+    let mut bytes = (
+        0x84u8,
+        MultiAddress::<_, ()>::Id(signer.clone()),
+        signature,
+        era,
+        nonce,
+        tip,
+    )
+        .encode();
+    scale_value::scale::encode_as_type(&call, call_ty.id, metadata.types(), &mut bytes).unwrap();
+    bytes
+}
+
+/// The response of `chain_getBlock`.
+#[derive(Serialize)]
+struct BlockResponse {
+    #[serde(with = "crate::ChainBlockRef")]
+    block: ChainBlock<PolkadotConfig>,
+    justifications: Option<()>,
+}
+
+/// The response of `state_getReadProof` and `state_getChildReadProof`.
+#[derive(Serialize)]
+struct ReadProofResponse {
+    at: H256,
+    proof: Vec<Bytes>,
+}
+
+fn failed(err: anyhow::Error) -> Error {
+    Error::Call(CallError::Failed(err))
+}
+
+fn block_number(number: NumberOrHex) -> Result<u32, Error> {
+    number
+        .into_u256()
+        .try_into()
+        .map_err(|_| failed(anyhow::format_err!("invalid block number")))
+}
+
+/// The hash of the given block, or of the latest block.
+async fn resolve(backend: &dyn Backend, block_hash: Option<H256>) -> Result<H256, Error> {
+    match block_hash {
+        Some(hash) => Ok(hash),
+        None => backend
+            .block_hash(None)
+            .await
+            .map_err(failed)?
+            .ok_or_else(|| failed(anyhow::format_err!("no blocks"))),
+    }
+}
+
+/// The metadata of the runtime at the given block, as decoded by subxt.
+async fn encoded_metadata(
+    backend: &dyn Backend,
+    block_hash: Option<H256>,
+) -> Result<Vec<u8>, Error> {
+    let block_hash = resolve(backend, block_hash).await?;
+    let metadata = backend.metadata(block_hash).await.map_err(failed)?;
+    let metadata = RuntimeMetadata::V14(metadata.runtime_metadata().clone());
+    Ok(RuntimeMetadataPrefixed(META_RESERVED, metadata).encode())
+}
+
+/// The proof of the given keys, the nodes shared by several keys are only included once.
+async fn read_proof(
+    backend: &dyn Backend,
+    child_trie: Option<&StorageKey>,
+    keys: Vec<StorageKey>,
+    block_hash: Option<H256>,
+) -> Result<ReadProofResponse, Error> {
+    let at = resolve(backend, block_hash).await?;
+    let mut proof = Vec::new();
+    for key in keys {
+        for node in backend
+            .read_proof(child_trie, &key.0, at)
+            .await
+            .map_err(failed)?
+        {
+            if !proof.contains(&node) {
+                proof.push(node);
+            }
+        }
+    }
+    Ok(ReadProofResponse {
+        at,
+        proof: proof.into_iter().map(Bytes).collect(),
+    })
+}
+
+/// Send the heads of the backend to the subscriber, until they end.
+fn pipe_heads(mut sink: SubscriptionSink, backend: Arc<Box<dyn Backend>>, finalized: bool) {
+    tokio::spawn(async move {
+        match backend.subscribe_heads(finalized).await {
+            Ok(heads) => {
+                sink.pipe_from_try_stream(heads).await;
+            }
+            Err(err) => {
+                let _ = sink.reject(CallError::Failed(err));
+            }
+        }
+    });
+}
+
+/// The `chain_*`, `state_*` and `childstate_*` methods and the head subscriptions, answered by
+/// the backend. Runtime calls are limited to fetching the metadata.
+fn rpc_module(backend: Box<dyn Backend>) -> RpcModule<Box<dyn Backend>> {
+    let mut module = RpcModule::new(backend);
+
+    module
+        .register_async_method("chain_getBlockHash", |params, backend| async move {
+            let number = params.sequence().optional_next::<NumberOrHex>()?;
+            let number = number.map(block_number).transpose()?;
+            backend.block_hash(number).await.map_err(failed)
+        })
+        .unwrap();
+    module
+        .register_async_method("chain_getFinalizedHead", |_, backend| async move {
+            resolve(&**backend, None).await
+        })
+        .unwrap();
+    module
+        .register_async_method("chain_getHeader", |params, backend| async move {
+            let block_hash = params.sequence().optional_next::<H256>()?;
+            backend.header(block_hash).await.map_err(failed)
+        })
+        .unwrap();
+    module
+        .register_async_method("chain_getBlock", |params, backend| async move {
+            let block_hash = params.sequence().optional_next::<H256>()?;
+            let block = backend.block(block_hash).await.map_err(failed)?;
+            Ok(block.map(|block| BlockResponse {
+                block,
+                justifications: None,
+            }))
+        })
+        .unwrap();
+    module
+        .register_async_method("state_getRuntimeVersion", |params, backend| async move {
+            let block_hash = params.sequence().optional_next::<H256>()?;
+            let block_hash = resolve(&**backend, block_hash).await?;
+            let version = backend.runtime_version(block_hash).await.map_err(failed)?;
+
+            let mut json = serde_json::Map::from_iter(version.other);
+            json.insert("specVersion".into(), version.spec_version.into());
+            json.insert(
+                "transactionVersion".into(),
+                version.transaction_version.into(),
+            );
+            Ok(json)
+        })
+        .unwrap();
+    module
+        .register_async_method("state_getMetadata", |params, backend| async move {
+            let block_hash = params.sequence().optional_next::<H256>()?;
+            Ok(Bytes(encoded_metadata(&**backend, block_hash).await?))
+        })
+        .unwrap();
+    module
+        .register_async_method("state_call", |params, backend| async move {
+            let mut params = params.sequence();
+            let function = params.next::<String>()?;
+            let _call_parameters = params.next::<Bytes>()?;
+            let block_hash = params.optional_next::<H256>()?;
+            match function.as_str() {
+                // the metadata is returned as an opaque `Vec<u8>`
+                "Metadata_metadata" => {
+                    let metadata = encoded_metadata(&**backend, block_hash).await?;
+                    Ok(Bytes(metadata.encode()))
+                }
+                _ => Err(failed(anyhow::format_err!("{function} is not supported"))),
+            }
+        })
+        .unwrap();
+    module
+        .register_async_method("state_getStorage", |params, backend| async move {
+            let mut params = params.sequence();
+            let key = params.next::<StorageKey>()?;
+            let block_hash = params.optional_next::<H256>()?;
+            let values = backend
+                .storage_values(None, vec![key], block_hash)
+                .await
+                .map_err(failed)?;
+            Ok(values.into_iter().next().and_then(|(_, value)| value))
+        })
+        .unwrap();
+    module
+        .register_async_method("state_getKeysPaged", |params, backend| async move {
+            let mut params = params.sequence();
+            let prefix = params.optional_next::<StorageKey>()?;
+            let count = params.next::<u32>()?;
+            let start_key = params.optional_next::<StorageKey>()?;
+            let block_hash = params.optional_next::<H256>()?;
+            let prefix = prefix.map(|prefix| prefix.0).unwrap_or_default();
+            backend
+                .storage_keys_paged(None, &prefix, count, start_key, block_hash)
+                .await
+                .map_err(failed)
+        })
+        .unwrap();
+    module
+        .register_async_method("state_queryStorageAt", |params, backend| async move {
+            let mut params = params.sequence();
+            let keys = params.next::<Vec<StorageKey>>()?;
+            let block_hash = params.optional_next::<H256>()?;
+            let block = resolve(&**backend, block_hash).await?;
+            let changes = backend
+                .storage_values(None, keys, Some(block))
+                .await
+                .map_err(failed)?;
+            Ok(vec![StorageChangeSet { block, changes }])
+        })
+        .unwrap();
+    module
+        .register_async_method("state_queryStorage", |params, backend| async move {
+            let mut params = params.sequence();
+            let keys = params.next::<Vec<StorageKey>>()?;
+            let from = params.next::<H256>()?;
+            let to = params.optional_next::<H256>()?;
+            backend.query_storage(keys, from, to).await.map_err(failed)
+        })
+        .unwrap();
+    module
+        .register_async_method("state_getReadProof", |params, backend| async move {
+            let mut params = params.sequence();
+            let keys = params.next::<Vec<StorageKey>>()?;
+            let block_hash = params.optional_next::<H256>()?;
+            read_proof(&**backend, None, keys, block_hash).await
+        })
+        .unwrap();
+    module
+        .register_async_method("state_getChildReadProof", |params, backend| async move {
+            let mut params = params.sequence();
+            let child_trie = params.next::<StorageKey>()?;
+            let keys = params.next::<Vec<StorageKey>>()?;
+            let block_hash = params.optional_next::<H256>()?;
+            read_proof(&**backend, Some(&child_trie), keys, block_hash).await
+        })
+        .unwrap();
+    module
+        .register_async_method("childstate_getKeysPaged", |params, backend| async move {
+            let mut params = params.sequence();
+            let child_trie = params.next::<StorageKey>()?;
+            let prefix = params.optional_next::<StorageKey>()?;
+            let count = params.next::<u32>()?;
+            let start_key = params.optional_next::<StorageKey>()?;
+            let block_hash = params.optional_next::<H256>()?;
+            let prefix = prefix.map(|prefix| prefix.0).unwrap_or_default();
+            backend
+                .storage_keys_paged(Some(&child_trie), &prefix, count, start_key, block_hash)
+                .await
+                .map_err(failed)
+        })
+        .unwrap();
+    module
+        .register_async_method("childstate_getStorage", |params, backend| async move {
+            let mut params = params.sequence();
+            let child_trie = params.next::<StorageKey>()?;
+            let key = params.next::<StorageKey>()?;
+            let block_hash = params.optional_next::<H256>()?;
+            let values = backend
+                .storage_values(Some(&child_trie), vec![key], block_hash)
+                .await
+                .map_err(failed)?;
+            Ok(values.into_iter().next().and_then(|(_, value)| value))
+        })
+        .unwrap();
+    module
+        .register_subscription(
+            "chain_subscribeNewHeads",
+            "chain_newHead",
+            "chain_unsubscribeNewHeads",
+            |_, sink, backend| {
+                pipe_heads(sink, backend, false);
+                Ok(())
+            },
+        )
+        .unwrap();
+    module
+        .register_subscription(
+            "chain_subscribeFinalizedHeads",
+            "chain_finalizedHead",
+            "chain_unsubscribeFinalizedHeads",
+            |_, sink, backend| {
+                pipe_heads(sink, backend, true);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+    module
+}
@@ -1,39 +1,335 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
 
 use anyhow::Result;
 use chrono::prelude::*;
 use codec::Decode;
 use frame_support::storage::storage_prefix;
 use futures::stream::{self, StreamExt, TryStreamExt};
-use sp_core::storage::well_known_keys::CHILD_STORAGE_KEY_PREFIX;
+use serde::Serialize;
 use sp_core::H256;
-use subxt::rpc::types::{ChainBlock, ChainBlockResponse, StorageChangeSet, StorageData};
-use subxt::rpc_params;
-use subxt::storage::StorageKey;
+use subxt::dynamic::{DecodedValueThunk, Value};
+use subxt::ext::frame_metadata::StorageEntryType;
+use subxt::ext::scale_decode::DecodeAsType;
+use subxt::ext::scale_value::{self, scale::TypeId, At, ValueDef};
+use subxt::metadata::{DecodeWithMetadata, Metadata};
+use subxt::rpc::types::{ChainBlock, RuntimeVersion, StorageChangeSet, StorageData};
+use subxt::storage::address::Yes;
+use subxt::storage::{Address, StorageAddress, StorageKey};
 use subxt::utils::AccountId32;
-use subxt::{config::PolkadotConfig, OnlineClient};
+use subxt::{config::PolkadotConfig, OfflineClient};
+
+use crate::backend::{Backend, BackendStream, Header, RpcBackend};
+use crate::bisect::{self, ProbeCache};
+use crate::cache::CachingClient;
+use crate::cassette::{Recorder, Replayer};
+use crate::file_backend::FileBackend;
+use crate::rpc::{NodeOptions, ReconnectingClient, RpcTransport};
 use tokio_stream::Stream;
 
-use self::polkadot::runtime_types::frame_system::AccountInfo;
-use self::polkadot::runtime_types::pallet_balances::types::AccountData;
-
 #[test]
 fn print_prefixes() {
-    use sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+    use sp_core::storage::well_known_keys::{
+        CHILD_STORAGE_KEY_PREFIX, DEFAULT_CHILD_STORAGE_KEY_PREFIX,
+    };
     dbg!(to_hex(CHILD_STORAGE_KEY_PREFIX));
     dbg!(to_hex(DEFAULT_CHILD_STORAGE_KEY_PREFIX));
 }
 
-/// Note, generate the file with subxt metadata -f bytes > metadata.scale
-#[subxt::subxt(runtime_metadata_path = "./metadata.scale")]
-mod polkadot {}
+/// A fake backend serving sorted keys page by page, the way `state_getKeysPaged` does.
+#[cfg(test)]
+struct FakeKeysBackend {
+    keys: std::collections::BTreeSet<StorageKey>,
+    requests: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl FakeKeysBackend {
+    fn new(keys: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        Self {
+            keys: keys.into_iter().map(StorageKey).collect(),
+            requests: Default::default(),
+        }
+    }
+
+    async fn keys_paged(
+        &self,
+        prefix: &[u8],
+        count: u32,
+        start_key: Option<StorageKey>,
+    ) -> Result<Vec<StorageKey>> {
+        self.requests
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Ok(self
+            .keys
+            .iter()
+            .filter(|k| k.0.starts_with(prefix) && Some(*k) > start_key.as_ref())
+            .take(count as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn collect(&self, prefix: &[u8], page_size: u32) -> (Vec<StorageKey>, usize) {
+        let keys = paginate_keys(page_size, None, |start_key| {
+            self.keys_paged(prefix, page_size, start_key)
+        })
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+        let requests = self.requests.load(std::sync::atomic::Ordering::SeqCst);
+        (keys, requests)
+    }
+}
+
+#[tokio::test]
+async fn test_paginate_keys_ends_on_short_page() {
+    let backend = FakeKeysBackend::new((0..250u32).map(|i| i.to_be_bytes().to_vec()));
+    let (keys, requests) = backend.collect(&[], 100).await;
 
+    assert_eq!(keys, backend.keys.iter().cloned().collect::<Vec<_>>());
+    assert_eq!(requests, 3);
+}
+
+#[tokio::test]
+async fn test_paginate_keys_ends_on_empty_page() {
+    let backend = FakeKeysBackend::new((0..200u32).map(|i| i.to_be_bytes().to_vec()));
+    let (keys, requests) = backend.collect(&[], 100).await;
+
+    assert_eq!(keys.len(), 200);
+    assert_eq!(requests, 3);
+}
+
+#[tokio::test]
+async fn test_paginate_keys_with_prefix() {
+    let backend =
+        FakeKeysBackend::new((0..150u8).flat_map(|i| [vec![1, i], vec![2, i], vec![3, i]]));
+    let (keys, _) = backend.collect(&[2], 20).await;
+
+    assert_eq!(
+        keys,
+        (0..150u8)
+            .map(|i| StorageKey(vec![2, i]))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn test_stream_migrating_blocks() {
+    use crate::mock_node::{set_contract_version, set_migration_in_progress, MockNode, Scenario};
+
+    // v9 up to block 2, migrating from block 3 to block 5, and v11 from block 6
+    let scenario = Scenario::fixtures()
+        .push_blocks(2, |_| {})
+        .push_block(|state| set_migration_in_progress(state, Some(vec![0])))
+        .push_block(|state| set_contract_version(state, 10))
+        .push_block(|_| {})
+        .push_block(|state| {
+            set_contract_version(state, 11);
+            set_migration_in_progress(state, None);
+        })
+        .push_blocks(2, |_| {});
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    let infos = client
+        .stream_migrating_blocks(None, 9, 1)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    let infos = infos
+        .iter()
+        .map(|info| (info.block_number, info.version, info.migration_in_progress))
+        .collect::<Vec<_>>();
+    assert_eq!(infos, [(5, 10, true), (3, 9, true), (2, 9, false)]);
+}
+
+#[tokio::test]
+async fn test_find_previous_migration_info() {
+    use crate::mock_node::{set_contract_version, MockNode, Scenario};
+
+    // v9 up to block 35, and v10 from block 36 to the head at block 39
+    let scenario = Scenario::fixtures()
+        .push_blocks(35, |_| {})
+        .push_block(|state| set_contract_version(state, 10))
+        .push_blocks(3, |_| {});
+    let node = MockNode::start(scenario.into_backend()).await;
+
+    // the head, then blocks 38, 37 and 35 walking back and block 36 bisecting, plus block 31
+    // when probing 4 blocks at once
+    for (parallel_probes, expected_probes) in [(1, 5), (4, 6)] {
+        let client = node.client().await;
+        let head = client.get_block_info(None).await.unwrap();
+        let info = client
+            .find_previous_migration_info(&head, parallel_probes)
+            .await
+            .unwrap();
+        assert_eq!((info.block_number, info.version), (35, 9));
+        assert_eq!(client.probes(), expected_probes);
+
+        // and the probed blocks are not fetched again
+        let infos = client
+            .stream_migrating_blocks(None, 9, parallel_probes)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(client.probes(), expected_probes);
+    }
+}
+
+#[tokio::test]
+async fn test_runtime_upgrade() {
+    use crate::file_backend::read_metadata;
+    use crate::mock_node::{MockNode, Scenario};
+    use std::path::Path;
+    use subxt::ext::frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed, META_RESERVED};
+
+    // block 2 upgrades to a runtime without the Timestamp pallet
+    let mut upgraded = read_metadata(Path::new("metadata.scale"))
+        .unwrap()
+        .runtime_metadata()
+        .clone();
+    upgraded.pallets.retain(|pallet| pallet.name != "Timestamp");
+    let upgraded = RuntimeMetadataPrefixed(META_RESERVED, RuntimeMetadata::V14(upgraded));
+    let scenario = Scenario::fixtures()
+        .push_block(|_| {})
+        .push_upgrade_block(upgraded.try_into().unwrap(), |_| {})
+        .push_block(|_| {});
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    // the upgrade block is executed by the previous runtime, and decoded with its metadata
+    let [upgrade, next] = [2, 3].map(|number| client.get_blockhash(number));
+    let (upgrade, next) = (upgrade.await.unwrap(), next.await.unwrap());
+    assert!(client
+        .get_metadata(Some(upgrade))
+        .await
+        .unwrap()
+        .pallet("Timestamp")
+        .is_ok());
+    assert!(client.get_timestamp(upgrade).await.is_ok());
+    assert!(client
+        .get_metadata(Some(next))
+        .await
+        .unwrap()
+        .pallet("Timestamp")
+        .is_err());
+    assert!(client.get_timestamp(next).await.is_err());
+}
+
+/// A client to query a node.
+///
+/// Storage is decoded with the metadata fetched from the node, through subxt's dynamic storage
+/// API, so the same binary works against any chain regardless of its runtime.
+/// The metadata is resolved at the parent of the queried block, the runtime that executed it, so
+/// blocks on both sides of a runtime upgrade are decoded with their own types.
 pub struct NodeClient {
-    client: OnlineClient<PolkadotConfig>,
+    backend: Box<dyn Backend>,
+    genesis_hash: H256,
+    /// The version of the runtime at each block hash we have queried.
+    runtime_versions: Mutex<HashMap<H256, RuntimeVersion>>,
+    /// The parent of each block whose runtime we have resolved.
+    parent_hashes: Mutex<HashMap<H256, H256>>,
+    /// A client carrying the metadata of each spec version we have loaded.
+    runtimes: Mutex<HashMap<u32, OfflineClient<PolkadotConfig>>>,
+    /// The migration info of each block number we have probed.
+    block_infos: ProbeCache<BlockInfo>,
 }
 
 type StorageVersion = u16;
 
+/// A storage key and its value.
+pub type StoragePair = (StorageKey, Option<StorageData>);
+
+/// The number of storage values fetched in a single request.
+const BATCH_SIZE: usize = 100;
+
+/// The default number of keys fetched in a single request.
+pub const PAGE_SIZE: u32 = 1000;
+
+/// A dynamic storage address, decoding the value into `T` using the node's metadata.
+type DynamicAddress<T> = Address<Value, T, Yes, Yes, Yes>;
+
+/// The `frame_system::AccountInfo` fields we care about.
+///
+/// Fields are decoded by name, so the other fields of the runtime type, and changes to them
+/// (e.g. to `AccountData`), are ignored.
+#[derive(Debug, DecodeAsType)]
+#[decode_as_type(crate_path = "subxt::ext::scale_decode")]
+pub struct AccountInfo {
+    pub providers: u32,
+}
+
+/// A `sp_weights::Weight`, the time and proof size consumed or allowed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, DecodeAsType)]
+#[decode_as_type(crate_path = "subxt::ext::scale_decode")]
+pub struct Weight {
+    pub ref_time: u64,
+    pub proof_size: u64,
+}
+
+impl std::ops::Add for Weight {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            ref_time: self.ref_time.saturating_add(other.ref_time),
+            proof_size: self.proof_size.saturating_add(other.proof_size),
+        }
+    }
+}
+
+/// The weight consumed by each dispatch class in a block, as stored in `System::BlockWeight`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, DecodeAsType)]
+#[decode_as_type(crate_path = "subxt::ext::scale_decode")]
+pub struct ConsumedWeight {
+    pub normal: Weight,
+    pub operational: Weight,
+    pub mandatory: Weight,
+}
+
+impl ConsumedWeight {
+    pub fn total(&self) -> Weight {
+        self.normal + self.operational + self.mandatory
+    }
+}
+
+/// The phase of the block in which an event was emitted.
+#[derive(Debug, Clone, Copy, PartialEq, DecodeAsType)]
+#[decode_as_type(crate_path = "subxt::ext::scale_decode")]
+pub enum Phase {
+    ApplyExtrinsic(u32),
+    Finalization,
+    Initialization,
+}
+
+/// A `frame_system::EventRecord`, its event decoded as a value of the runtime event type.
+#[derive(Debug, DecodeAsType)]
+#[decode_as_type(crate_path = "subxt::ext::scale_decode")]
+pub struct EventRecord {
+    pub phase: Phase,
+    pub event: scale_value::Value<TypeId>,
+}
+
+impl EventRecord {
+    /// The pallet emitting the event, and the name of the event.
+    pub fn name(&self) -> Option<(&str, &str)> {
+        let ValueDef::Variant(pallet) = &self.event.value else {
+            return None;
+        };
+        let Some(ValueDef::Variant(event)) = pallet.values.values().next().map(|v| &v.value) else {
+            return None;
+        };
+        Some((&pallet.name, &event.name))
+    }
+
+    /// The given field of the event.
+    pub fn field(&self, name: &str) -> Option<&scale_value::Value<TypeId>> {
+        self.event.at(0)?.at(name)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockInfo {
     pub block_hash: H256,
@@ -50,19 +346,192 @@ impl BlockInfo {
 }
 
 impl NodeClient {
+    /// Connect to the node with the default options.
+    #[cfg(test)]
     pub async fn from_url(url: &str) -> Result<NodeClient> {
-        let client = OnlineClient::<PolkadotConfig>::from_url(url).await?;
-        Ok(NodeClient { client })
+        Self::connect(NodeOptions::with_url(url)).await
+    }
+
+    /// Connect to the node, retrying failed requests as configured and going through the cache
+    /// and the recorder when enabled, or load the exports of the `--offline` directory, or the
+    /// cassette to `--replay`.
+    pub async fn connect(options: NodeOptions) -> Result<NodeClient> {
+        let backend: Box<dyn Backend> = if let Some(dir) = &options.offline {
+            Box::new(FileBackend::load(dir)?)
+        } else if let Some(cassette) = &options.replay {
+            Box::new(RpcBackend::new(Replayer::load(cassette)?).await?)
+        } else {
+            let mut rpc: Box<dyn RpcTransport> =
+                Box::new(ReconnectingClient::connect(options.clone()).await?);
+            // cache hits are recorded too, so that the cassette replays without the cache
+            if options.cache {
+                rpc = Box::new(CachingClient::new(rpc, &options.cache_dir()?).await?);
+            }
+            if let Some(cassette) = &options.record {
+                rpc = Box::new(Recorder::create(rpc, cassette)?);
+            }
+            Box::new(RpcBackend::new(rpc).await?)
+        };
+        Self::new(backend).await
+    }
+
+    pub async fn new(backend: Box<dyn Backend>) -> Result<NodeClient> {
+        let genesis_hash = backend
+            .block_hash(Some(0))
+            .await?
+            .ok_or_else(|| anyhow::format_err!("genesis block not found"))?;
+        Ok(NodeClient {
+            backend,
+            genesis_hash,
+            runtime_versions: Default::default(),
+            parent_hashes: Default::default(),
+            runtimes: Default::default(),
+            block_infos: Default::default(),
+        })
+    }
+
+    /// Get the hash of the given block, or of the latest block if none is provided.
+    async fn resolve_blockhash(&self, block_hash: Option<H256>) -> Result<H256> {
+        match block_hash {
+            Some(hash) => Ok(hash),
+            None => self
+                .backend
+                .block_hash(None)
+                .await?
+                .ok_or_else(|| anyhow::format_err!("latest block hash not found")),
+        }
+    }
+
+    /// Get the version of the runtime at the given block.
+    async fn get_runtime_version(&self, block_hash: H256) -> Result<RuntimeVersion> {
+        if let Some(version) = self.runtime_versions.lock().unwrap().get(&block_hash) {
+            return Ok(version.clone());
+        }
+
+        let version = self.backend.runtime_version(block_hash).await?;
+
+        self.runtime_versions
+            .lock()
+            .unwrap()
+            .insert(block_hash, version.clone());
+        Ok(version)
+    }
+
+    /// Get the state version of the runtime at the given block, i.e. its trie layout.
+    pub async fn get_state_version(&self, block_hash: H256) -> Result<u8> {
+        let version = self.get_runtime_version(block_hash).await?;
+
+        // runtimes predating state versions use the first layout
+        let state_version = version
+            .other
+            .get("stateVersion")
+            .and_then(|v| v.as_u64())
+            .unwrap_or_default();
+        Ok(state_version as u8)
+    }
+
+    /// Get the parent of the given block, the genesis block being its own parent.
+    async fn get_parent_hash(&self, block_hash: H256) -> Result<H256> {
+        if let Some(parent_hash) = self.parent_hashes.lock().unwrap().get(&block_hash) {
+            return Ok(*parent_hash);
+        }
+
+        let header = self.get_header(block_hash).await?;
+        let parent_hash = match header.number {
+            0 => block_hash,
+            _ => header.parent_hash,
+        };
+        self.parent_hashes
+            .lock()
+            .unwrap()
+            .insert(block_hash, parent_hash);
+        Ok(parent_hash)
+    }
+
+    /// Get a client holding the metadata of the runtime that executed the given block.
+    ///
+    /// That is the runtime at the parent block: at a block upgrading the runtime, the node already
+    /// reports the new one, while the storage, events and extrinsics of the block were written by
+    /// the previous one. The metadata is fetched once per spec version, at the first block we see
+    /// using it.
+    async fn runtime_at(&self, block_hash: H256) -> Result<OfflineClient<PolkadotConfig>> {
+        let parent_hash = self.get_parent_hash(block_hash).await?;
+        let runtime_version = self.get_runtime_version(parent_hash).await?;
+        let spec_version = runtime_version.spec_version;
+        if let Some(runtime) = self.runtimes.lock().unwrap().get(&spec_version) {
+            return Ok(runtime.clone());
+        }
+
+        let metadata = self.backend.metadata(parent_hash).await?;
+        let runtime = OfflineClient::new(self.genesis_hash, runtime_version, metadata);
+        self.runtimes
+            .lock()
+            .unwrap()
+            .insert(spec_version, runtime.clone());
+        Ok(runtime)
+    }
+
+    /// Get the metadata of the runtime that executed the given block, see [`Self::runtime_at`].
+    pub async fn get_metadata(&self, block_hash: Option<H256>) -> Result<Metadata> {
+        let block_hash = self.resolve_blockhash(block_hash).await?;
+        Ok(self.runtime_at(block_hash).await?.metadata())
+    }
+
+    /// Fetch and decode the value at the given storage address, using the metadata of the
+    /// runtime that executed the given block.
+    async fn fetch<Addr: StorageAddress>(
+        &self,
+        addr: &Addr,
+        block_hash: Option<H256>,
+    ) -> Result<Option<Addr::Target>> {
+        let block_hash = self.resolve_blockhash(block_hash).await?;
+        let runtime = self.runtime_at(block_hash).await?;
+        let key = runtime.storage().address_bytes(addr)?;
+
+        let Some(StorageData(value)) = self.get_storage_value(key, Some(block_hash)).await? else {
+            return Ok(None);
+        };
+
+        let metadata = runtime.metadata();
+        let entry = metadata
+            .pallet(addr.pallet_name())?
+            .storage(addr.entry_name())?;
+        let type_id = match &entry.ty {
+            StorageEntryType::Plain(ty) => ty.id,
+            StorageEntryType::Map { value, .. } => value.id,
+        };
+
+        let value = Addr::Target::decode_with_metadata(&mut value.as_slice(), type_id, &metadata)?;
+        Ok(Some(value))
+    }
+
+    /// Get the key of the given storage item and map keys, using the metadata of the runtime at
+    /// the given block.
+    pub async fn get_storage_key(
+        &self,
+        pallet: &str,
+        item: &str,
+        keys: Vec<Value>,
+        block_hash: H256,
+    ) -> Result<Vec<u8>> {
+        let runtime = self.runtime_at(block_hash).await?;
+        let addr: DynamicAddress<DecodedValueThunk> = Address::new(pallet, item, keys);
+        Ok(runtime.storage().address_bytes(&addr)?)
     }
 
     /// Get the block number of the current block.
     pub async fn get_blocknumber(&self) -> Result<u32> {
-        Ok(self.client.blocks().at_latest().await?.number())
+        let header = self
+            .backend
+            .header(None)
+            .await?
+            .ok_or_else(|| anyhow::format_err!("latest block not found"))?;
+        Ok(header.number)
     }
 
     /// Get the contract storage version.
     pub async fn get_contract_version(&self, block_hash: Option<H256>) -> Result<StorageVersion> {
-        let key = storage_prefix(b"Contracts", b":__STORAGE_VERSION__:");
+        let key = storage_version_key("Contracts");
         let StorageData(value) = self
             .get_storage_value(key, block_hash)
             .await?
@@ -72,36 +541,74 @@ impl NodeClient {
             .map_err(|reason| anyhow::format_err!("failed to decode StorageVersion: {:?}", reason))
     }
 
+    /// Get the storage version of the pallets with the given storage prefixes, `None` for the
+    /// pallets without one.
+    pub async fn get_pallet_versions(
+        &self,
+        prefixes: &[String],
+        block_hash: H256,
+    ) -> Result<Vec<Option<StorageVersion>>> {
+        let keys = prefixes
+            .iter()
+            .map(|prefix| StorageKey(storage_version_key(prefix).to_vec()))
+            .collect();
+        let values = self.get_storage_values(keys, Some(block_hash)).await?;
+
+        values
+            .into_iter()
+            .map(|(_, value)| {
+                let Some(StorageData(value)) = value else {
+                    return Ok(None);
+                };
+                StorageVersion::decode(&mut value.as_slice())
+                    .map(Some)
+                    .map_err(|reason| {
+                        anyhow::format_err!("failed to decode StorageVersion: {:?}", reason)
+                    })
+            })
+            .collect()
+    }
+
     pub async fn contracts_migration_in_progress(&self, block_hash: Option<H256>) -> Result<bool> {
-        let addr = polkadot::storage().contracts().migration_in_progress();
+        let addr: DynamicAddress<DecodedValueThunk> =
+            Address::new("Contracts", "MigrationInProgress", vec![]);
 
-        let storage = if let Some(hash) = block_hash {
-            self.client.storage().at(hash)
-        } else {
-            self.client.storage().at_latest().await?
+        let is_in_progress = self.fetch(&addr, block_hash).await?.is_some();
+        Ok(is_in_progress)
+    }
+
+    /// Get the `MigrationInProgress` cursor of the contracts migration, if one is running.
+    ///
+    /// The cursor is the encoded state of the running step, see [`crate::migration`].
+    pub async fn get_migration_cursor(&self, block_hash: H256) -> Result<Option<Vec<u8>>> {
+        let key = storage_prefix(b"Contracts", b"MigrationInProgress");
+        let Some(StorageData(value)) = self.get_storage_value(key, Some(block_hash)).await? else {
+            return Ok(None);
         };
+        let cursor = Vec::<u8>::decode(&mut value.as_slice()).map_err(|reason| {
+            anyhow::format_err!("failed to decode MigrationInProgress: {reason}")
+        })?;
+        Ok(Some(cursor))
+    }
 
-        let is_in_progress = storage.fetch(&addr).await?.is_some();
-        Ok(is_in_progress)
+    /// Subscribe to the headers of the new best blocks, or of the finalized blocks.
+    pub async fn subscribe_heads(&self, finalized: bool) -> Result<BackendStream<Header>> {
+        self.backend.subscribe_heads(finalized).await
     }
 
     /// Get the block hash of the given block number.
     pub async fn get_blockhash(&self, block_number: u32) -> Result<H256> {
-        self.client
-            .rpc()
-            .request("chain_getBlockHash", rpc_params![block_number])
-            .await
-            .map_err(|reason| anyhow::format_err!("failed to get block hash: {:?}", reason))
+        self.backend
+            .block_hash(Some(block_number))
+            .await?
+            .ok_or_else(|| anyhow::format_err!("block {block_number} not found"))
     }
 
     /// Get the timestamp of the given block.
     pub async fn get_timestamp(&self, block_hash: H256) -> Result<DateTime<Local>> {
-        let now_addr = polkadot::storage().timestamp().now();
+        let now_addr: DynamicAddress<u64> = Address::new("Timestamp", "Now", vec![]);
         let now = self
-            .client
-            .storage()
-            .at(block_hash)
-            .fetch(&now_addr)
+            .fetch(&now_addr, Some(block_hash))
             .await?
             .ok_or_else(|| anyhow::format_err!("timestamp::now not found"))?;
 
@@ -113,119 +620,109 @@ impl NodeClient {
         Ok(now)
     }
 
-    /// Get all the keys in storage at the given block.
-    pub async fn get_keys(&self, block_hash: Option<H256>) -> Result<Vec<StorageKey>> {
-        const PAGE_SIZE: usize = 100;
-        let mut keys = Vec::<StorageKey>::new();
-        let mut start_key = None;
-        let rpc = self.client.rpc();
-
-        loop {
-            let new_keys = rpc
-                .storage_keys_paged(&[], PAGE_SIZE as u32, start_key, block_hash)
-                .await
-                .map_err(|reason| anyhow::format_err!("get_keys failed: {:?}", reason))?;
-
-            let has_more = new_keys.len() > PAGE_SIZE;
-            keys.extend(new_keys);
-            if !has_more {
-                break;
+    /// Stream the keys in storage at the given block, optionally restricted to the given prefix.
+    pub fn stream_keys(
+        &self,
+        prefix: Option<&[u8]>,
+        start_key: Option<StorageKey>,
+        page_size: u32,
+        block_hash: Option<H256>,
+    ) -> impl Stream<Item = Result<StorageKey>> + '_ {
+        let prefix = prefix.unwrap_or_default().to_vec();
+        paginate_keys(page_size, start_key, move |start_key| {
+            let prefix = prefix.clone();
+            async move {
+                self.backend
+                    .storage_keys_paged(None, &prefix, page_size, start_key, block_hash)
+                    .await
             }
-            start_key = keys.last().map(|k| k.as_ref());
-        }
-
-        Ok(keys)
+        })
     }
 
-    pub async fn get_all_child_storage_pairs(
+    /// Stream the keys of the given child trie, optionally restricted to the given prefix.
+    pub fn stream_child_keys(
         &self,
-        keys: Vec<StorageKey>,
+        child_key: StorageKey,
+        prefix: Option<&[u8]>,
+        start_key: Option<StorageKey>,
+        page_size: u32,
         block_hash: Option<H256>,
-    ) -> Result<HashMap<StorageKey, Vec<(StorageKey, Option<StorageData>)>>> {
-        let child_keys = keys
-            .into_iter()
-            .filter(|k| k.0.starts_with(CHILD_STORAGE_KEY_PREFIX));
-
-        let map = stream::iter(child_keys)
-            .then(|key| async move {
-                let pair = self
-                    .get_child_storage_pair(key.as_ref(), block_hash)
-                    .await?;
+    ) -> impl Stream<Item = Result<StorageKey>> + '_ {
+        let prefix = prefix.unwrap_or_default().to_vec();
+        paginate_keys(page_size, start_key, move |start_key| {
+            let child_key = child_key.clone();
+            let prefix = prefix.clone();
+            async move {
+                self.backend
+                    .storage_keys_paged(Some(&child_key), &prefix, page_size, start_key, block_hash)
+                    .await
+            }
+        })
+    }
 
-                Ok::<(StorageKey, Vec<(StorageKey, Option<StorageData>)>), anyhow::Error>((
-                    key, pair,
-                ))
+    /// Stream the values of the given keys, fetching up to `concurrency` batches of
+    /// [`BATCH_SIZE`] keys at once. Pairs are yielded in the order of the keys.
+    pub fn stream_storage_pairs<'a>(
+        &'a self,
+        keys: impl Stream<Item = Result<StorageKey>> + 'a,
+        block_hash: Option<H256>,
+        concurrency: usize,
+    ) -> impl Stream<Item = Result<StoragePair>> + 'a {
+        keys.chunks(BATCH_SIZE)
+            .map(move |chunk| async move {
+                let keys = chunk.into_iter().collect::<Result<Vec<_>>>()?;
+                self.get_storage_values(keys, block_hash).await
             })
-            .try_collect::<HashMap<_, _>>()
-            .await?;
-
-        Ok(map)
+            .buffered(concurrency)
+            .map_ok(|pairs| stream::iter(pairs.into_iter().map(Ok)))
+            .try_flatten()
     }
 
-    pub async fn get_child_storage_pair(
+    /// Stream the key-value pairs of the given child trie, fetching up to `concurrency` batches
+    /// of [`BATCH_SIZE`] values at once. Pairs are yielded in the order of the keys.
+    pub fn stream_child_storage_pairs(
         &self,
-        key: &[u8],
+        child_key: StorageKey,
+        prefix: Option<&[u8]>,
+        start_key: Option<StorageKey>,
+        page_size: u32,
         block_hash: Option<H256>,
-    ) -> Result<Vec<(StorageKey, Option<StorageData>)>> {
-        const PAGE_SIZE: usize = 100;
-        let mut start_key: &[u8] = &[];
-        let prefix: &[u8] = &[];
-
-        let mut pairs = Vec::<(StorageKey, Option<StorageData>)>::new();
-        let rpc = self.client.rpc();
-
-        loop {
-            let new_keys: Vec<StorageKey> = rpc
-                .request(
-                    "childstate_getKeysPaged",
-                    rpc_params![
-                        to_hex(key),
-                        to_hex(prefix),
-                        100,
-                        to_hex(start_key),
-                        block_hash
-                    ],
-                )
-                .await?;
-
-            let has_more = new_keys.len() > PAGE_SIZE;
-
-            let new_pairs = stream::iter(new_keys)
-                .then(|child_key| async move {
-                    let data = self
-                        .get_child_key_storage(key.as_ref(), child_key.as_ref(), block_hash)
-                        .await?;
-                    Ok::<(StorageKey, Option<StorageData>), anyhow::Error>((child_key, data))
-                })
-                .try_collect::<Vec<_>>()
-                .await?;
-
-            pairs.extend(new_pairs);
-            if !has_more {
-                break;
-            }
-
-            start_key = pairs.last().map(|(k, _)| k.as_ref()).unwrap_or_default();
-        }
+        concurrency: usize,
+    ) -> impl Stream<Item = Result<StoragePair>> + '_ {
+        self.stream_child_keys(child_key.clone(), prefix, start_key, page_size, block_hash)
+            .chunks(BATCH_SIZE)
+            .map(move |chunk| {
+                let child_key = child_key.clone();
+                async move {
+                    let keys = chunk.into_iter().collect::<Result<Vec<_>>>()?;
+                    self.get_child_storage_values(&child_key, keys, block_hash)
+                        .await
+                }
+            })
+            .buffered(concurrency)
+            .map_ok(|pairs| stream::iter(pairs.into_iter().map(Ok)))
+            .try_flatten()
+    }
 
-        Ok(pairs)
+    /// Get the values of the given child trie keys.
+    pub async fn get_child_storage_values(
+        &self,
+        child_key: &StorageKey,
+        keys: Vec<StorageKey>,
+        block_hash: Option<H256>,
+    ) -> Result<Vec<StoragePair>> {
+        self.backend
+            .storage_values(Some(child_key), keys, block_hash)
+            .await
     }
 
-    pub async fn get_child_key_storage(
+    /// Get the values of the given keys.
+    pub async fn get_storage_values(
         &self,
-        key: &[u8],
-        child_key: &[u8],
+        keys: Vec<StorageKey>,
         block_hash: Option<H256>,
-    ) -> Result<Option<StorageData>> {
-        let data = self
-            .client
-            .rpc()
-            .request(
-                "childstate_getStorage",
-                rpc_params![to_hex(key), to_hex(child_key), block_hash],
-            )
-            .await?;
-        Ok(data)
+    ) -> Result<Vec<StoragePair>> {
+        self.backend.storage_values(None, keys, block_hash).await
     }
 
     pub async fn get_storage_value<K: AsRef<[u8]>>(
@@ -233,52 +730,115 @@ impl NodeClient {
         key: K,
         block_hash: Option<H256>,
     ) -> Result<Option<StorageData>> {
-        self.client
-            .rpc()
-            .storage(key.as_ref(), block_hash)
-            .await
-            .map_err(|err| anyhow::format_err!("failed to get storage value: {:?}", err))
+        let key = StorageKey(key.as_ref().to_vec());
+        let mut values = self.get_storage_values(vec![key], block_hash).await?;
+        Ok(values.pop().and_then(|(_, value)| value))
     }
 
+    /// Query the changes of the given keys from `block_hash` to `to_block_hash`, or to the best
+    /// block. The first change set holds the values at `block_hash`.
     pub async fn query_storage_value(
         &self,
         keys: Vec<StorageKey>,
         block_hash: H256,
+        to_block_hash: Option<H256>,
     ) -> Result<Vec<StorageChangeSet<H256>>> {
-        let keys = keys.iter().map(|k| &*k.0);
-        self.client
-            .rpc()
-            .query_storage(keys, block_hash, None)
+        self.backend
+            .query_storage(keys, block_hash, to_block_hash)
+            .await
+    }
+
+    /// Query the changes of the given keys between two blocks, running up to `concurrency`
+    /// queries of [`BATCH_SIZE`] keys at once.
+    pub fn stream_storage_changes(
+        &self,
+        keys: Vec<StorageKey>,
+        from: H256,
+        to: H256,
+        concurrency: usize,
+    ) -> impl Stream<Item = Result<Vec<StorageChangeSet<H256>>>> + '_ {
+        let chunks = keys
+            .chunks(BATCH_SIZE)
+            .map(|chunk| chunk.to_vec())
+            .collect::<Vec<_>>();
+        stream::iter(chunks)
+            .map(move |keys| self.query_storage_value(keys, from, Some(to)))
+            .buffered(concurrency)
+    }
+
+    pub async fn get_header(&self, block_hash: H256) -> Result<Header> {
+        self.backend
+            .header(Some(block_hash))
+            .await?
+            .ok_or_else(|| anyhow::format_err!("header not found"))
+    }
+
+    /// Get the trie nodes proving the given keys, or their absence.
+    pub async fn get_read_proof(&self, key: &[u8], block_hash: H256) -> Result<Vec<Vec<u8>>> {
+        self.backend.read_proof(None, key, block_hash).await
+    }
+
+    /// Get the trie nodes proving the given key, or its absence, in a child trie.
+    pub async fn get_child_read_proof(
+        &self,
+        child_key: &StorageKey,
+        key: &[u8],
+        block_hash: H256,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.backend
+            .read_proof(Some(child_key), key, block_hash)
             .await
-            .map_err(|err| anyhow::format_err!("get_keys failed: {:?}", err))
     }
 
     pub async fn get_block(&self, block_hash: Option<H256>) -> Result<ChainBlock<PolkadotConfig>> {
-        self.client
-            .rpc()
+        self.backend
             .block(block_hash)
             .await?
             .ok_or_else(|| anyhow::format_err!("block not found"))
-            .map(|ChainBlockResponse { block, .. }| block)
     }
 
     pub async fn get_account_info(
         &self,
         account: AccountId32,
         block_hash: Option<H256>,
-    ) -> Result<Option<AccountInfo<u32, AccountData<u128>>>> {
-        let addr = polkadot::storage().system().account(account);
-
-        let storage = if let Some(hash) = block_hash {
-            self.client.storage().at(hash)
-        } else {
-            self.client.storage().at_latest().await?
-        };
+    ) -> Result<Option<AccountInfo>> {
+        let addr: DynamicAddress<AccountInfo> =
+            Address::new("System", "Account", vec![Value::from_bytes(account)]);
 
-        let data = storage.fetch(&addr).await?;
+        let data = self.fetch(&addr, block_hash).await?;
         Ok(data)
     }
 
+    /// Get the weight consumed in the given block.
+    pub async fn get_block_weight(&self, block_hash: H256) -> Result<ConsumedWeight> {
+        let addr: DynamicAddress<ConsumedWeight> = Address::new("System", "BlockWeight", vec![]);
+        self.fetch(&addr, Some(block_hash))
+            .await?
+            .ok_or_else(|| anyhow::format_err!("System::BlockWeight not found"))
+    }
+
+    /// Get the events emitted in the given block.
+    pub async fn get_events(&self, block_hash: H256) -> Result<Vec<EventRecord>> {
+        let addr: DynamicAddress<Vec<EventRecord>> = Address::new("System", "Events", vec![]);
+        let events = self.fetch(&addr, Some(block_hash)).await?;
+        Ok(events.unwrap_or_default())
+    }
+
+    /// Get a constant of the runtime at the given block.
+    pub async fn get_constant<T: DecodeWithMetadata>(
+        &self,
+        pallet: &str,
+        constant: &str,
+        block_hash: H256,
+    ) -> Result<T> {
+        let runtime = self.runtime_at(block_hash).await?;
+        let addr = subxt::constants::Address::<T>::new(pallet, constant);
+        Ok(runtime.constants().at(&addr)?)
+    }
+
+    /// Get the migration info of the given block, or of the latest block if none is provided.
+    ///
+    /// The info of each block is fetched once, and counted in [`Self::probes`].
     pub async fn get_block_info(&self, block_number: Option<u32>) -> Result<BlockInfo> {
         let block_number = if let Some(block_number) = block_number {
             block_number
@@ -286,29 +846,40 @@ impl NodeClient {
             self.get_blocknumber().await?
         };
 
-        let block_hash = self.get_blockhash(block_number).await?;
-        let (version, migration_in_progress) = futures::try_join!(
-            self.get_contract_version(Some(block_hash)),
-            self.contracts_migration_in_progress(block_hash.into()),
-        )?;
-
-        Ok(BlockInfo {
-            block_hash,
-            block_number,
-            version,
-            migration_in_progress,
-        })
+        let fetch = |block_number| async move {
+            let block_hash = self.get_blockhash(block_number).await?;
+            let (version, migration_in_progress) = futures::try_join!(
+                self.get_contract_version(Some(block_hash)),
+                self.contracts_migration_in_progress(block_hash.into()),
+            )?;
+
+            Ok(BlockInfo {
+                block_hash,
+                block_number,
+                version,
+                migration_in_progress,
+            })
+        };
+        self.block_infos.probe(block_number, fetch).await
+    }
+
+    /// The number of blocks whose info was fetched by [`Self::get_block_info`].
+    pub fn probes(&self) -> usize {
+        self.block_infos.probes()
     }
 
     pub fn stream_migrating_blocks(
         &self,
         from_block_number: Option<u32>,
         target_version: u16,
+        parallel_probes: usize,
     ) -> impl Stream<Item = anyhow::Result<BlockInfo>> + '_ {
         async_stream::try_stream!({
             let mut info = self.get_block_info(from_block_number).await?;
             loop {
-                info = self.find_previous_migration_info(&info).await?;
+                info = self
+                    .find_previous_migration_info(&info, parallel_probes)
+                    .await?;
                 yield info.clone();
                 if info.version <= target_version && !info.migration_in_progress {
                     break;
@@ -316,39 +887,68 @@ impl NodeClient {
             }
         })
     }
+
+    /// Find the latest block before `initial_info` whose migration info differs, or block 0 if
+    /// the whole history matches, see [`bisect::find_previous_change`].
     pub async fn find_previous_migration_info(
         &self,
         initial_info: &BlockInfo,
+        parallel_probes: usize,
     ) -> Result<BlockInfo> {
-        // git bisect between 0..start_block_number to find the oldest block where BlockMigrationInfo == initial_state
-        let mut lower = 0;
-        let mut upper = initial_info.block_number;
-        loop {
-            let block_number = (lower + upper) / 2;
-            let mut info = self.get_block_info(block_number.into()).await?;
+        if initial_info.block_number == 0 {
+            anyhow::bail!("no migration found before block 0");
+        }
 
-            //  the previous block is in [lower, mid]
-            if info.matching_migration_info(initial_info) {
-                upper = block_number;
+        let change = bisect::find_previous_change(
+            initial_info.block_number,
+            0,
+            parallel_probes,
+            |block_number| self.get_block_info(Some(block_number)),
+            |info| info.matching_migration_info(initial_info),
+        )
+        .await?;
+        match change {
+            Some((_, info)) => Ok(info),
+            // the whole history matches, e.g. on a chain exported from genesis
+            None => self.get_block_info(Some(0)).await,
+        }
+    }
+}
 
-            // the previous block is in [mid, upper]
-            } else {
-                lower = block_number;
-            }
+/// Stream the keys following `start_key` page by page, `fetch_page(start_key)` returning up to
+/// `page_size` keys following `start_key`. The stream ends on the first page shorter than
+/// `page_size`.
+fn paginate_keys<'a, F, Fut>(
+    page_size: u32,
+    mut start_key: Option<StorageKey>,
+    mut fetch_page: F,
+) -> impl Stream<Item = Result<StorageKey>> + 'a
+where
+    F: FnMut(Option<StorageKey>) -> Fut + 'a,
+    Fut: Future<Output = Result<Vec<StorageKey>>> + 'a,
+{
+    async_stream::try_stream!({
+        loop {
+            let keys = fetch_page(start_key.take()).await?;
+            let is_last_page = keys.is_empty() || keys.len() < page_size as usize;
+            start_key = keys.last().cloned();
 
-            // stop when the upper and lower bounds are adjacent
-            if upper - lower <= 1 {
-                if info.matching_migration_info(initial_info) {
-                    let previous_block = info.block_number - 1;
-                    info = self.get_block_info(previous_block.into()).await?;
-                }
+            for key in keys {
+                yield key;
+            }
 
-                return Ok(info);
+            if is_last_page {
+                break;
             }
         }
-    }
+    })
+}
+
+/// The key of the storage version of the pallet with the given storage prefix.
+pub fn storage_version_key(prefix: &str) -> [u8; 32] {
+    storage_prefix(prefix.as_bytes(), b":__STORAGE_VERSION__:")
 }
 
-fn to_hex(bytes: impl AsRef<[u8]>) -> String {
+pub fn to_hex(bytes: impl AsRef<[u8]>) -> String {
     format!("0x{}", hex::encode(bytes.as_ref()))
 }
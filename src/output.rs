@@ -0,0 +1,95 @@
+//! The formats of the reports printed by the subcommands, selected with `--output`.
+
+use anyhow::Result;
+use serde::Serialize;
+
+#[test]
+fn test_csv() {
+    struct Row(&'static str, u32);
+    impl CsvRecord for Row {
+        const HEADER: &'static [&'static str] = &["name", "count"];
+        fn fields(&self) -> Vec<String> {
+            vec![self.0.to_string(), self.1.to_string()]
+        }
+    }
+
+    let rows = [
+        Row("plain", 1),
+        Row("with, comma", 2),
+        Row("with \"quotes\"", 3),
+    ];
+    assert_eq!(
+        to_csv(&rows),
+        "name,count\nplain,1\n\"with, comma\",2\n\"with \"\"quotes\"\"\",3\n"
+    );
+}
+
+/// The format of a report.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// A single json document.
+    Json,
+    /// One json object per line, for each row of the report.
+    Jsonl,
+    /// A header line, followed by a line for each row of the report.
+    Csv,
+}
+
+/// A row of a csv report.
+pub trait CsvRecord {
+    /// The name of each column.
+    const HEADER: &'static [&'static str];
+
+    /// The value of each column, in the order of [`Self::HEADER`].
+    fn fields(&self) -> Vec<String>;
+}
+
+/// Quote a csv field when it holds a separator, a quote or a line break.
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The csv document of the given rows, with its header.
+pub fn to_csv<T: CsvRecord>(rows: &[T]) -> String {
+    let mut csv = T::HEADER.join(",");
+    csv.push('\n');
+    for row in rows {
+        let fields = row.fields();
+        csv.push_str(
+            &fields
+                .iter()
+                .map(|field| escape(field))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Print the report in a machine readable format: the whole report as json, or its rows as json
+/// lines or csv. Text reports are printed by each subcommand.
+pub fn print_report<R, T>(format: OutputFormat, report: &R, rows: &[T]) -> Result<()>
+where
+    R: Serialize,
+    T: Serialize + CsvRecord,
+{
+    match format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+        OutputFormat::Jsonl => {
+            for row in rows {
+                println!("{}", serde_json::to_string(row)?);
+            }
+        }
+        OutputFormat::Csv => print!("{}", to_csv(rows)),
+    }
+    Ok(())
+}
@@ -0,0 +1,586 @@
+//! A JSON-RPC client retrying failed requests, and reconnecting to the node when the websocket
+//! connection drops.
+//!
+//! Transient failures, such as timeouts or rate limiting, are retried with an exponential backoff
+//! and jitter. Other errors, e.g. invalid params or an unknown block, fail right away. Subscriptions
+//! are resumed on the new connection.
+
+use anyhow::Result;
+use futures::stream::StreamExt;
+use jsonrpsee::core::client::{ClientT, Subscription, SubscriptionClientT, SubscriptionKind};
+use jsonrpsee::core::params::BatchRequestBuilder;
+use jsonrpsee::core::traits::ToRpcParams;
+use jsonrpsee::core::Error;
+use jsonrpsee::types::error::{CallError, ErrorObject, SERVER_IS_BUSY_CODE};
+use jsonrpsee::types::SubscriptionId;
+use jsonrpsee::ws_client::{WsClient, WsClientBuilder};
+use rand::Rng;
+use serde_json::value::RawValue;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use subxt::error::RpcError;
+use subxt::rpc::{RpcClientT, RpcFuture, RpcSubscription};
+use tokio::sync::RwLock;
+
+/// A stand-in node answering `counter` with the number of calls so far, after failing the calls
+/// listed in `failures`, and notifying `subscribe_ticks` subscribers of 1, 2, 3...
+#[cfg(test)]
+async fn failing_server(
+    addr: &str,
+    failures: Vec<(usize, Error)>,
+) -> (std::net::SocketAddr, jsonrpsee::server::ServerHandle) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let failures = Arc::new(std::sync::Mutex::new(failures));
+    let mut module = jsonrpsee::RpcModule::new(());
+    module
+        .register_async_method("counter", move |_, _| {
+            let calls = calls.clone();
+            let failures = failures.clone();
+            async move {
+                let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                let failure = {
+                    let mut failures = failures.lock().unwrap();
+                    let index = failures.iter().position(|(nth, _)| *nth == call);
+                    index.map(|index| failures.remove(index).1)
+                };
+                match failure {
+                    // stand for a node too slow to answer
+                    Some(Error::RequestTimeout) => {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        Ok(call)
+                    }
+                    Some(err) => Err(err),
+                    None => Ok(call),
+                }
+            }
+        })
+        .unwrap();
+    module
+        .register_subscription(
+            "subscribe_ticks",
+            "tick",
+            "unsubscribe_ticks",
+            |_, mut sink, _| {
+                let ticks = futures::stream::unfold(1, |tick| async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    Some((tick, tick + 1))
+                });
+                tokio::spawn(async move { sink.pipe_from_stream(Box::pin(ticks)).await });
+                Ok(())
+            },
+        )
+        .unwrap();
+
+    // the address of a stopped server may not be released yet
+    let server = loop {
+        match jsonrpsee::server::ServerBuilder::default()
+            .build(addr)
+            .await
+        {
+            Ok(server) => break server,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let addr = server.local_addr().unwrap();
+    (addr, server.start(module).unwrap())
+}
+
+#[cfg(test)]
+fn test_options(url: String) -> NodeOptions {
+    NodeOptions {
+        url,
+        offline: None,
+        record: None,
+        replay: None,
+        cache: false,
+        cache_dir: None,
+        max_retries: 3,
+        retry_delay_ms: 10,
+        request_timeout: 1,
+    }
+}
+
+#[cfg(test)]
+fn call_error(code: i32, message: &str) -> Error {
+    Error::Call(CallError::Custom(ErrorObject::owned(
+        code,
+        message.to_string(),
+        None::<()>,
+    )))
+}
+
+#[cfg(test)]
+async fn counter(client: &ReconnectingClient) -> Result<usize, Error> {
+    client
+        .call(|rpc| async move { rpc.request("counter", Params(None)).await })
+        .await
+}
+
+#[tokio::test]
+async fn test_retry_transient_errors() {
+    let failures = vec![
+        (1, call_error(-32005, "Too many requests")),
+        (2, call_error(SERVER_IS_BUSY_CODE, "Server is busy")),
+        (3, Error::RequestTimeout),
+    ];
+    let (addr, _server) = failing_server("127.0.0.1:0", failures).await;
+    let client = ReconnectingClient::connect(test_options(format!("ws://{addr}")))
+        .await
+        .unwrap();
+
+    assert_eq!(counter(&client).await.unwrap(), 4);
+}
+
+#[tokio::test]
+async fn test_fail_on_fatal_errors() {
+    use jsonrpsee::types::error::INTERNAL_ERROR_CODE;
+
+    let busy = || call_error(SERVER_IS_BUSY_CODE, "Server is busy");
+    let failures = vec![
+        (1, call_error(-32602, "Invalid params")),
+        (2, call_error(INTERNAL_ERROR_CODE, "Execution failed")),
+        (3, busy()),
+        (4, busy()),
+        (5, busy()),
+        (6, busy()),
+    ];
+    let (addr, _server) = failing_server("127.0.0.1:0", failures).await;
+    let mut options = test_options(format!("ws://{addr}"));
+    options.max_retries = 2;
+    let client = ReconnectingClient::connect(options).await.unwrap();
+
+    // not retried
+    assert!(counter(&client).await.is_err());
+    assert!(counter(&client).await.is_err());
+
+    // retried twice, then the error of the third attempt is returned
+    let err = counter(&client).await.unwrap_err();
+    assert!(
+        matches!(err, Error::Call(CallError::Custom(err)) if err.code() == SERVER_IS_BUSY_CODE)
+    );
+    // the last failure is retried
+    assert_eq!(counter(&client).await.unwrap(), 7);
+}
+
+#[tokio::test]
+async fn test_reconnect() {
+    let (addr, server) = failing_server("127.0.0.1:0", vec![]).await;
+    let mut options = test_options(format!("ws://{addr}"));
+    options.max_retries = 10;
+    let client = ReconnectingClient::connect(options).await.unwrap();
+    assert_eq!(counter(&client).await.unwrap(), 1);
+
+    // restart the node on the same address while the client is connected
+    server.stop().unwrap();
+    let restart = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        failing_server(&addr.to_string(), vec![]).await
+    });
+
+    assert_eq!(counter(&client).await.unwrap(), 1);
+    restart.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_resume_subscription() {
+    let (addr, server) = failing_server("127.0.0.1:0", vec![]).await;
+    let mut options = test_options(format!("ws://{addr}"));
+    options.max_retries = 10;
+    let client = ReconnectingClient::connect(options).await.unwrap();
+    let mut ticks = client
+        .subscribe_raw("subscribe_ticks", None, "unsubscribe_ticks")
+        .await
+        .unwrap()
+        .stream
+        .map(|tick| tick.unwrap().get().parse::<u32>().unwrap());
+    assert_eq!(ticks.next().await, Some(1));
+
+    // restart the node on the same address during the subscription
+    server.stop().unwrap();
+    let restart = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        failing_server(&addr.to_string(), vec![]).await
+    });
+
+    // the ticks received before the restart, then the ticks of the new subscription
+    let resumed = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            match ticks.next().await {
+                Some(1) => break ticks.next().await,
+                Some(_) => {}
+                None => break None,
+            }
+        }
+    });
+    assert_eq!(resumed.await.unwrap(), Some(2));
+    restart.await.unwrap();
+}
+
+/// The default number of retries of a failed request.
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// The default delay before the first retry, in milliseconds.
+pub const DEFAULT_RETRY_DELAY_MS: u64 = 500;
+
+/// The default time to wait for a response, in seconds.
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+
+/// The longest delay between two retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How to connect to the node, and retry failed requests.
+#[derive(clap::Args, Clone, Debug)]
+pub struct NodeOptions {
+    /// The websocket url of the node
+    #[clap(
+        short,
+        long,
+        default_value = "wss://rococo-contracts-rpc.polkadot.io:443"
+    )]
+    pub url: String,
+    /// Serve the chain data from a directory of exports instead of a node: `blocks.json`,
+    /// `db-<block number>.json` and `metadata.scale`
+    #[clap(long, value_name = "DIR")]
+    pub offline: Option<PathBuf>,
+    /// Record the requests sent to the node and its responses in a cassette
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["offline", "replay"])]
+    pub record: Option<PathBuf>,
+    /// Answer the requests from a cassette written with `--record`, instead of a node
+    #[clap(long, value_name = "FILE", conflicts_with = "offline")]
+    pub replay: Option<PathBuf>,
+    /// Keep the results of the queries at a given block on disk, and reuse them in later runs
+    #[clap(long, conflicts_with_all = ["offline", "replay"])]
+    pub cache: bool,
+    /// The directory of the cache, `~/.cache/contracts-query` by default
+    #[clap(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// The number of times a failed request is retried
+    #[clap(long, default_value_t = DEFAULT_MAX_RETRIES)]
+    pub max_retries: u32,
+    /// The delay before the first retry in milliseconds, doubled after each retry
+    #[clap(long, default_value_t = DEFAULT_RETRY_DELAY_MS)]
+    pub retry_delay_ms: u64,
+    /// The time to wait for a response, in seconds
+    #[clap(long, default_value_t = DEFAULT_REQUEST_TIMEOUT)]
+    pub request_timeout: u64,
+}
+
+impl NodeOptions {
+    /// Connect to the given node with the default options.
+    #[cfg(test)]
+    pub fn with_url(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            offline: None,
+            record: None,
+            replay: None,
+            cache: false,
+            cache_dir: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay_ms: DEFAULT_RETRY_DELAY_MS,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// The directory of the cache: `--cache-dir`, or `contracts-query` in the user cache directory.
+    pub fn cache_dir(&self) -> Result<PathBuf> {
+        if let Some(dir) = &self.cache_dir {
+            return Ok(dir.clone());
+        }
+        let cache_home = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .ok_or_else(|| anyhow::format_err!("no cache directory found, use --cache-dir"))?;
+        Ok(cache_home.join("contracts-query"))
+    }
+
+    /// The delay before the given retry: an exponential backoff, with up to half of it replaced by
+    /// a random jitter so that concurrent requests do not retry in lockstep.
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay = Duration::from_millis(self.retry_delay_ms)
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_DELAY);
+        let jitter = rand::thread_rng().gen_range(0.0..=0.5);
+        delay.mul_f64(1.0 - jitter)
+    }
+}
+
+/// What to do about a failed request.
+#[derive(Debug, PartialEq)]
+enum Failure {
+    /// The request itself is wrong, retrying would fail again.
+    Fatal,
+    /// The node could not answer, e.g. it timed out or is rate limiting us.
+    Transient,
+    /// The connection dropped, reconnect before retrying.
+    Disconnected,
+}
+
+impl Failure {
+    fn of(err: &Error) -> Self {
+        match err {
+            Error::Transport(_) | Error::RestartNeeded(_) | Error::Internal(_) => {
+                Self::Disconnected
+            }
+            Error::RequestTimeout | Error::MaxSlotsExceeded => Self::Transient,
+            Error::Call(CallError::Custom(err)) if is_transient(err) => Self::Transient,
+            _ => Self::Fatal,
+        }
+    }
+}
+
+/// Whether the node is too busy to process the call, rather than rejecting it.
+///
+/// Internal errors are not retried, as they are usually deterministic, e.g. a runtime call that
+/// fails at the given block, unless their message is about rate limiting.
+fn is_transient(err: &ErrorObject) -> bool {
+    let message = err.message().to_lowercase();
+    err.code() == SERVER_IS_BUSY_CODE
+        || message.contains("rate limit")
+        || message.contains("too many requests")
+}
+
+/// Wrap an error of a [`RpcClientT`] implementation.
+pub fn client_error(err: impl std::error::Error + Send + Sync + 'static) -> RpcError {
+    RpcError::ClientError(Box::new(err))
+}
+
+/// A JSON-RPC client sending batch requests, on top of the requests and subscriptions of subxt.
+pub trait RpcTransport: RpcClientT {
+    /// Call `method` once with each of the given params, in a single batch request. The responses
+    /// are in the order of the params.
+    fn batch_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Vec<Option<Box<RawValue>>>,
+    ) -> RpcFuture<'a, Vec<Box<RawValue>>>;
+}
+
+impl RpcClientT for Box<dyn RpcTransport> {
+    fn request_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Option<Box<RawValue>>,
+    ) -> RpcFuture<'a, Box<RawValue>> {
+        (**self).request_raw(method, params)
+    }
+
+    fn subscribe_raw<'a>(
+        &'a self,
+        sub: &'a str,
+        params: Option<Box<RawValue>>,
+        unsub: &'a str,
+    ) -> RpcFuture<'a, RpcSubscription> {
+        (**self).subscribe_raw(sub, params, unsub)
+    }
+}
+
+impl RpcTransport for Box<dyn RpcTransport> {
+    fn batch_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Vec<Option<Box<RawValue>>>,
+    ) -> RpcFuture<'a, Vec<Box<RawValue>>> {
+        (**self).batch_raw(method, params)
+    }
+}
+
+/// Already serialized params.
+#[derive(Clone)]
+struct Params(Option<Box<RawValue>>);
+
+impl ToRpcParams for Params {
+    fn to_rpc_params(self) -> Result<Option<Box<RawValue>>, Error> {
+        Ok(self.0)
+    }
+}
+
+/// A websocket client retrying failed requests, and reconnecting when the connection drops.
+#[derive(Clone)]
+pub struct ReconnectingClient {
+    options: NodeOptions,
+    /// Shared with the subscription streams, to resume them on a new connection.
+    client: Arc<RwLock<Arc<WsClient>>>,
+}
+
+impl ReconnectingClient {
+    pub async fn connect(options: NodeOptions) -> Result<Self> {
+        let client = Self::build(&options).await.map_err(|reason| {
+            anyhow::format_err!("failed to connect to {}: {reason}", options.url)
+        })?;
+        Ok(Self {
+            options,
+            client: Arc::new(RwLock::new(client)),
+        })
+    }
+
+    async fn build(options: &NodeOptions) -> Result<Arc<WsClient>, Error> {
+        let timeout = Duration::from_secs(options.request_timeout);
+        let client = WsClientBuilder::default()
+            .max_notifs_per_subscription(4096)
+            .request_timeout(timeout)
+            .connection_timeout(timeout)
+            .build(&options.url)
+            .await?;
+        Ok(Arc::new(client))
+    }
+
+    /// Replace the given disconnected client, unless a concurrent request already did.
+    async fn reconnect(&self, disconnected: &Arc<WsClient>) -> Result<(), Error> {
+        let mut client = self.client.write().await;
+        if Arc::ptr_eq(&client, disconnected) {
+            *client = Self::build(&self.options).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a request with the current connection, retrying transient failures.
+    pub async fn call<T, F, Fut>(&self, mut request: F) -> Result<T, Error>
+    where
+        F: FnMut(Arc<WsClient>) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let client = self.client.read().await.clone();
+            let err = match request(client.clone()).await {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+
+            let failure = Failure::of(&err);
+            if failure == Failure::Fatal || attempt >= self.options.max_retries {
+                return Err(err);
+            }
+            tokio::time::sleep(self.options.retry_delay(attempt)).await;
+            attempt += 1;
+
+            // if the node is not back yet, the next request fails and reconnecting is retried
+            if failure == Failure::Disconnected {
+                match self.reconnect(&client).await {
+                    Err(err) if attempt >= self.options.max_retries => return Err(err),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Subscribe with the current connection, retrying transient failures, and return the
+    /// connection along with the subscription.
+    async fn subscribe(
+        &self,
+        sub: &str,
+        params: &Option<Box<RawValue>>,
+        unsub: &str,
+    ) -> Result<(Arc<WsClient>, Subscription<Box<RawValue>>), Error> {
+        self.call(|client| {
+            let params = Params(params.clone());
+            async move {
+                let subscription = client.subscribe(sub, params, unsub).await?;
+                Ok((client, subscription))
+            }
+        })
+        .await
+    }
+}
+
+impl RpcClientT for ReconnectingClient {
+    fn request_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Option<Box<RawValue>>,
+    ) -> RpcFuture<'a, Box<RawValue>> {
+        Box::pin(async move {
+            self.call(|client| {
+                let params = Params(params.clone());
+                async move { client.request(method, params).await }
+            })
+            .await
+            .map_err(client_error)
+        })
+    }
+
+    fn subscribe_raw<'a>(
+        &'a self,
+        sub: &'a str,
+        params: Option<Box<RawValue>>,
+        unsub: &'a str,
+    ) -> RpcFuture<'a, RpcSubscription> {
+        Box::pin(async move {
+            let (client, subscription) = self
+                .subscribe(sub, &params, unsub)
+                .await
+                .map_err(client_error)?;
+
+            // the id of the first subscription, the resumed ones get their own
+            let id = match subscription.kind() {
+                SubscriptionKind::Subscription(SubscriptionId::Str(id)) => {
+                    Some(id.clone().into_owned())
+                }
+                _ => None,
+            };
+
+            let this = self.clone();
+            let (sub, unsub) = (sub.to_string(), unsub.to_string());
+            let stream = async_stream::stream! {
+                let (mut client, mut subscription) = (client, subscription);
+                loop {
+                    match subscription.next().await {
+                        Some(notification) => yield notification.map_err(client_error),
+                        // the node closed the subscription
+                        None if client.is_connected() => break,
+                        // the connection dropped, the notifications sent meanwhile are lost
+                        None => match this.subscribe(&sub, &params, &unsub).await {
+                            Ok(resumed) => (client, subscription) = resumed,
+                            Err(err) => {
+                                yield Err(client_error(err));
+                                break;
+                            }
+                        },
+                    }
+                }
+            };
+            Ok(RpcSubscription {
+                stream: stream.boxed(),
+                id,
+            })
+        })
+    }
+}
+
+impl RpcTransport for ReconnectingClient {
+    fn batch_raw<'a>(
+        &'a self,
+        method: &'a str,
+        params: Vec<Option<Box<RawValue>>>,
+    ) -> RpcFuture<'a, Vec<Box<RawValue>>> {
+        Box::pin(async move {
+            let mut batch = BatchRequestBuilder::new();
+            for params in params {
+                batch.insert(method, Params(params)).map_err(client_error)?;
+            }
+
+            self.call(|client| {
+                let batch = batch.clone();
+                async move {
+                    let responses = client
+                        .batch_request::<Box<RawValue>>(batch)
+                        .await?
+                        .into_ok()
+                        .map_err(|mut errors| match errors.next() {
+                            Some(err) => Error::Call(CallError::Custom(err.into_owned())),
+                            None => Error::Custom("the batch failed without errors".to_string()),
+                        })?;
+                    Ok(responses.collect())
+                }
+            })
+            .await
+            .map_err(client_error)
+        })
+    }
+}
@@ -0,0 +1,496 @@
+//! Verify that a database export is complete, by rebuilding its state root.
+
+use crate::db_export::{DBExport, Manifest, TrieEntries};
+use crate::node_client::{to_hex, NodeClient};
+use crate::rpc::NodeOptions;
+use anyhow::Result;
+use clap::Parser;
+use serde::Deserialize;
+use sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use sp_core::{Blake2Hasher, H256};
+use sp_trie::{
+    HashDBT, LayoutV0, LayoutV1, MemoryDB, TrieConfiguration, TrieDBMutBuilder, TrieMut,
+    EMPTY_PREFIX,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use subxt::storage::StorageKey;
+use subxt::{Config, PolkadotConfig};
+use trie_db::node::{Node, NodeHandle};
+use trie_db::NodeCodec as _;
+
+#[test]
+fn test_verify_fixtures() {
+    let blocks = std::fs::read_to_string("blocks.json").unwrap();
+    let blocks: Vec<ExportedBlock> = serde_json::from_str(&blocks).unwrap();
+
+    for (number, block) in blocks.iter().enumerate() {
+        let export = DBExport::load(&format!("db-{number}.json")).unwrap();
+        let report = verify(&export, 1);
+        assert!(report.child_mismatches.is_empty());
+        assert_eq!(report.root, block.header.state_root, "block {number}");
+    }
+
+    // dropping a child trie entry is caught by its root entry
+    let mut export = DBExport::load("db-2.json").unwrap();
+    let (child_key, entries) = export.child_tries.iter_mut().next().unwrap();
+    let child_key = child_key.clone();
+    entries.clear();
+    let report = verify(&export, 1);
+    assert_eq!(report.child_mismatches.len(), 1);
+    assert_eq!(report.child_mismatches[0].0, child_key);
+}
+
+#[tokio::test]
+async fn test_find_divergence() {
+    let state = DBExport::load("db-2.json").unwrap().root;
+    let (mut state_nodes, state_root) = build_trie::<LayoutV1<Blake2Hasher>>(&state).unwrap();
+    let state_nodes = state_nodes
+        .drain()
+        .into_values()
+        .map(|(node, _)| (H256(sp_core::blake2_256(&node)), node))
+        .collect::<HashMap<_, _>>();
+    let fetch_nodes = |_| async { Ok(state_nodes.clone()) };
+
+    // drop the Timestamp::Now entry
+    let mut export = state.clone();
+    let key = hex::decode("f0c365c3cf59d671eb72da0e7a4113c49f1f0515f462cdcf84e0f1d6045dfcbb");
+    export.remove(&StorageKey(key.unwrap())).unwrap();
+
+    let divergence = find_divergence(&export, state_root, 1, fetch_nodes)
+        .await
+        .unwrap();
+    assert_eq!(divergence, "keys under 0xf are missing from the export");
+
+    // drop a System::Account entry, deeper in the trie
+    let mut export = state.clone();
+    let system_account = "26aa394eea5630e07c48ae0c9558cef7b99d880ec681799c0cf30e8886371da9";
+    let key = state
+        .keys()
+        .find(|key| hex::encode(&key.0).starts_with(system_account))
+        .unwrap();
+    export.remove(key).unwrap();
+
+    let divergence = find_divergence(&export, state_root, 1, fetch_nodes)
+        .await
+        .unwrap();
+    assert!(divergence.starts_with(&format!("keys under 0x{system_account}")));
+}
+
+#[tokio::test]
+async fn test_verify_export_from_node() {
+    use crate::db_export::{DBExportWriter, ExportFormat};
+    use crate::mock_node::{temp_path, MockNode};
+
+    let node = MockNode::fixtures().await;
+    let verify_cmd = |export_file: &str| {
+        VerifyExportCmd::parse_from(["verify-export", export_file, "--at-block", "2"])
+    };
+    verify_export(node.options(), verify_cmd("db-2.json"))
+        .await
+        .unwrap();
+
+    // the divergence is found with the proofs served by the node
+    let mut export = DBExport::load("db-2.json").unwrap();
+    export.root.pop_last();
+    let export_file = temp_path("db-2-incomplete.json");
+    let export_file = export_file.to_str().unwrap();
+    export
+        .write(DBExportWriter::create(export_file, ExportFormat::Json).unwrap())
+        .unwrap();
+    let err = verify_export(node.options(), verify_cmd(export_file))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("verification of {export_file} failed")
+    );
+}
+
+/// Check that an export is complete, by rebuilding the state root of its block.
+#[derive(Parser, Debug)]
+pub struct VerifyExportCmd {
+    /// The export to verify
+    export_file: String,
+    /// The block of the export, read from its manifest by default
+    #[clap(long)]
+    at_block: Option<u32>,
+    /// Read the block header from a `block-export` file, instead of the node
+    #[clap(long)]
+    blocks_file: Option<String>,
+    /// The state version of the trie layout, read from the runtime by default
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=1))]
+    state_version: Option<u8>,
+}
+
+/// A block of a `block-export` file, only the header is read.
+#[derive(Deserialize)]
+struct ExportedBlock {
+    header: <PolkadotConfig as Config>::Header,
+}
+
+/// Verify the export, against the node or a `block-export` file.
+///
+/// When the node is used and the state root does not match, the trie nodes of the block are
+/// fetched to find the first subtree of the export that disagrees with the state.
+pub async fn verify_export(node: NodeOptions, cmd: VerifyExportCmd) -> Result<()> {
+    let manifest = Manifest::load(&cmd.export_file)?;
+    if let Some(manifest) = &manifest {
+        if !manifest.is_full() || manifest.delta_from.is_some() {
+            anyhow::bail!(
+                "{} is a partial export and can not be verified",
+                cmd.export_file
+            );
+        }
+    }
+
+    let block_number = cmd
+        .at_block
+        .or(manifest.map(|m| m.block_number))
+        .ok_or_else(|| anyhow::format_err!("no manifest found, the block must be given"))?;
+
+    let (state_root, state_versions, node) = match &cmd.blocks_file {
+        Some(blocks_file) => {
+            let blocks = std::fs::read_to_string(blocks_file)?;
+            let blocks: Vec<ExportedBlock> = serde_json::from_str(&blocks)?;
+            let header = blocks
+                .into_iter()
+                .map(|block| block.header)
+                .find(|header| header.number == block_number)
+                .ok_or_else(|| anyhow::format_err!("block {block_number} not in {blocks_file}"))?;
+
+            // without a runtime to ask, try both layouts
+            let state_versions = cmd.state_version.map_or(vec![1, 0], |v| vec![v]);
+            (header.state_root, state_versions, None)
+        }
+        None => {
+            let client = NodeClient::connect(node).await?;
+            let block_hash = client.get_blockhash(block_number).await?;
+            let state_root = client.get_header(block_hash).await?.state_root;
+            let state_version = match cmd.state_version {
+                Some(version) => version,
+                None => client.get_state_version(block_hash).await?,
+            };
+            (state_root, vec![state_version], Some((client, block_hash)))
+        }
+    };
+
+    let export = DBExport::load(&cmd.export_file)?;
+    let reports = state_versions
+        .iter()
+        .map(|version| (*version, verify(&export, *version)))
+        .collect::<Vec<_>>();
+
+    if let Some((state_version, _)) = reports.iter().find(|(_, r)| r.is_valid(state_root)) {
+        println!(
+            "Export matches the state root {state_root:?} of block {block_number} (state version {state_version})"
+        );
+        return Ok(());
+    }
+
+    let (state_version, report) = &reports[0];
+    println!(
+        "Export does not match the state of block {block_number} (state version {state_version})"
+    );
+    if report.root != state_root {
+        println!("  root: computed {:?}, header {state_root:?}", report.root);
+    }
+    if report.missing_values > 0 {
+        println!("  {} root entries have no value", report.missing_values);
+    }
+    for (key, computed, exported) in &report.child_mismatches {
+        let exported = exported.map_or("none".to_string(), |root| format!("{root:?}"));
+        println!(
+            "  child trie {}: computed {computed:?}, root entry {exported}",
+            to_hex(key)
+        );
+    }
+
+    if let Some((client, block_hash)) = &node {
+        let trie = TrieNodes {
+            client,
+            block_hash: *block_hash,
+            child_trie: None,
+        };
+        if report.root != state_root {
+            let fetch_nodes = |nibbles| trie.fetch(nibbles);
+            let divergence =
+                find_divergence(&export.root, state_root, *state_version, fetch_nodes).await?;
+            println!("  root trie: {divergence}");
+        }
+
+        for (key, _, exported) in &report.child_mismatches {
+            let Some(expected) = exported else {
+                continue;
+            };
+            let trie = TrieNodes {
+                child_trie: Some(key),
+                ..trie
+            };
+            let entries = export.child_tries.get(key).cloned().unwrap_or_default();
+            let fetch_nodes = |nibbles| trie.fetch(nibbles);
+            let divergence =
+                find_divergence(&entries, *expected, *state_version, fetch_nodes).await?;
+            println!("  child trie {}: {divergence}", to_hex(key));
+        }
+    }
+
+    anyhow::bail!("verification of {} failed", cmd.export_file)
+}
+
+/// The roots rebuilt from an export.
+pub struct Report {
+    /// The state root computed from the root entries.
+    pub root: H256,
+    /// The number of root entries exported without a value, which can not be part of the trie.
+    missing_values: usize,
+    /// The child tries whose computed root differs from the value of their root entry, with the
+    /// computed and the exported root.
+    child_mismatches: Vec<(StorageKey, H256, Option<H256>)>,
+}
+
+impl Report {
+    pub fn is_valid(&self, state_root: H256) -> bool {
+        self.root == state_root && self.missing_values == 0 && self.child_mismatches.is_empty()
+    }
+}
+
+/// Rebuild the roots of the export with the layout of the given state version.
+pub fn verify(export: &DBExport, state_version: u8) -> Report {
+    match state_version {
+        0 => verify_with::<LayoutV0<Blake2Hasher>>(export),
+        _ => verify_with::<LayoutV1<Blake2Hasher>>(export),
+    }
+}
+
+fn verify_with<L: TrieConfiguration<Hash = Blake2Hasher>>(export: &DBExport) -> Report {
+    let root = L::trie_root(
+        export
+            .root
+            .iter()
+            .filter_map(|(key, value)| Some((&key.0, &value.as_ref()?.0))),
+    );
+    let missing_values = export.root.values().filter(|v| v.is_none()).count();
+
+    // every child trie of the state has a root entry holding its root
+    let mut child_roots = export
+        .root
+        .iter()
+        .filter(|(key, _)| key.0.starts_with(DEFAULT_CHILD_STORAGE_KEY_PREFIX))
+        .map(|(key, value)| {
+            let root = value.as_ref().and_then(|v| v.0.as_slice().try_into().ok());
+            (key.clone(), root.map(H256))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut child_mismatches = Vec::new();
+    let empty = TrieEntries::new();
+    let mut keys = child_roots.keys().cloned().collect::<Vec<_>>();
+    keys.extend(export.child_tries.keys().cloned());
+    keys.sort();
+    keys.dedup();
+
+    for key in keys {
+        let entries = export.child_tries.get(&key).unwrap_or(&empty);
+        let computed = L::trie_root(
+            entries
+                .iter()
+                .filter_map(|(key, value)| Some((&key.0, &value.as_ref()?.0))),
+        );
+        let exported = child_roots.remove(&key).flatten();
+        if exported != Some(computed) {
+            child_mismatches.push((key, computed, exported));
+        }
+    }
+
+    Report {
+        root: H256(root.into()),
+        missing_values,
+        child_mismatches,
+    }
+}
+
+/// Fetch the trie nodes of the state, or of one of its child tries, from the node.
+#[derive(Clone, Copy)]
+struct TrieNodes<'a> {
+    client: &'a NodeClient,
+    block_hash: H256,
+    child_trie: Option<&'a StorageKey>,
+}
+
+impl TrieNodes<'_> {
+    /// Fetch the nodes on the path of the given nibbles, indexed by hash.
+    async fn fetch(self, nibbles: Vec<u8>) -> Result<HashMap<H256, Vec<u8>>> {
+        // a key with an odd number of nibbles is padded, its proof still goes through the path
+        let key = nibbles
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or_default())
+            .collect::<Vec<_>>();
+
+        let proof = match self.child_trie {
+            Some(child_key) => {
+                self.client
+                    .get_child_read_proof(child_key, &key, self.block_hash)
+                    .await?
+            }
+            None => self.client.get_read_proof(&key, self.block_hash).await?,
+        };
+
+        Ok(proof
+            .into_iter()
+            .map(|node| (H256(sp_core::blake2_256(&node)), node))
+            .collect())
+    }
+}
+
+/// Walk down the rebuilt trie and the trie of the node, along the first differing child, and
+/// describe where they diverge.
+///
+/// `fetch_nodes` returns the nodes of the state on the path of the given nibbles, by hash.
+async fn find_divergence<Fut>(
+    entries: &TrieEntries,
+    expected_root: H256,
+    state_version: u8,
+    mut fetch_nodes: impl FnMut(Vec<u8>) -> Fut,
+) -> Result<String>
+where
+    Fut: Future<Output = Result<HashMap<H256, Vec<u8>>>>,
+{
+    let (db, root) = match state_version {
+        0 => build_trie::<LayoutV0<Blake2Hasher>>(entries)?,
+        _ => build_trie::<LayoutV1<Blake2Hasher>>(entries)?,
+    };
+
+    let mut path = Vec::new();
+    let mut theirs = fetch_nodes(path.clone()).await?;
+    let mut ours_node = db
+        .get(&root, EMPTY_PREFIX)
+        .ok_or_else(|| anyhow::format_err!("missing root node"))?;
+    let mut their_node = theirs
+        .get(&expected_root)
+        .cloned()
+        .ok_or_else(|| anyhow::format_err!("the node did not return the root node"))?;
+
+    loop {
+        let ours = decode(&ours_node)?;
+        let their = decode(&their_node)?;
+
+        let (our_children, their_children) = match (&ours, &their) {
+            (Node::NibbledBranch(p1, c1, v1), Node::NibbledBranch(p2, c2, v2)) if p1 == p2 => {
+                path.extend((0..p1.len()).map(|i| p1.at(i)));
+                if v1 != v2 {
+                    return Ok(format!("value of key {} differs", nibbles_to_hex(&path)));
+                }
+                (c1, c2)
+            }
+            (Node::Leaf(p1, _), Node::Leaf(p2, _)) if p1 == p2 => {
+                path.extend((0..p1.len()).map(|i| p1.at(i)));
+                return Ok(format!("value of key {} differs", nibbles_to_hex(&path)));
+            }
+            (Node::Empty, _) => return Ok("the export is empty".to_string()),
+            // a single exported key where the state has several
+            (Node::Leaf(..), Node::NibbledBranch(..)) => {
+                return Ok(format!(
+                    "keys under {} are missing from the export",
+                    nibbles_to_hex(&path)
+                ))
+            }
+            (Node::NibbledBranch(..), Node::Leaf(..)) => {
+                return Ok(format!(
+                    "keys under {} are not in the state",
+                    nibbles_to_hex(&path)
+                ))
+            }
+            _ => {
+                return Ok(format!(
+                    "keys under {} differ from the state",
+                    nibbles_to_hex(&path)
+                ))
+            }
+        };
+
+        let Some(index) = (0..16).find(|i| our_children[*i] != their_children[*i]) else {
+            return Ok(format!(
+                "node at {} is encoded differently, check the state version",
+                nibbles_to_hex(&path)
+            ));
+        };
+        path.push(index as u8);
+
+        match (our_children[index], their_children[index]) {
+            (None, _) => {
+                return Ok(format!(
+                    "keys under {} are missing from the export",
+                    nibbles_to_hex(&path)
+                ))
+            }
+            (_, None) => {
+                return Ok(format!(
+                    "keys under {} are not in the state",
+                    nibbles_to_hex(&path)
+                ))
+            }
+            (Some(ours), Some(their)) => {
+                ours_node = match ours {
+                    NodeHandle::Hash(hash) => db
+                        .get(&H256::from_slice(hash), EMPTY_PREFIX)
+                        .ok_or_else(|| anyhow::format_err!("missing node {}", to_hex(hash)))?,
+                    NodeHandle::Inline(node) => node.to_vec(),
+                };
+                their_node = match their {
+                    NodeHandle::Hash(hash) => {
+                        let hash = H256::from_slice(hash);
+                        if !theirs.contains_key(&hash) {
+                            theirs = fetch_nodes(path.clone()).await?;
+                        }
+                        theirs.get(&hash).cloned().ok_or_else(|| {
+                            anyhow::format_err!("the node did not return the node {hash:?}")
+                        })?
+                    }
+                    NodeHandle::Inline(node) => node.to_vec(),
+                };
+            }
+        }
+    }
+}
+
+/// Build the trie of the given entries, keeping its nodes.
+fn build_trie<L: TrieConfiguration<Hash = Blake2Hasher>>(
+    entries: &TrieEntries,
+) -> Result<(MemoryDB<Blake2Hasher>, H256)> {
+    let mut db = MemoryDB::<Blake2Hasher>::default();
+    let mut root = Default::default();
+    {
+        let mut trie = TrieDBMutBuilder::<L>::new(&mut db, &mut root).build();
+        for (key, value) in entries {
+            if let Some(value) = value {
+                trie.insert(&key.0, &value.0)
+                    .map_err(|reason| anyhow::format_err!("failed to build the trie: {reason}"))?;
+            }
+        }
+    }
+    Ok((db, root))
+}
+
+/// All the nodes of the trie of the given entries, a superset of the proof of any of its keys.
+pub fn trie_nodes(entries: &TrieEntries, state_version: u8) -> Result<Vec<Vec<u8>>> {
+    let (mut db, _) = match state_version {
+        0 => build_trie::<LayoutV0<Blake2Hasher>>(entries)?,
+        _ => build_trie::<LayoutV1<Blake2Hasher>>(entries)?,
+    };
+    Ok(db.drain().into_values().map(|(node, _)| node).collect())
+}
+
+fn decode(node: &[u8]) -> Result<Node<'_>> {
+    sp_trie::NodeCodec::<Blake2Hasher>::decode(node)
+        .map_err(|reason| anyhow::format_err!("invalid trie node: {reason:?}"))
+}
+
+/// Render a nibble path as hex, e.g. `0x26a` for 3 nibbles.
+fn nibbles_to_hex(nibbles: &[u8]) -> String {
+    let digits = nibbles
+        .iter()
+        .map(|n| char::from_digit(*n as u32, 16).unwrap_or('?'))
+        .collect::<String>();
+    format!("0x{digits}")
+}
@@ -0,0 +1,261 @@
+//! The `:__STORAGE_VERSION__:` of every pallet of the runtime, and the blocks where they changed.
+
+use crate::bisect::{self, ProbeCache};
+use crate::node_client::NodeClient;
+use crate::output::{self, CsvRecord, OutputFormat};
+use anyhow::Result;
+use clap::Parser;
+use serde::Serialize;
+use sp_core::H256;
+
+#[tokio::test]
+async fn test_pallet_versions() {
+    use crate::mock_node::{set_contract_version, set_value, MockNode, Scenario};
+    use crate::node_client::storage_version_key;
+    use codec::Encode;
+
+    // contracts is upgraded at block 3, and balances at block 5
+    let scenario = Scenario::fixtures()
+        .push_blocks(2, |_| {})
+        .push_block(|state| set_contract_version(state, 10))
+        .push_block(|_| {})
+        .push_block(|state| {
+            let key = storage_version_key("Balances");
+            set_value(state, &key, Some(7u16.encode()));
+        })
+        .push_block(|_| {});
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    let version = |versions: &PalletVersions, pallet: &str| {
+        let version = versions.versions.iter().find(|v| v.pallet == pallet);
+        version.unwrap().version
+    };
+    let genesis = pallet_versions(&client, 0, None, 1).await.unwrap();
+    assert_eq!(version(&genesis, "Contracts"), Some(9));
+    assert!(genesis.changes.is_empty());
+
+    let versions = pallet_versions(&client, 6, Some(0), 2).await.unwrap();
+    assert_eq!(version(&versions, "Contracts"), Some(10));
+    assert_eq!(version(&versions, "Balances"), Some(7));
+    let changes = versions
+        .changes
+        .iter()
+        .map(|change| {
+            let pallet = change.pallet.as_str();
+            (
+                change.block_number,
+                pallet,
+                change.from_version,
+                change.to_version,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        changes,
+        [
+            (3, "Contracts", Some(9), Some(10)),
+            (5, "Balances", version(&genesis, "Balances"), Some(7)),
+        ]
+    );
+}
+
+#[derive(Parser, Debug)]
+pub struct PalletVersionsCmd {
+    /// The block to list the versions at, the latest block by default
+    #[clap(short, long)]
+    block_number: Option<u32>,
+    /// Find the blocks where the versions changed, from this block to `--block-number`
+    #[clap(long)]
+    start_block: Option<u32>,
+    /// The number of blocks probed at once when searching for a change
+    #[clap(long, default_value_t = 1)]
+    parallel_probes: usize,
+}
+
+/// The storage version of a pallet.
+#[derive(Debug, Clone, Serialize)]
+pub struct PalletVersion {
+    pub pallet: String,
+    /// `None` for the pallets without a storage version.
+    pub version: Option<u16>,
+}
+
+impl CsvRecord for PalletVersion {
+    const HEADER: &'static [&'static str] = &["pallet", "version"];
+
+    fn fields(&self) -> Vec<String> {
+        let version = self.version.map(|v| v.to_string()).unwrap_or_default();
+        vec![self.pallet.clone(), version]
+    }
+}
+
+/// A change of the storage version of a pallet.
+#[derive(Debug, Clone, Serialize)]
+pub struct PalletVersionChange {
+    pub pallet: String,
+    /// The first block with the new version.
+    pub block_number: u32,
+    pub block_hash: H256,
+    /// The timestamp of the block, in RFC 3339 format.
+    pub timestamp: String,
+    pub from_version: Option<u16>,
+    pub to_version: Option<u16>,
+}
+
+impl CsvRecord for PalletVersionChange {
+    const HEADER: &'static [&'static str] = &[
+        "pallet",
+        "block_number",
+        "block_hash",
+        "timestamp",
+        "from_version",
+        "to_version",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        let version = |version: Option<u16>| version.map(|v| v.to_string()).unwrap_or_default();
+        vec![
+            self.pallet.clone(),
+            self.block_number.to_string(),
+            format!("{:?}", self.block_hash),
+            self.timestamp.clone(),
+            version(self.from_version),
+            version(self.to_version),
+        ]
+    }
+}
+
+/// The storage versions of the pallets at a block, and their changes in the range searched.
+#[derive(Debug, Serialize)]
+pub struct PalletVersions {
+    pub block_number: u32,
+    pub block_hash: H256,
+    pub versions: Vec<PalletVersion>,
+    /// The changes from the oldest, empty when no range is searched.
+    pub changes: Vec<PalletVersionChange>,
+    /// The number of blocks probed to find the changes.
+    pub probes: usize,
+}
+
+fn format_version(version: Option<u16>) -> String {
+    match version {
+        Some(version) => version.to_string(),
+        None => "none".to_string(),
+    }
+}
+
+/// Get the storage version of each pallet of the metadata at `block_number`, and, given a
+/// `start_block`, find the blocks where they changed since, see [`bisect::find_changes`].
+///
+/// The pallets removed from the runtime before `block_number` are not listed.
+pub async fn pallet_versions(
+    client: &NodeClient,
+    block_number: u32,
+    start_block: Option<u32>,
+    parallel_probes: usize,
+) -> Result<PalletVersions> {
+    let block_hash = client.get_blockhash(block_number).await?;
+    let metadata = client.get_metadata(Some(block_hash)).await?;
+    let pallets = &metadata.runtime_metadata().pallets;
+    let prefixes = pallets
+        .iter()
+        .map(|pallet| {
+            let prefix = pallet.storage.as_ref().map(|storage| &storage.prefix);
+            prefix.unwrap_or(&pallet.name).clone()
+        })
+        .collect::<Vec<_>>();
+
+    // the versions of every pallet at each block probed, shared by the search of each pallet
+    let probed = ProbeCache::default();
+    let prefixes = &prefixes;
+    let fetch = |block_number| async move {
+        let block_hash = client.get_blockhash(block_number).await?;
+        client.get_pallet_versions(prefixes, block_hash).await
+    };
+    let probe = |block_number| probed.probe(block_number, fetch);
+
+    let at_block = probe(block_number).await?;
+    let versions = pallets
+        .iter()
+        .zip(&at_block)
+        .map(|(pallet, version)| PalletVersion {
+            pallet: pallet.name.clone(),
+            version: *version,
+        })
+        .collect::<Vec<_>>();
+
+    let mut changes = Vec::new();
+    if let Some(start_block) = start_block {
+        for (index, pallet) in pallets.iter().enumerate() {
+            let probe = |block_number| async move { Ok(probe(block_number).await?[index]) };
+            let found =
+                bisect::find_changes(start_block, block_number, parallel_probes, probe).await?;
+            for (block_number, from_version, to_version) in found {
+                let block_hash = client.get_blockhash(block_number).await?;
+                changes.push(PalletVersionChange {
+                    pallet: pallet.name.clone(),
+                    block_number,
+                    block_hash,
+                    timestamp: client.get_timestamp(block_hash).await?.to_rfc3339(),
+                    from_version,
+                    to_version,
+                });
+            }
+        }
+    }
+    // stable, so the pallets upgraded in the same block keep the order of the metadata
+    changes.sort_by_key(|change| change.block_number);
+
+    Ok(PalletVersions {
+        block_number,
+        block_hash,
+        versions,
+        changes,
+        probes: probed.probes(),
+    })
+}
+
+/// Print the storage version of each pallet, and the blocks where they changed.
+pub async fn print_pallet_versions(
+    client: &NodeClient,
+    cmd: PalletVersionsCmd,
+    output: OutputFormat,
+) -> Result<()> {
+    let block_number = match cmd.block_number {
+        Some(block_number) => block_number,
+        None => client.get_blocknumber().await?,
+    };
+    let report =
+        pallet_versions(client, block_number, cmd.start_block, cmd.parallel_probes).await?;
+
+    if output == OutputFormat::Text {
+        println!("Storage versions at block {}:", report.block_number);
+        for version in &report.versions {
+            println!(
+                "    {}: {}",
+                version.pallet,
+                format_version(version.version)
+            );
+        }
+        if let Some(start_block) = cmd.start_block {
+            println!("Changes from block {start_block}:");
+            for change in &report.changes {
+                println!(
+                    "    Block {} ({}): {} {} -> {}",
+                    change.block_number,
+                    change.timestamp,
+                    change.pallet,
+                    format_version(change.from_version),
+                    format_version(change.to_version)
+                );
+            }
+            println!("Probed {} block(s)", report.probes);
+        }
+    }
+
+    match cmd.start_block {
+        Some(_) => output::print_report(output, &report, &report.changes),
+        None => output::print_report(output, &report, &report.versions),
+    }
+}
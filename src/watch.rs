@@ -0,0 +1,257 @@
+//! Follow a contracts migration as it happens, block by block, from the heads of the node.
+
+use crate::migration::{self, MigrationProgress, StepTotals};
+use crate::node_client::NodeClient;
+use anyhow::Result;
+use clap::Parser;
+use futures::{Stream, TryStreamExt};
+use sp_core::H256;
+use std::fmt;
+use subxt::config::Header as _;
+
+#[tokio::test]
+async fn test_watch_migration() {
+    use crate::mock_node::{
+        fixture_contracts, set_contract_version, set_migration_in_progress, MockNode, Scenario,
+    };
+    use codec::Encode;
+
+    // migrating from block 3 to block 7, one step per version
+    let contract = fixture_contracts()[0].clone();
+    let scenario = Scenario::fixtures()
+        .push_blocks(2, |_| {})
+        .push_block(|state| set_migration_in_progress(state, Some(None::<()>.encode())))
+        .push_block(|state| set_migration_in_progress(state, Some(Some(&contract).encode())))
+        .push_block(|state| set_contract_version(state, 10))
+        .push_block(|state| set_migration_in_progress(state, Some(vec![])))
+        .push_block(|state| {
+            set_contract_version(state, 11);
+            set_migration_in_progress(state, None);
+        })
+        .push_blocks(2, |_| {});
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    // the mock node replays its blocks as heads, and the watch ends on the target version
+    let events = migration_events(&client, 11, false)
+        .map_ok(|event| event.to_string())
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(
+        events,
+        [
+            "Block 0: version 9, no migration in progress".to_string(),
+            "Block 3: step 09 -> 10, 0/2 contracts (0.0%), running for 1 block(s)".to_string(),
+            format!(
+                "Block 4: step 09 -> 10, 1/2 contracts (50.0%), last contract {contract}, \
+                running for 2 block(s)"
+            ),
+            "Block 5: version 09 -> 10 took 3 block(s), from blocks 3 to 5".to_string(),
+            "Block 6: step 10 -> 11, running for 1 block(s)".to_string(),
+            "Block 7: version 10 -> 11 took 2 block(s), from blocks 6 to 7".to_string(),
+            "Block 7: migration completed at version 11".to_string(),
+        ]
+    );
+}
+
+#[derive(Parser, Debug)]
+pub struct WatchMigrationCmd {
+    /// Exit once this version is reached and no migration is in progress
+    #[clap(short, long)]
+    target_version: u16,
+    /// Follow the finalized blocks instead of the best blocks
+    #[clap(long)]
+    finalized: bool,
+}
+
+/// The migration state of a block.
+#[derive(Debug, Clone)]
+pub struct MigrationState {
+    pub block_number: u32,
+    pub version: u16,
+    /// The raw `MigrationInProgress` cursor.
+    pub cursor: Option<Vec<u8>>,
+}
+
+impl MigrationState {
+    async fn fetch(client: &NodeClient, block_number: u32, block_hash: H256) -> Result<Self> {
+        let (version, cursor) = futures::try_join!(
+            client.get_contract_version(Some(block_hash)),
+            client.get_migration_cursor(block_hash),
+        )?;
+        Ok(Self {
+            block_number,
+            version,
+            cursor,
+        })
+    }
+
+    /// The progress of the migration running at the block, if any.
+    async fn progress(
+        &self,
+        client: &NodeClient,
+        totals: &StepTotals,
+        block_hash: H256,
+    ) -> Result<Option<MigrationProgress>> {
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+        let cursor = migration::decode_cursor(self.version, cursor);
+        Ok(Some(
+            migration::progress(client, totals, cursor, block_hash).await?,
+        ))
+    }
+}
+
+/// A change of the migration state.
+#[derive(Debug)]
+pub enum MigrationEvent {
+    /// The state of the first block watched, and the progress of its migration.
+    Watching {
+        state: MigrationState,
+        progress: Option<MigrationProgress>,
+    },
+    /// A migration step started, or its cursor moved. The step started at block `step_start`.
+    Progress {
+        state: MigrationState,
+        step_start: u32,
+        progress: MigrationProgress,
+    },
+    /// The version changed, completing the step started at block `step_start`.
+    Upgraded {
+        state: MigrationState,
+        from_version: u16,
+        step_start: u32,
+    },
+    /// The last step completed, and no migration is in progress anymore.
+    Completed(MigrationState),
+}
+
+impl fmt::Display for MigrationEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Watching { state, progress } => {
+                write!(f, "Block {}: version {}, ", state.block_number, state.version)?;
+                match progress {
+                    Some(progress) => write!(f, "migrating {progress}"),
+                    None => write!(f, "no migration in progress"),
+                }
+            }
+            Self::Progress {
+                state,
+                step_start,
+                progress,
+            } => write!(
+                f,
+                "Block {}: {progress}, running for {} block(s)",
+                state.block_number,
+                state.block_number - step_start + 1,
+            ),
+            Self::Upgraded {
+                state,
+                from_version,
+                step_start,
+            } => write!(
+                f,
+                "Block {}: version {from_version:02} -> {:02} took {} block(s), from blocks {step_start} to {}",
+                state.block_number,
+                state.version,
+                state.block_number - step_start + 1,
+                state.block_number,
+            ),
+            Self::Completed(state) => write!(
+                f,
+                "Block {}: migration completed at version {}",
+                state.block_number, state.version
+            ),
+        }
+    }
+}
+
+/// Follow the heads of the node, or its finalized heads, and stream each change of the storage
+/// version or of the migration cursor. The stream ends once `target_version` is reached and no
+/// migration is in progress.
+pub fn migration_events(
+    client: &NodeClient,
+    target_version: u16,
+    finalized: bool,
+) -> impl Stream<Item = Result<MigrationEvent>> + '_ {
+    let is_done =
+        move |state: &MigrationState| state.version >= target_version && state.cursor.is_none();
+
+    async_stream::try_stream!({
+        let mut heads = client.subscribe_heads(finalized).await?;
+        let mut previous: Option<MigrationState> = None;
+        let totals = StepTotals::default();
+        // the first block of the current migration step
+        let mut step_start = 0;
+
+        while let Some(head) = heads.try_next().await? {
+            // the blocks skipped by the subscription are fetched by number, and heads that are
+            // not past the last block watched, e.g. after a reorg, are ignored
+            let from = previous
+                .as_ref()
+                .map_or(head.number, |state| state.block_number + 1);
+            for block_number in from..=head.number {
+                let block_hash = match block_number == head.number {
+                    true => head.hash(),
+                    false => client.get_blockhash(block_number).await?,
+                };
+                let state = MigrationState::fetch(client, block_number, block_hash).await?;
+
+                let Some(prev) = previous.replace(state.clone()) else {
+                    step_start = block_number;
+                    yield MigrationEvent::Watching {
+                        state: state.clone(),
+                        progress: state.progress(client, &totals, block_hash).await?,
+                    };
+                    if is_done(&state) {
+                        return;
+                    }
+                    continue;
+                };
+
+                if state.version != prev.version {
+                    yield MigrationEvent::Upgraded {
+                        state: state.clone(),
+                        from_version: prev.version,
+                        step_start,
+                    };
+                    step_start = block_number + 1;
+                } else if let Some(progress) = match state.cursor != prev.cursor {
+                    true => state.progress(client, &totals, block_hash).await?,
+                    false => None,
+                } {
+                    if prev.cursor.is_none() {
+                        step_start = block_number;
+                    }
+                    yield MigrationEvent::Progress {
+                        state: state.clone(),
+                        step_start,
+                        progress,
+                    };
+                }
+
+                if state.cursor.is_none() && prev.cursor.is_some() {
+                    yield MigrationEvent::Completed(state.clone());
+                }
+                if is_done(&state) {
+                    return;
+                }
+            }
+        }
+
+        Err(anyhow::format_err!("the subscription to the heads ended"))?;
+    })
+}
+
+/// Print each change of the migration state, until the target version is reached.
+pub async fn watch_migration(client: &NodeClient, cmd: WatchMigrationCmd) -> Result<()> {
+    let events = migration_events(client, cmd.target_version, cmd.finalized);
+    tokio::pin!(events);
+    while let Some(event) = events.try_next().await? {
+        println!("{event}");
+    }
+    Ok(())
+}
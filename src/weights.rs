@@ -0,0 +1,333 @@
+//! The weight and length of the blocks of a migration, against the limits of the runtime, to tell
+//! whether the migration saturated blocks and starved user transactions.
+
+use crate::migration::{MigrationReport, VersionTransition};
+use crate::node_client::{NodeClient, Weight};
+use crate::output::{self, CsvRecord, OutputFormat};
+use anyhow::Result;
+use clap::Parser;
+use codec::Encode;
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use subxt::ext::scale_decode::DecodeAsType;
+
+#[tokio::test]
+async fn test_weight_report() {
+    use crate::mock_node::{
+        set_block_weight, set_contract_version, set_migration_in_progress, MockNode, Scenario,
+    };
+
+    // the migration fills blocks 3 and 4 with mandatory weight, leaving room for transactions in
+    // block 5
+    let scenario = Scenario::fixtures()
+        .push_blocks(2, |state| set_block_weight(state, [10, 1], [0, 0]))
+        .push_block(|state| {
+            set_migration_in_progress(state, Some(vec![0]));
+            set_block_weight(state, [0, 0], [500_000_000_000, 5_242_880]);
+        })
+        .push_block(|state| {
+            set_contract_version(state, 10);
+            set_block_weight(state, [0, 0], [250_000_000_000, 0]);
+        })
+        .push_block(|state| set_block_weight(state, [125_000_000_000, 0], [0, 0]))
+        .push_block(|state| {
+            set_contract_version(state, 11);
+            set_migration_in_progress(state, None);
+        });
+    let node = MockNode::start(scenario.into_backend()).await;
+    let client = node.client().await;
+
+    let report = weight_report(&client, None, 9, 1, 2).await.unwrap();
+    let blocks = report
+        .blocks
+        .iter()
+        .map(|block| (block.block_number, block.ref_time_percent, block.to_version))
+        .collect::<Vec<_>>();
+    assert_eq!(blocks, [(3, 100.0, 10), (4, 50.0, 11), (5, 25.0, 11)]);
+    assert_eq!(report.blocks[2].normal_weight.ref_time, 125_000_000_000);
+
+    let steps = report
+        .steps
+        .iter()
+        .map(|step| {
+            (
+                step.to_version,
+                step.totals.block_count,
+                step.totals.ref_time_percent,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(steps, [(10, 1, 100.0), (11, 2, 37.5)]);
+    assert_eq!(report.total.weight.ref_time, 875_000_000_000);
+    assert_eq!(report.total.max_weight.ref_time, 1_500_000_000_000);
+}
+
+#[derive(Parser, Debug)]
+pub struct MigrationWeightsCmd {
+    #[clap(short, long)]
+    from_block_number: Option<u32>,
+    #[clap(short, long)]
+    target_version: u16,
+    /// The number of blocks probed at once when searching for a migration
+    #[clap(long, default_value_t = 1)]
+    parallel_probes: usize,
+    /// The number of blocks fetched at once
+    #[clap(
+        long,
+        default_value_t = 8,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrency: usize,
+}
+
+/// The `frame_system::limits::BlockWeights` fields we care about.
+#[derive(Debug, DecodeAsType)]
+#[decode_as_type(crate_path = "subxt::ext::scale_decode")]
+struct BlockWeights {
+    max_block: Weight,
+}
+
+/// The weight and length of a block of a migration.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockUsage {
+    pub block_number: u32,
+    /// The migration step the block belongs to.
+    pub from_version: u16,
+    pub to_version: u16,
+    /// The weight consumed by all the dispatch classes.
+    pub weight: Weight,
+    /// The weight consumed by the user transactions.
+    pub normal_weight: Weight,
+    /// The weight consumed by the inherents and the hooks, such as the migration run in
+    /// `on_idle`.
+    pub mandatory_weight: Weight,
+    /// The `max_block` weight of the runtime.
+    pub max_weight: Weight,
+    pub ref_time_percent: f64,
+    pub proof_size_percent: f64,
+    /// The encoded length of the extrinsics.
+    pub length: u32,
+    pub extrinsics: u32,
+}
+
+impl CsvRecord for BlockUsage {
+    const HEADER: &'static [&'static str] = &[
+        "block_number",
+        "from_version",
+        "to_version",
+        "ref_time",
+        "proof_size",
+        "normal_ref_time",
+        "normal_proof_size",
+        "mandatory_ref_time",
+        "mandatory_proof_size",
+        "max_ref_time",
+        "max_proof_size",
+        "ref_time_percent",
+        "proof_size_percent",
+        "length",
+        "extrinsics",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.block_number.to_string(),
+            self.from_version.to_string(),
+            self.to_version.to_string(),
+            self.weight.ref_time.to_string(),
+            self.weight.proof_size.to_string(),
+            self.normal_weight.ref_time.to_string(),
+            self.normal_weight.proof_size.to_string(),
+            self.mandatory_weight.ref_time.to_string(),
+            self.mandatory_weight.proof_size.to_string(),
+            self.max_weight.ref_time.to_string(),
+            self.max_weight.proof_size.to_string(),
+            format!("{:.1}", self.ref_time_percent),
+            format!("{:.1}", self.proof_size_percent),
+            self.length.to_string(),
+            self.extrinsics.to_string(),
+        ]
+    }
+}
+
+/// The weight consumed by a range of blocks, against the weight they could consume.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct WeightTotals {
+    pub block_count: u32,
+    pub weight: Weight,
+    pub normal_weight: Weight,
+    pub max_weight: Weight,
+    pub ref_time_percent: f64,
+    pub proof_size_percent: f64,
+    pub extrinsics: u32,
+}
+
+impl WeightTotals {
+    fn new<'a>(blocks: impl IntoIterator<Item = &'a BlockUsage>) -> Self {
+        let mut totals = Self::default();
+        for block in blocks {
+            totals.block_count += 1;
+            totals.weight = totals.weight + block.weight;
+            totals.normal_weight = totals.normal_weight + block.normal_weight;
+            totals.max_weight = totals.max_weight + block.max_weight;
+            totals.extrinsics += block.extrinsics;
+        }
+        totals.ref_time_percent = percent(totals.weight.ref_time, totals.max_weight.ref_time);
+        totals.proof_size_percent = percent(totals.weight.proof_size, totals.max_weight.proof_size);
+        totals
+    }
+}
+
+/// The weight consumed by a migration step.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepUsage {
+    pub from_version: u16,
+    pub to_version: u16,
+    pub start_block: u32,
+    pub end_block: u32,
+    #[serde(flatten)]
+    pub totals: WeightTotals,
+}
+
+/// The weight consumed by each block and each step of a migration.
+#[derive(Debug, Serialize)]
+pub struct WeightReport {
+    pub blocks: Vec<BlockUsage>,
+    pub steps: Vec<StepUsage>,
+    pub total: WeightTotals,
+}
+
+fn percent(used: u64, max: u64) -> f64 {
+    match max {
+        0 => 0.0,
+        max => used as f64 * 100.0 / max as f64,
+    }
+}
+
+/// Get the weight and length of the given block of a migration step.
+async fn block_usage(
+    client: &NodeClient,
+    block_number: u32,
+    step: &VersionTransition,
+) -> Result<BlockUsage> {
+    let block_hash = client.get_blockhash(block_number).await?;
+    let (consumed, block, limits) = futures::try_join!(
+        client.get_block_weight(block_hash),
+        client.get_block(Some(block_hash)),
+        client.get_constant::<BlockWeights>("System", "BlockWeights", block_hash),
+    )?;
+
+    let weight = consumed.total();
+    let length = block
+        .extrinsics
+        .iter()
+        .map(|extrinsic| extrinsic.0.encoded_size() as u32)
+        .sum();
+    Ok(BlockUsage {
+        block_number,
+        from_version: step.from_version,
+        to_version: step.to_version,
+        weight,
+        normal_weight: consumed.normal,
+        mandatory_weight: consumed.mandatory,
+        max_weight: limits.max_block,
+        ref_time_percent: percent(weight.ref_time, limits.max_block.ref_time),
+        proof_size_percent: percent(weight.proof_size, limits.max_block.proof_size),
+        length,
+        extrinsics: block.extrinsics.len() as u32,
+    })
+}
+
+/// Get the weight consumed by the blocks of each migration step, until the target version is
+/// reached.
+pub async fn weight_report(
+    client: &NodeClient,
+    from_block_number: Option<u32>,
+    target_version: u16,
+    parallel_probes: usize,
+    concurrency: usize,
+) -> Result<WeightReport> {
+    let migration =
+        MigrationReport::fetch(client, from_block_number, target_version, parallel_probes).await?;
+
+    // from the oldest step
+    let mut blocks = Vec::new();
+    let mut steps = Vec::new();
+    for step in migration.transitions.iter().rev() {
+        let first = blocks.len();
+        let step_blocks = stream::iter(step.start_block..=step.end_block)
+            .map(|block_number| block_usage(client, block_number, step))
+            .buffered(concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+        blocks.extend(step_blocks);
+        steps.push(StepUsage {
+            from_version: step.from_version,
+            to_version: step.to_version,
+            start_block: step.start_block,
+            end_block: step.end_block,
+            totals: WeightTotals::new(&blocks[first..]),
+        });
+    }
+
+    Ok(WeightReport {
+        total: WeightTotals::new(&blocks),
+        blocks,
+        steps,
+    })
+}
+
+/// Print the weight consumed by each block and each step of the migrations, until the target
+/// version is reached.
+pub async fn print_migration_weights(
+    client: &NodeClient,
+    cmd: MigrationWeightsCmd,
+    output: OutputFormat,
+) -> Result<()> {
+    let report = weight_report(
+        client,
+        cmd.from_block_number,
+        cmd.target_version,
+        cmd.parallel_probes,
+        cmd.concurrency,
+    )
+    .await?;
+
+    if output == OutputFormat::Text {
+        for block in &report.blocks {
+            println!(
+                "Block {} (v{:02} -> v{:02}): ref_time {} ({:.1}%), proof_size {} ({:.1}%), normal ref_time {}, {} extrinsic(s), {} bytes",
+                block.block_number,
+                block.from_version,
+                block.to_version,
+                block.weight.ref_time,
+                block.ref_time_percent,
+                block.weight.proof_size,
+                block.proof_size_percent,
+                block.normal_weight.ref_time,
+                block.extrinsics,
+                block.length,
+            );
+        }
+
+        println!("Summary:");
+        let totals = report.steps.iter().map(|step| {
+            let name = format!(
+                "Version {:02} -> {:02}, blocks {} to {}",
+                step.from_version, step.to_version, step.start_block, step.end_block
+            );
+            (name, &step.totals)
+        });
+        for (name, totals) in totals.chain([("Total".to_string(), &report.total)]) {
+            println!(
+                "{name}: {} block(s), ref_time {:.1}%, proof_size {:.1}%, normal ref_time {}, {} extrinsic(s)",
+                totals.block_count,
+                totals.ref_time_percent,
+                totals.proof_size_percent,
+                totals.normal_weight.ref_time,
+                totals.extrinsics,
+            );
+        }
+    }
+    output::print_report(output, &report, &report.blocks)
+}